@@ -1,13 +1,16 @@
 #include "bark_native/critical.h"
 
+// the _SAFE variants check whether we're in an ISR and do the right thing,
+// which lets CriticalMutex be used from both task and interrupt context
+
 void
 rtos_taskENTER_CRITICAL(const portMUX_TYPE* spinlock)
 {
-    taskENTER_CRITICAL(spinlock);
+    portENTER_CRITICAL_SAFE(spinlock);
 }
 
 void
 rtos_taskEXIT_CRITICAL(const portMUX_TYPE* spinlock)
 {
-    taskEXIT_CRITICAL(spinlock);
+    portEXIT_CRITICAL_SAFE(spinlock);
 }
@@ -3,3 +3,6 @@ pub const MAX_QUEUED_PACKETS: usize = 50;
 const DELAY_START_MS: usize = 50;
 const DELAY_START_SAMPLES: usize = (DELAY_START_MS * bark_protocol::SAMPLE_RATE.0 as usize) / 1000;
 pub const DELAY_START_PACKETS: usize = DELAY_START_SAMPLES / bark_protocol::FRAMES_PER_PACKET;
+
+pub const PACKET_DURATION_US: u64 =
+    (bark_protocol::FRAMES_PER_PACKET as u64 * 1_000_000) / bark_protocol::SAMPLE_RATE.0 as u64;
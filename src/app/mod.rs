@@ -8,12 +8,16 @@ use static_assertions::{const_assert, const_assert_eq};
 
 use bark_protocol::buffer::pbuf as bark_pbuf;
 
-use crate::system::task;
+use crate::system::heap::realtime::AudioPathTask;
+use crate::system::log::ratelimit::warn_ratelimited;
+use crate::system::task::{self, SpawnError};
+#[cfg(feature = "static-alloc")]
+use crate::system::task::TaskStack;
 
 mod consts;
 mod protocol;
 mod stream;
-mod timing;
+pub mod timing;
 mod queue;
 
 use protocol::{Protocol, BindError, SocketError};
@@ -30,10 +34,32 @@ const_assert!(
 const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(224, 100, 100, 100);
 const MULTICAST_PORT: u16 = 1530;
 
+#[cfg(feature = "static-alloc")]
+static APP_STACK: TaskStack<{ 16 * 1024 }> = TaskStack::new();
+
+/// How many times to try for the app's static stack, a tick apart, before
+/// deciding the app is still running
+const START_ATTEMPTS: u32 = 10;
+
 pub fn start() {
-    task::new("bark::app")
-        .spawn(task)
-        .expect("spawn app task");
+    for _ in 0..START_ATTEMPTS {
+        let builder = task::new("bark::app");
+
+        #[cfg(feature = "static-alloc")]
+        let builder = builder.static_stack(&APP_STACK);
+
+        match builder.spawn(task) {
+            Ok(()) => return,
+            Err(SpawnError::StackInUse) => {
+                // the previous app task may have exited but not suspended
+                // yet, give it a moment to get there:
+                unsafe { sys::vTaskDelay(1); }
+            }
+            Err(e) => panic!("spawn app task: {e:?}"),
+        }
+    }
+
+    log::warn!("app task already running, not starting another");
 }
 
 pub fn stop() {
@@ -53,11 +79,21 @@ async fn task() -> Result<(), AppError> {
     let mut protocol = Protocol::bind(MULTICAST_GROUP, MULTICAST_PORT)?;
     let mut receiver = Receiver::new();
 
+    // everything long-lived has been allocated by now, from here on we're
+    // on the audio path:
+    let _audio_path = AudioPathTask::register_current();
+
+    #[cfg(feature = "static-alloc")]
+    crate::system::heap::realtime::seal();
+
     loop {
         let (packet, addr) = match protocol.receive().await {
             Ok(result) => result,
+            Err(e@SocketError::ReceiveCallbackGone) => {
+                return Err(e.into());
+            }
             Err(e) => {
-                log::warn!("error receiving protocol packet: {e:?}");
+                warn_ratelimited!("error receiving protocol packet: {e:?}");
                 continue;
             }
         };
@@ -87,7 +123,7 @@ async fn task() -> Result<(), AppError> {
                 }
             }
             _ => {
-                log::warn!("received unhandled packet kind: {packet:?}");
+                warn_ratelimited!("received unhandled packet kind: {packet:?}");
             }
         }
     }
@@ -114,6 +150,9 @@ impl Receiver {
         };
 
         if new_stream {
+            // drop the old stream first so its resources can be reused:
+            self.stream = None;
+
             match Stream::new(sid, seq) {
                 Ok(stream) => { self.stream = Some(stream); }
                 Err(e) => {
@@ -9,21 +9,28 @@ use esp_pbuf::PbufUninit;
 
 use crate::platform::net;
 use crate::platform::net::NetError;
-use crate::platform::net::udp::Udp;
+use crate::platform::net::pool;
+use crate::platform::net::udp::{CallbackError, Udp};
 use crate::stats::STATS;
-use crate::sync::queue::{self, QueueReceiver, AllocQueueError};
-use crate::system::heap::MallocError;
+use crate::sync::spsc::{self, SpscReceiver};
+use crate::system::heap::{accounting, MallocError, Subsystem};
+use crate::system::heap::realtime::AudioPathTask;
+
+// spsc channels leave one slot empty, this holds 16 packets:
+const PACKET_QUEUE_SLOTS: usize = 17;
+
+type PacketQueueItem = Result<(PacketBuffer, SocketAddrV4), AllocError>;
 
 pub struct Protocol {
     socket: Udp,
-    packet_rx: QueueReceiver<Result<(PacketBuffer, SocketAddrV4), AllocError>>,
+    packet_rx: SpscReceiver<PacketQueueItem, PACKET_QUEUE_SLOTS>,
 }
 
 #[derive(Debug)]
 pub enum BindError {
     NewSocket(net::NetError),
-    AllocatePacketQueue(AllocQueueError),
-    SetOnReceiveCallback(MallocError),
+    AllocatePacketQueue(MallocError),
+    SetOnReceiveCallback(CallbackError),
     BindSocket(net::NetError),
     JoinMulticastGroup(net::NetError),
 }
@@ -32,6 +39,7 @@ pub enum BindError {
 pub enum SocketError {
     AllocatePacketBuffer(AllocError),
     Net(NetError),
+    ReceiveCallbackGone,
 }
 
 impl Protocol {
@@ -39,10 +47,16 @@ impl Protocol {
         let mut socket = net::udp::Udp::new()
             .map_err(BindError::NewSocket)?;
 
-        let (mut packet_tx, packet_rx) = queue::channel(16)
+        let (mut packet_tx, packet_rx) = spsc::channel()
             .map_err(BindError::AllocatePacketQueue)?;
 
+        // the callback runs on the lwIP thread, which we don't control, so
+        // register it as being on the audio path the first time we see it:
+        let mut audio_path = None;
+
         socket.on_receive(move |pbuf, addr| {
+            audio_path.get_or_insert_with(AudioPathTask::register_current);
+
             STATS.wifi_packets_received.increment();
 
             let buffer = PacketBuffer::from_raw(pbuf);
@@ -75,7 +89,13 @@ impl Protocol {
 
     pub async fn receive(&mut self) -> Result<(PacketKind, SocketAddrV4), SocketError> {
         loop {
-            let (buffer, addr) = self.packet_rx.receive().await?;
+            let Some(result) = self.packet_rx.receive().await else {
+                // the sender lives in the socket's receive callback, so this
+                // only happens if the callback has been torn down under us
+                return Err(SocketError::ReceiveCallbackGone);
+            };
+
+            let (buffer, addr) = result?;
             let Some(packet) = Packet::from_buffer(buffer) else { continue };
             let Some(packet) = packet.parse() else { continue };
             return Ok((packet, addr));
@@ -102,12 +122,25 @@ fn align_packet_buffer(buffer: PacketBuffer) -> Result<PacketBuffer, AllocError>
         return Ok(buffer);
     }
 
-    // packet is not aligned :( we have to reallocate + move it
+    // packet is not aligned :( we have to move it. try the pool first to
+    // keep malloc out of the receive path:
+    if let Some(pbuf) = pool::alloc_copy(buffer.as_bytes()) {
+        return Ok(PacketBuffer::from_raw(pbuf));
+    }
+
+    STATS.packet_pool_exhausted.increment();
+
+    // no pool buffer available, fall back to allocating a fresh pbuf
     let pbuf = PbufUninit::allocate_layout(
         bark_pbuf::ffi::PBUF_TRANSPORT,
         bark_pbuf::ffi::PBUF_RAM,
         Layout::from_size_align(buffer.len(), ALIGN).unwrap(),
-    ).map_err(AllocError)?;
+    ).map_err(|e| {
+        accounting::record_failure(Subsystem::Packet);
+        AllocError(e)
+    })?;
+
+    accounting::record_untracked_alloc(Subsystem::Packet);
 
     let pbuf = pbuf.copied_from_slice(buffer.as_bytes());
 
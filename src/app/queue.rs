@@ -1,32 +1,90 @@
+use derive_more::From;
 use heapless::Deque;
 
 use bark_protocol::packet::Audio;
 
 use crate::stats::STATS;
-use crate::system::heap::{SharedBox, MallocError};
+use crate::system::heap::{SharedBox, WeakBox, MallocError};
+use crate::system::heap::arc::SlotInUse;
+#[cfg(not(feature = "static-alloc"))]
+use crate::system::heap::{Caps, HeapBox, Subsystem};
+#[cfg(feature = "static-alloc")]
+use crate::system::heap::SharedSlot;
+use crate::system::journal::{self, Event};
+use crate::system::log::ratelimit::warn_ratelimited;
 use crate::sync::mutex::TaskMutex;
 
-use super::consts::MAX_QUEUED_PACKETS;
+use super::consts::{MAX_QUEUED_PACKETS, PACKET_DURATION_US};
 
-#[derive(Clone)]
 pub struct PacketQueue {
     shared: SharedBox<TaskMutex<Shared>>,
 }
 
+#[derive(Debug, From)]
+pub enum NewQueueError {
+    Alloc(MallocError),
+    SlotInUse(SlotInUse),
+}
+
+/// The previous stream's task can hang on to its queue for a moment after
+/// the stream has been replaced, so alternate between two slots
+#[cfg(feature = "static-alloc")]
+static QUEUE_SLOTS: [SharedSlot<TaskMutex<Shared>>; 2] = [SharedSlot::new(), SharedSlot::new()];
+
+/// Handle to a [`PacketQueue`] which doesn't keep it alive. Once the stream
+/// owning the queue is gone, upgrading fails.
+pub struct WeakPacketQueue {
+    shared: WeakBox<TaskMutex<Shared>>,
+}
+
+impl WeakPacketQueue {
+    pub fn upgrade(&self) -> Option<PacketQueue> {
+        let shared = self.shared.upgrade()?;
+        Some(PacketQueue { shared })
+    }
+}
+
 impl PacketQueue {
-    pub fn disconnected(&self) -> bool {
-        SharedBox::unique(&self.shared)
+    pub fn downgrade(&self) -> WeakPacketQueue {
+        WeakPacketQueue { shared: SharedBox::downgrade(&self.shared) }
     }
 
-    pub fn new(start_seq: u64) -> Result<Self, MallocError> {
-        let shared = Shared::new(start_seq);
-        let shared = SharedBox::alloc(TaskMutex::new(shared))?;
+    #[cfg(not(feature = "static-alloc"))]
+    pub fn new(start_seq: u64) -> Result<Self, NewQueueError> {
+        // packet storage is only touched from tasks, so can live in PSRAM
+        // where available, freeing up internal RAM. the lock guarding it
+        // can't, see Caps::SPIRAM:
+        let packets = HeapBox::alloc_caps(Subsystem::Stream, Caps::spiram_or_default(), Deque::new())?;
+
+        let shared = TaskMutex::new(Shared::new(start_seq, packets));
+        let shared = SharedBox::alloc_tagged(Subsystem::Stream, shared)?;
         Ok(PacketQueue { shared })
     }
 
+    #[cfg(feature = "static-alloc")]
+    pub fn new(start_seq: u64) -> Result<Self, NewQueueError> {
+        for slot in &QUEUE_SLOTS {
+            let shared = TaskMutex::new(Shared::new(start_seq, Deque::new()));
+
+            if let Ok(shared) = SharedBox::in_slot(slot, shared) {
+                return Ok(PacketQueue { shared });
+            }
+        }
+
+        Err(SlotInUse.into())
+    }
+
     pub async fn receive_packet(&self, packet: Audio) {
-        let mut shared = self.shared.lock().await;
-        shared.insert_packet(packet);
+        let result = {
+            let mut shared = self.shared.lock().await;
+            shared.insert_packet(packet)
+        };
+
+        // log once the lock is released, so the player isn't kept waiting
+        // on the logger:
+        if let Err(rejected) = result {
+            rejected.log();
+        }
     }
 
     pub async fn pop_front(&self) -> Option<Audio> {
@@ -35,10 +93,17 @@ impl PacketQueue {
     }
 }
 
+#[cfg(not(feature = "static-alloc"))]
+type Packets = HeapBox<Deque<Option<Audio>, MAX_QUEUED_PACKETS>>;
+#[cfg(feature = "static-alloc")]
+type Packets = Deque<Option<Audio>, MAX_QUEUED_PACKETS>;
+
 struct Shared {
-    queue: Deque<Option<Audio>, MAX_QUEUED_PACKETS>,
+    queue: Packets,
     /// The seq of the first packet in the queue, the rest are implied
     head_seq: u64,
+    /// Seq and arrival time of the latest packet received in order
+    last_arrival: Option<(u64, u64)>,
 }
 
 enum NoSlot {
@@ -46,41 +111,92 @@ enum NoSlot {
     TooFarInFuture,
 }
 
+/// Why a received packet wasn't queued
+enum Rejected {
+    Duplicate { packet_seq: u64 },
+    Late { head_seq: u64, packet_seq: u64 },
+    Early { tail_seq: u64, packet_seq: u64 },
+}
+
+impl Rejected {
+    fn log(&self) {
+        match *self {
+            Rejected::Duplicate { packet_seq } => {
+                warn_ratelimited!("received duplicate packet, retaining first received: packet_seq={packet_seq}");
+            }
+            Rejected::Late { head_seq, packet_seq } => {
+                warn_ratelimited!("received packet in past, dropping: head_seq={head_seq}, packet_seq={packet_seq}");
+            }
+            Rejected::Early { tail_seq, packet_seq } => {
+                warn_ratelimited!("received packet too far in future, dropping: tail_seq={tail_seq}, packet_seq={packet_seq}");
+            }
+        }
+    }
+}
+
 impl Shared {
-    pub fn new(start_seq: u64) -> Shared {
+    pub fn new(start_seq: u64, queue: Packets) -> Shared {
         Shared {
-            queue: Deque::default(),
-            head_seq: start_seq
+            queue,
+            head_seq: start_seq,
+            last_arrival: None,
         }
     }
 
-    pub fn insert_packet(&mut self, packet: Audio) {
+    pub fn insert_packet(&mut self, packet: Audio) -> Result<(), Rejected> {
         let packet_seq = packet.header().seq;
         let head_seq = self.head_seq;
         let tail_seq = self.head_seq + self.queue.capacity() as u64;
 
+        self.record_arrival(packet_seq);
+
         match self.queue_slot_mut(packet_seq) {
             Ok(slot@&mut None) => {
                 *slot = Some(packet);
                 STATS.audio_packets_received_on_time.increment();
+                STATS.packet_queue_depth.set(self.queue.len() as u32);
+                Ok(())
             }
-            Ok(Some(_)) => {
-                log::warn!("received duplicate packet, retaining first received: packet_seq={packet_seq}");
-            }
+            Ok(Some(_)) => Err(Rejected::Duplicate { packet_seq }),
             Err(NoSlot::InPast) => {
-                // log::warn!("received packet in past, dropping: head_seq={head_seq}, packet_seq={packet_seq}");
                 STATS.audio_packets_received_late.increment();
+                journal::record(Event::LatePacket);
+                Err(Rejected::Late { head_seq, packet_seq })
             }
             Err(NoSlot::TooFarInFuture) => {
-                // log::warn!("received packet too far in future, dropping: tail_seq={tail_seq}, packet_seq={packet_seq}");
                 STATS.audio_packets_received_early.increment();
+                Err(Rejected::Early { tail_seq, packet_seq })
             }
         }
     }
 
     pub fn pop_front(&mut self) -> Option<Audio> {
+        STATS.queue_depth_at_pop.record(self.queue.len() as u32);
+
         self.head_seq += 1;
-        self.queue.pop_front().flatten()
+        let packet = self.queue.pop_front().flatten();
+        STATS.packet_queue_depth.set(self.queue.len() as u32);
+        packet
+    }
+
+    /// Records how far this packet's arrival strayed from where its seq
+    /// says it should be relative to the previous packet
+    fn record_arrival(&mut self, seq: u64) {
+        let now = super::timestamp().0;
+
+        if let Some((last_seq, last_micros)) = self.last_arrival {
+            if seq <= last_seq {
+                // reordered or duplicate, measure against in order packets only
+                return;
+            }
+
+            let expected = (seq - last_seq) * PACKET_DURATION_US;
+            let actual = now.saturating_sub(last_micros);
+            let jitter = actual.abs_diff(expected);
+            STATS.arrival_jitter_us.record(jitter.try_into().unwrap_or(u32::MAX));
+        }
+
+        self.last_arrival = Some((seq, now));
     }
 
     fn head_seq(&self) -> u64 {
@@ -1,3 +1,5 @@
+use core::time::Duration;
+
 use bark_protocol::{SAMPLES_PER_PACKET, FRAMES_PER_PACKET};
 use derive_more::From;
 
@@ -6,12 +8,18 @@ use bark_protocol::types::SessionId;
 
 use crate::platform::dac::{Dac, DacError, NewDacError, Frame};
 use crate::stats::STATS;
-use crate::system::heap::MallocError;
+use crate::system::heap::realtime::AudioPathTask;
+use crate::system::journal::{self, Event};
 use crate::system::task::{self, SpawnError};
+#[cfg(feature = "static-alloc")]
+use crate::system::task::TaskStack;
 
 use super::consts::DELAY_START_PACKETS;
 use super::timing::Timing;
-use super::queue::PacketQueue;
+use super::queue::{NewQueueError, PacketQueue, WeakPacketQueue};
+
+#[cfg(feature = "static-alloc")]
+static STREAM_STACK: TaskStack<{ 16 * 1024 }> = TaskStack::new();
 
 #[allow(unused)]
 pub struct Stream {
@@ -28,7 +36,7 @@ enum BufferStart {
 
 #[derive(Debug, From)]
 pub enum NewStreamError {
-    AllocatePacketQueue(MallocError),
+    NewPacketQueue(NewQueueError),
     SpawnAudioTask(SpawnError),
 }
 
@@ -36,6 +44,8 @@ impl Stream {
     pub fn new(sid: SessionId, seq: u64) -> Result<Self, NewStreamError> {
         let queue = PacketQueue::new(seq)?;
 
+        journal::record(Event::SessionStart { sid });
+
         Ok(Stream {
             sid,
             timing: Timing::default(),
@@ -64,16 +74,37 @@ impl Stream {
     }
 
     fn start_task(&mut self) {
-        task::new("bark::stream")
+        // the stream task is woken for every packet's worth of audio, if it
+        // goes quiet for this long something is stuck:
+        let builder = task::new("bark::stream")
             .priority(16)
             .use_alternate_core()
-            .spawn({
-                let queue = self.queue.clone();
-                || async move { run_stream(queue).await }
-            })
-            .unwrap();
+            .watchdog_timeout(Duration::from_secs(2));
+
+        #[cfg(feature = "static-alloc")]
+        let builder = builder.static_stack(&STREAM_STACK);
+
+        let result = builder.spawn({
+            let queue = self.queue.downgrade();
+            || async move { run_stream(queue).await }
+        });
+
+        match result {
+            Ok(()) => {
+                self.start = BufferStart::Started;
+            }
+            Err(SpawnError::StackInUse) => {
+                // the previous stream's task hasn't noticed its stream is
+                // gone yet, try again on the next packet
+            }
+            Err(e) => panic!("spawn stream task: {e:?}"),
+        }
+    }
+}
 
-        self.start = BufferStart::Started;
+impl Drop for Stream {
+    fn drop(&mut self) {
+        journal::record(Event::SessionStop { sid: self.sid });
     }
 }
 
@@ -85,7 +116,9 @@ enum AudioTaskError {
 
 static SILENCE: [f32; SAMPLES_PER_PACKET] = [0.0; SAMPLES_PER_PACKET];
 
-async fn run_stream(queue: PacketQueue) -> Result<(), AudioTaskError> {
+async fn run_stream(weak_queue: WeakPacketQueue) -> Result<(), AudioTaskError> {
+    let _audio_path = AudioPathTask::register_current();
+
     let mut dac = Dac::new()?;
     dac.enable()?;
     dac.start_async_writing()?;
@@ -93,12 +126,15 @@ async fn run_stream(queue: PacketQueue) -> Result<(), AudioTaskError> {
     let mut buff = [Frame::default(); FRAMES_PER_PACKET];
 
     loop {
-        if queue.disconnected() {
+        // only hold the queue for as long as it takes to pop a packet, so
+        // that it's freed as soon as the stream goes away:
+        let Some(queue) = weak_queue.upgrade() else {
             log::warn!("PacketQueue has disconnected! stream task exiting");
             break;
-        }
+        };
 
         let packet = queue.pop_front().await;
+        drop(queue);
 
         match packet {
             Some(_) => { STATS.stream_hit.increment(); }
@@ -117,7 +153,10 @@ async fn run_stream(queue: PacketQueue) -> Result<(), AudioTaskError> {
             buff[i] = Frame(l, r);
         }
 
+        let write_start = super::timestamp();
         dac.write(&buff).await?;
+        let wait = super::timestamp().0.saturating_sub(write_start.0);
+        STATS.dac_write_wait_us.record(wait.try_into().unwrap_or(u32::MAX));
         // unsafe { esp_idf_sys::vTaskDelay(1); }
     }
 
@@ -4,8 +4,29 @@ use bark_protocol::packet::Time;
 use bark_protocol::time::ClockDelta;
 use heapless::{HistoryBuffer, Vec};
 
+use crate::stats::STATS;
+use crate::sync::mutex::CriticalMutex;
+
 const SAMPLE_HISTORY: usize = 64;
 
+static LATEST: CriticalMutex<Estimate> = CriticalMutex::declare(Estimate::NONE);
+
+/// Medians over the current stream's recent time packets
+#[derive(Clone, Copy)]
+pub struct Estimate {
+    pub network_latency: Option<Duration>,
+    pub clock_delta: Option<ClockDelta>,
+}
+
+impl Estimate {
+    pub const NONE: Estimate = Estimate { network_latency: None, clock_delta: None };
+}
+
+/// Latest estimate from whichever stream is playing, for reporting
+pub fn latest() -> Estimate {
+    *LATEST.lock()
+}
+
 #[derive(Default)]
 pub struct Timing {
     latency: Aggregate<Duration>,
@@ -23,11 +44,21 @@ impl Timing {
             return;
         };
 
-        let network_latency = Duration::from_micros(rtt_usec / 2);
+        let latency_usec = rtt_usec / 2;
+        STATS.network_latency_us.record(latency_usec.try_into().unwrap_or(u32::MAX));
+
+        let network_latency = Duration::from_micros(latency_usec);
         self.latency.observe(network_latency);
 
         let clock_delta = ClockDelta::from_time_packet(&packet);
         self.clock_delta.observe(clock_delta);
+
+        let estimate = Estimate {
+            network_latency: self.network_latency(),
+            clock_delta: self.clock_delta(),
+        };
+
+        *LATEST.lock() = estimate;
     }
 
     pub fn network_latency(&self) -> Option<Duration> {
@@ -39,6 +70,13 @@ impl Timing {
     }
 }
 
+impl Drop for Timing {
+    fn drop(&mut self) {
+        // stream has gone, its estimates are stale:
+        *LATEST.lock() = Estimate::NONE;
+    }
+}
+
 #[derive(Default)]
 pub struct Aggregate<T> {
     samples: HistoryBuffer<T, SAMPLE_HISTORY>
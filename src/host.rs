@@ -0,0 +1,188 @@
+//! Support for running unit tests on the build machine.
+//!
+//! Only the hardware independent parts of the crate are built for host
+//! tests, see the `cfg(test)` gates in `main.rs`. Those still reach ESP-IDF
+//! for critical sections, the timer and the heap, so [`sys`] stands in for
+//! the few bindings they use. Tasks are simulated by the host version of
+//! [`crate::system::task`].
+//!
+//! Run with `cargo test-host`.
+
+/// Virtual time, in microseconds since the test started. Each test thread
+/// has its own clock, which only moves when a test moves it.
+pub mod clock {
+    use std::cell::Cell;
+
+    thread_local! {
+        static NOW: Cell<i64> = const { Cell::new(0) };
+    }
+
+    pub fn now() -> i64 {
+        NOW.with(Cell::get)
+    }
+
+    pub fn set(micros: i64) {
+        NOW.with(|now| now.set(micros));
+    }
+}
+
+/// Stands in for the lwIP socket wrappers in `platform::net`
+pub mod net {
+    use core::net::SocketAddrV4;
+    use std::io;
+
+    pub struct UdpSocket {
+        socket: std::net::UdpSocket,
+    }
+
+    impl UdpSocket {
+        pub fn new() -> io::Result<Self> {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+            socket.set_nonblocking(true)?;
+            Ok(UdpSocket { socket })
+        }
+
+        pub fn send_to(&self, data: &[u8], addr: SocketAddrV4) -> io::Result<()> {
+            self.socket.send_to(data, addr).map(|_| ())
+        }
+    }
+}
+
+#[allow(non_camel_case_types, non_snake_case, non_upper_case_globals)]
+pub mod sys {
+    use core::ffi::{c_char, c_void};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::cell::Cell;
+
+    #[derive(Default)]
+    pub struct portMUX_TYPE {
+        pub owner: u32,
+        pub count: u32,
+    }
+
+    pub const SPINLOCK_FREE: u32 = 0xB33FFFFF;
+
+    /// Critical sections mask interrupts on the current core, which on the
+    /// host we approximate with one process wide lock. Like on target, a
+    /// thread already in a critical section may enter another.
+    static CRITICAL_OWNER: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        static CRITICAL_DEPTH: Cell<u32> = const { Cell::new(0) };
+    }
+
+    fn thread_token() -> usize {
+        thread_local! {
+            static TOKEN: u8 = const { 0 };
+        }
+
+        TOKEN.with(|token| token as *const u8 as usize)
+    }
+
+    pub unsafe fn rtos_taskENTER_CRITICAL(_spinlock: *const portMUX_TYPE) {
+        CRITICAL_DEPTH.with(|depth| {
+            if depth.get() == 0 {
+                while CRITICAL_OWNER
+                    .compare_exchange_weak(0, thread_token(), Ordering::Acquire, Ordering::Relaxed)
+                    .is_err()
+                {
+                    std::thread::yield_now();
+                }
+            }
+
+            depth.set(depth.get() + 1);
+        });
+    }
+
+    pub unsafe fn rtos_taskEXIT_CRITICAL(_spinlock: *const portMUX_TYPE) {
+        CRITICAL_DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+
+            if depth.get() == 0 {
+                CRITICAL_OWNER.store(0, Ordering::Release);
+            }
+        });
+    }
+
+    pub type TickType_t = u32;
+
+    pub const CONFIG_FREERTOS_HZ: u32 = 100;
+
+    pub unsafe fn esp_timer_get_time() -> i64 {
+        super::clock::now()
+    }
+
+    pub unsafe fn xPortInIsrContext() -> i32 {
+        0
+    }
+
+    pub enum tskTaskControlBlock {}
+
+    pub unsafe fn xTaskGetCurrentTaskHandle() -> *mut tskTaskControlBlock {
+        core::ptr::null_mut()
+    }
+
+    /// Every simulated task has the same name
+    pub unsafe fn pcTaskGetName(_task: *mut tskTaskControlBlock) -> *mut c_char {
+        cstr::cstr!("test").as_ptr().cast_mut()
+    }
+
+    pub const MALLOC_CAP_DEFAULT: u32 = 1 << 12;
+    pub const MALLOC_CAP_INTERNAL: u32 = 1 << 11;
+    pub const MALLOC_CAP_8BIT: u32 = 1 << 2;
+    pub const MALLOC_CAP_DMA: u32 = 1 << 3;
+    pub const MALLOC_CAP_SPIRAM: u32 = 1 << 10;
+
+    mod libc {
+        use core::ffi::c_void;
+
+        extern "C" {
+            pub fn malloc(size: usize) -> *mut c_void;
+            pub fn free(ptr: *mut c_void);
+            pub fn posix_memalign(ptr: *mut *mut c_void, align: usize, size: usize) -> i32;
+        }
+    }
+
+    pub unsafe fn malloc(size: u32) -> *mut c_void {
+        libc::malloc(size as usize)
+    }
+
+    pub unsafe fn free(ptr: *mut c_void) {
+        libc::free(ptr)
+    }
+
+    /// Every capability is satisfied by the host heap
+    pub unsafe fn heap_caps_malloc(size: usize, _caps: u32) -> *mut c_void {
+        libc::malloc(size)
+    }
+
+    pub unsafe fn heap_caps_aligned_alloc(align: usize, size: usize, _caps: u32) -> *mut c_void {
+        let mut ptr = core::ptr::null_mut();
+
+        match libc::posix_memalign(&mut ptr, align, size) {
+            0 => ptr,
+            _ => core::ptr::null_mut(),
+        }
+    }
+
+    pub unsafe fn heap_caps_aligned_free(ptr: *mut c_void) {
+        libc::free(ptr)
+    }
+
+    /// There is no PSRAM on the host
+    pub unsafe fn heap_caps_get_total_size(caps: u32) -> usize {
+        if caps & MALLOC_CAP_SPIRAM != 0 { 0 } else { usize::MAX }
+    }
+
+    pub unsafe fn heap_caps_get_free_size(caps: u32) -> usize {
+        heap_caps_get_total_size(caps)
+    }
+
+    pub unsafe fn heap_caps_get_largest_free_block(caps: u32) -> usize {
+        heap_caps_get_total_size(caps)
+    }
+
+    pub unsafe fn heap_caps_get_minimum_free_size(caps: u32) -> usize {
+        heap_caps_get_total_size(caps)
+    }
+}
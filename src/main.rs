@@ -1,24 +1,62 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), feature(alloc_error_handler))]
 #![feature(array_chunks)]
 #![feature(core_intrinsics)]
 #![feature(ip_in_core)]
 #![feature(sync_unsafe_cell)]
 #![feature(type_alias_impl_trait)]
 #![feature(waker_getters)]
+// host tests only build part of the crate, see host.rs:
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
+extern crate alloc;
+
+#[cfg(not(test))]
 mod app;
+#[cfg(not(test))]
 mod platform;
+#[cfg(not(test))]
 mod stats;
+// the hardware independent parts of these, for host tests:
+#[cfg(test)]
+mod platform {
+    pub mod net {
+        pub mod http;
+    }
+}
+#[cfg(test)]
+mod stats {
+    pub mod histogram;
+    pub mod prometheus {
+        pub mod text;
+    }
+}
 mod sync;
 mod system;
 
+#[cfg(test)]
+mod host;
+
+/// Code which is also built for host tests imports ESP-IDF through here
+#[cfg(not(test))]
+use esp_idf_sys as sys;
+#[cfg(test)]
+use host::sys;
+
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn app_main() {
     system::init();
     log::info!("System initialized");
 
+    system::boot::init();
+
+    #[cfg(feature = "bench")]
+    sync::bench::run();
+
     system::task::top::start();
+    system::log::deferred::start();
     stats::start();
 
     platform::init();
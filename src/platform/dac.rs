@@ -10,9 +10,12 @@ use core::task::{Context, Poll};
 
 use derive_more::From;
 use esp_idf_sys as sys;
+use log::Level;
 
 use crate::stats::STATS;
 use crate::sync::ringbuffer::RingBuffer;
+use crate::system::journal::{self, Event};
+use crate::system::log::deferred::deferred;
 use crate::system::task::TaskWakerSet;
 
 const DMA_BUFFER_COUNT: usize = 4;
@@ -202,9 +205,12 @@ unsafe extern "C" fn on_convert_done(
     if n < output.len() {
         output[n..].fill(DmaFrame::default());
         STATS.dac_underruns.increment();
+        journal::record(Event::Underrun);
+        deferred!(Level::Debug, "underrun, {} of {} frames short", output.len() - n, output.len());
     }
 
     STATS.dac_frames_sent.add(n as u32);
+    STATS.dac_buffer_fill.set(BUFFER.len() as u32);
 
     // notify writers that they can poll again:
     let result = WAKER.wake_from_isr();
@@ -5,7 +5,7 @@ use bitflags::bitflags;
 
 use crate::platform::wifi::WifiState;
 use crate::sync::EventGroup;
-use crate::system::task;
+use crate::system::{boot, task};
 
 pub mod dac;
 pub mod eventloop;
@@ -27,9 +27,35 @@ pub unsafe fn init() {
 
     eventloop::init();
     nvs::init();
+
+    if boot::is_safe_mode() {
+        log::warn!("safe mode: reverting to default config");
+
+        if let Err(e) = nvs::erase_config() {
+            log::error!("failed to erase config: {e:?}");
+        }
+    }
+
+    if let Err(e) = crate::system::log::filter::load() {
+        log::warn!("failed to load log filters: {e:?}");
+    }
+
+    if let Err(e) = crate::system::log::syslog::load() {
+        log::warn!("failed to load syslog config: {e:?}");
+    }
+
+    if let Err(e) = crate::stats::lifetime::load() {
+        log::warn!("failed to load lifetime totals: {e:?}");
+    }
+
     wifi::init();
+    crate::system::log::syslog::start();
+    net::http::server::start();
 
+    // platform_task blocks on the event group rather than awaiting it, so
+    // would trip the watchdog while waiting for events:
     task::new("bark::platform")
+        .no_watchdog()
         .spawn(platform_task)
         .expect("spawn platform task");
 }
@@ -54,6 +80,9 @@ fn on_wifi_event() {
     log::info!("Wifi event! current wifi state: {state:?}");
 
     match state {
+        WifiState::Online if boot::is_safe_mode() => {
+            log::warn!("safe mode: not starting app");
+        }
         WifiState::Online => { crate::app::start(); }
         WifiState::Disconnected => { crate::app::stop(); }
         _ => {}
@@ -6,6 +6,10 @@ use esp_idf_sys as sys;
 
 use crate::system::heap::MallocError;
 
+pub mod http;
+pub mod pool;
+pub mod socket;
+pub mod tcp;
 pub mod udp;
 
 pub fn join_multicast_group(group: Ipv4Addr) -> Result<(), NetError> {
@@ -86,6 +90,8 @@ fn netif() -> Result<*mut sys::netif, NetError> {
 pub enum NetError {
     NoNetif,
     NewSocket,
+    /// Only one socket at a time in static allocation mode
+    SocketInUse,
     Alloc(MallocError),
     Lwip(LwipError),
 }
@@ -0,0 +1,240 @@
+//! Just enough HTTP/1.0 to serve plain text to a scraper or `curl`. Each
+//! connection carries one request, of which only the request line is read,
+//! and is closed after the response. See [`server`] for what is served.
+
+use core::fmt::{self, Write};
+
+#[cfg(not(test))]
+pub mod server;
+
+/// Responses are sent in segments of up to this many bytes
+const SEGMENT_LEN: usize = 512;
+
+/// A connection to serve a request on, such as a
+/// [`super::tcp::TcpStream`]
+pub trait Connection {
+    type Error: fmt::Debug;
+
+    /// Returns 0 once the peer has closed its end
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Reads up to the end of the request headers. Requests too long for the
+/// buffer are cut short, we only need the request line.
+pub fn read_request<C: Connection>(conn: &mut C, buf: &mut [u8]) -> Result<usize, C::Error> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        let n = conn.read(&mut buf[len..])?;
+        if n == 0 {
+            break;
+        }
+
+        len += n;
+
+        if buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+
+    Ok(len)
+}
+
+/// Method, path and query string
+pub fn request_line(request: &[u8]) -> Option<(&str, &str, &str)> {
+    let end = request.iter().position(|b| *b == b'\r' || *b == b'\n')?;
+    let line = core::str::from_utf8(&request[..end]).ok()?;
+
+    let mut parts = line.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    Some((method, path, query))
+}
+
+/// Value of a query string parameter
+pub fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Buffers a response into reasonably sized segments. The connection is
+/// closed after each response, so no content length is needed.
+pub struct Response<'a, C: Connection> {
+    conn: &'a mut C,
+    buf: heapless::Vec<u8, SEGMENT_LEN>,
+    error: Option<C::Error>,
+}
+
+impl<'a, C: Connection> Response<'a, C> {
+    pub fn new(conn: &'a mut C) -> Self {
+        Response { conn, buf: heapless::Vec::new(), error: None }
+    }
+
+    pub fn status(&mut self, status: &str, content_type: &str) -> fmt::Result {
+        write!(self, "HTTP/1.0 {status}\r\nContent-Type: {content_type}\r\nConnection: close\r\n\r\n")
+    }
+
+    fn flush(&mut self) -> Result<(), C::Error> {
+        let result = self.conn.write_all(&self.buf);
+        self.buf.clear();
+        result
+    }
+
+    /// Sends whatever is still buffered, and reports the first failure to
+    /// write to the connection, if any
+    pub fn finish(mut self) -> Result<(), C::Error> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.flush(),
+        }
+    }
+}
+
+impl<C: Connection> Write for Response<'_, C> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.error.is_some() {
+            return Err(fmt::Error);
+        }
+
+        for chunk in s.as_bytes().chunks(self.buf.capacity()) {
+            if self.buf.len() + chunk.len() > self.buf.capacity() {
+                if let Err(e) = self.flush() {
+                    self.error = Some(e);
+                    return Err(fmt::Error);
+                }
+            }
+
+            // always fits, we just made room:
+            let _ = self.buf.extend_from_slice(chunk);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Serves a canned request, and records each write separately
+    struct TestConnection<'a> {
+        request: &'a [u8],
+        writes: Vec<Vec<u8>>,
+        fail_writes: bool,
+    }
+
+    impl<'a> TestConnection<'a> {
+        fn new(request: &'a [u8]) -> Self {
+            TestConnection { request, writes: Vec::new(), fail_writes: false }
+        }
+    }
+
+    #[derive(Debug)]
+    struct Closed;
+
+    impl Connection for TestConnection<'_> {
+        type Error = Closed;
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Closed> {
+            // a few bytes at a time, like a slow client:
+            let len = self.request.len().min(buf.len()).min(7);
+            buf[..len].copy_from_slice(&self.request[..len]);
+            self.request = &self.request[len..];
+            Ok(len)
+        }
+
+        fn write_all(&mut self, data: &[u8]) -> Result<(), Closed> {
+            if self.fail_writes {
+                return Err(Closed);
+            }
+
+            self.writes.push(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parses_request_line() {
+        assert_eq!(request_line(b"GET /metrics HTTP/1.1\r\nHost: bark\r\n\r\n"), Some(("GET", "/metrics", "")));
+        assert_eq!(request_line(b"POST /log/filter?level=debug&save=1 HTTP/1.1\r\n"), Some(("POST", "/log/filter", "level=debug&save=1")));
+        assert_eq!(request_line(b"GET /journal?since=4\n"), Some(("GET", "/journal", "since=4")));
+    }
+
+    #[test]
+    fn rejects_bad_request_line() {
+        // cut short before the end of the line:
+        assert_eq!(request_line(b"GET /metrics HTTP/1.1"), None);
+        // no path:
+        assert_eq!(request_line(b"GET\r\n"), None);
+        assert_eq!(request_line(b"GET /\xff HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn finds_query_params() {
+        let query = "level=debug&target=bark::app&save";
+
+        assert_eq!(query_param(query, "level"), Some("debug"));
+        assert_eq!(query_param(query, "target"), Some("bark::app"));
+        assert_eq!(query_param(query, "save"), None);
+        assert_eq!(query_param(query, "missing"), None);
+        assert_eq!(query_param("", "level"), None);
+    }
+
+    #[test]
+    fn reads_up_to_end_of_headers() {
+        let mut conn = TestConnection::new(b"GET / HTTP/1.1\r\nHost: bark\r\n\r\nextra body");
+        let mut buf = [0u8; 64];
+
+        let len = read_request(&mut conn, &mut buf).unwrap();
+
+        // stops after the read which saw the blank line:
+        assert_eq!(&buf[..len], b"GET / HTTP/1.1\r\nHost: bark\r\n\r\nextra");
+    }
+
+    #[test]
+    fn reads_truncated_request() {
+        let mut conn = TestConnection::new(b"GET /a/very/long/path HTTP/1.1\r\n\r\n");
+        let mut buf = [0u8; 16];
+
+        assert_eq!(read_request(&mut conn, &mut buf).unwrap(), 16);
+        assert_eq!(request_line(&buf), None);
+    }
+
+    #[test]
+    fn writes_response_in_segments() {
+        let mut conn = TestConnection::new(b"");
+        let body = "0123456789".repeat(130);
+
+        let mut response = Response::new(&mut conn);
+        response.status("200 OK", "text/plain").unwrap();
+        response.write_str(&body).unwrap();
+        response.finish().unwrap();
+
+        let head = "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n";
+        let lens = conn.writes.iter().map(Vec::len).collect::<Vec<_>>();
+
+        assert_eq!(lens, [head.len(), SEGMENT_LEN, SEGMENT_LEN, body.len() - 2 * SEGMENT_LEN]);
+        assert!(lens.iter().all(|len| *len <= SEGMENT_LEN));
+        assert_eq!(conn.writes.concat(), [head, &body].concat().as_bytes());
+    }
+
+    #[test]
+    fn reports_write_failure() {
+        let mut conn = TestConnection::new(b"");
+        conn.fail_writes = true;
+
+        let mut response = Response::new(&mut conn);
+        assert!(response.write_str(&"x".repeat(SEGMENT_LEN + 1)).is_err());
+        // later writes fail straight away:
+        assert!(response.write_str("x").is_err());
+        assert!(response.finish().is_err());
+    }
+}
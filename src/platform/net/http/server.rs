@@ -0,0 +1,245 @@
+//! The HTTP server, and the routes it serves:
+//!
+//! - `GET /metrics`: stats in the Prometheus text format, see
+//!   [`crate::stats::prometheus`]
+//! - `GET /journal`: the event journal as plain text. Pass `?since=<seq>` to
+//!   fetch only entries from that seq onwards.
+//! - `GET /log/filter`: the log filters, changed by POSTing to the same
+//!   path, see [`set_log_filter`]
+//! - `POST /lifetime/reset`: zeroes the lifetime totals
+//! - `GET /crashes`: the most recent crashes since power on, oldest first
+//! - `GET /log/history`: the most recent log output, carried over from
+//!   before the last reset, see [`crate::system::log::history`]
+//! - `GET /syslog`: the syslog collector logs are forwarded to, if any,
+//!   changed by POSTing to the same path, see [`set_syslog_collector`]
+
+use core::fmt::{self, Write};
+use core::time::Duration;
+
+use log::LevelFilter;
+
+use crate::platform::net::tcp::TcpListener;
+use crate::stats::{lifetime, prometheus};
+use crate::system::{crash, journal};
+use crate::system::log::{filter, history, syslog};
+use crate::system::task;
+
+use super::{query_param, read_request, request_line, Connection, Response};
+
+pub const PORT: u16 = 9100;
+
+const BACKLOG: i32 = 2;
+
+/// A client which takes longer than this is abandoned
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAX_REQUEST_LEN: usize = 512;
+
+pub fn start() {
+    // blocks in accept, so can't be watched:
+    let result = task::new("bark::http")
+        .stack_size(6 * 1024)
+        .no_watchdog()
+        .spawn(server_task);
+
+    if let Err(e) = result {
+        log::error!("failed to spawn http task: {e:?}");
+    }
+}
+
+async fn server_task() {
+    let listener = match TcpListener::bind(PORT, BACKLOG) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("failed to listen for http on port {PORT}: {e:?}");
+            return;
+        }
+    };
+
+    log::info!("serving http on port {PORT}");
+
+    loop {
+        let mut stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("failed to accept http connection: {e:?}");
+                task::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let result = stream.set_timeout(CLIENT_TIMEOUT)
+            .and_then(|()| serve(&mut stream));
+
+        if let Err(e) = result {
+            log::debug!("http client failed: {e:?}");
+        }
+    }
+}
+
+fn serve<C: Connection>(conn: &mut C) -> Result<(), C::Error> {
+    let mut request = [0u8; MAX_REQUEST_LEN];
+    let len = read_request(conn, &mut request)?;
+
+    let mut response = Response::new(conn);
+
+    let _ = match request_line(&request[..len]) {
+        Some(("GET", "/metrics", _)) => {
+            response.status("200 OK", "text/plain; version=0.0.4")
+                .and_then(|()| prometheus::write_metrics(&mut response))
+        }
+        Some(("GET", "/journal", query)) => {
+            response.status("200 OK", "text/plain")
+                .and_then(|()| write_journal(&mut response, query))
+        }
+        Some(("GET", "/log/filter", _)) => {
+            response.status("200 OK", "text/plain")
+                .and_then(|()| writeln!(response, "{}", filter::spec()))
+        }
+        Some(("POST", "/log/filter", query)) => set_log_filter(&mut response, query),
+        Some(("POST", "/lifetime/reset", _)) => reset_lifetime(&mut response),
+        Some(("GET", "/syslog", _)) => {
+            response.status("200 OK", "text/plain")
+                .and_then(|()| write_syslog_collector(&mut response))
+        }
+        Some(("POST", "/syslog", query)) => set_syslog_collector(&mut response, query),
+        Some(("GET", "/crashes", _)) => {
+            response.status("200 OK", "text/plain")
+                .and_then(|()| write_crashes(&mut response))
+        }
+        Some(("GET", "/log/history", _)) => write_log_history(&mut response),
+        Some(("GET", _, _)) => response.status("404 Not Found", "text/plain"),
+        Some(_) => response.status("405 Method Not Allowed", "text/plain"),
+        None => response.status("400 Bad Request", "text/plain"),
+    };
+
+    response.finish()
+}
+
+fn write_journal(out: &mut impl Write, query: &str) -> fmt::Result {
+    let since = query_param(query, "since")
+        .and_then(|since| since.parse().ok())
+        .unwrap_or(0);
+
+    let mut result = Ok(());
+
+    journal::read(since, |entry| {
+        result = result.and_then(|()| writeln!(out, "{entry}"));
+    });
+
+    result
+}
+
+fn write_crashes(out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "{} crashes since power on", crash::total())?;
+
+    for record in &crash::history() {
+        writeln!(out, "{record}")?;
+    }
+
+    Ok(())
+}
+
+/// Log lines are not captured while the history is being sent, they are
+/// counted as missed instead
+fn write_log_history<C: Connection>(response: &mut Response<C>) -> fmt::Result {
+    let mut result = Ok(());
+
+    let dumped = history::dump(|left, right| {
+        result = response.status("200 OK", "text/plain")
+            .and_then(|()| history::write_bytes(response, left))
+            .and_then(|()| history::write_bytes(response, right));
+    });
+
+    if !dumped {
+        response.status("503 Service Unavailable", "text/plain")?;
+        return writeln!(response, "log history busy, try again");
+    }
+
+    result?;
+    writeln!(response, "--- {} lines missed since boot ---", history::missed())
+}
+
+/// Sets the default log level with `?level=<level>`, or the level for one
+/// target with `&target=<target>` added. With `&save=1`, the resulting
+/// filters are persisted to NVS.
+fn set_log_filter<C: Connection>(response: &mut Response<C>, query: &str) -> fmt::Result {
+    let level = query_param(query, "level").and_then(|level| level.parse::<LevelFilter>().ok());
+
+    let Some(level) = level else {
+        response.status("400 Bad Request", "text/plain")?;
+        return writeln!(response, "missing or invalid level");
+    };
+
+    let result = match query_param(query, "target") {
+        Some(target) => filter::set(target, level),
+        None => {
+            filter::set_default(level);
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        response.status("400 Bad Request", "text/plain")?;
+        return writeln!(response, "{e:?}");
+    }
+
+    if query_param(query, "save").is_some() {
+        if let Err(e) = filter::save() {
+            response.status("500 Internal Server Error", "text/plain")?;
+            return writeln!(response, "failed to save log filters: {e:?}");
+        }
+    }
+
+    response.status("200 OK", "text/plain")?;
+    writeln!(response, "{}", filter::spec())
+}
+
+/// Forwards logs to the collector at `?addr=<ip>[:<port>]`, or stops
+/// forwarding with `?addr=` left empty. With `&save=1`, the collector is
+/// persisted to NVS.
+fn set_syslog_collector<C: Connection>(response: &mut Response<C>, query: &str) -> fmt::Result {
+    let addr = match query_param(query, "addr") {
+        Some("") => None,
+        Some(addr) => match syslog::parse_collector(addr) {
+            Some(addr) => Some(addr),
+            None => {
+                response.status("400 Bad Request", "text/plain")?;
+                return writeln!(response, "invalid addr, expected ip or ip:port");
+            }
+        },
+        None => {
+            response.status("400 Bad Request", "text/plain")?;
+            return writeln!(response, "missing addr");
+        }
+    };
+
+    syslog::set_collector(addr);
+
+    if query_param(query, "save").is_some() {
+        if let Err(e) = syslog::save() {
+            response.status("500 Internal Server Error", "text/plain")?;
+            return writeln!(response, "failed to save syslog collector: {e:?}");
+        }
+    }
+
+    response.status("200 OK", "text/plain")?;
+    write_syslog_collector(response)
+}
+
+fn write_syslog_collector(out: &mut impl Write) -> fmt::Result {
+    match syslog::collector() {
+        Some(addr) => writeln!(out, "{addr}"),
+        None => writeln!(out, "off"),
+    }
+}
+
+fn reset_lifetime<C: Connection>(response: &mut Response<C>) -> fmt::Result {
+    if let Err(e) = lifetime::reset() {
+        response.status("500 Internal Server Error", "text/plain")?;
+        return writeln!(response, "failed to reset lifetime totals: {e:?}");
+    }
+
+    response.status("200 OK", "text/plain")?;
+    writeln!(response, "{}", lifetime::totals())
+}
@@ -0,0 +1,149 @@
+//! Pool of preallocated, aligned packet buffers.
+//!
+//! Buffers are handed to lwIP as custom pbufs, so that when the last
+//! reference to one is dropped lwIP calls back into us and the buffer goes
+//! back on the free list rather than to the heap. The free list is a
+//! lock-free stack, safe to use from callbacks and ISRs.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr::{self, NonNull, addr_of_mut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use esp_idf_sys as sys;
+use esp_pbuf::PbufMut;
+use esp_pbuf::raw::PbufPtr;
+
+/// Enough to cover the protocol queue plus a typical jitter buffer, the
+/// receive path falls back to malloc beyond this
+const POOL_BUFFERS: usize = 24;
+
+/// Large enough for any packet that fits in an ethernet frame
+pub const POOL_BUFFER_SIZE: usize = 1536;
+
+/// Free list terminator
+const NIL: u32 = 0xffff;
+
+#[repr(C)]
+struct Slot {
+    // must be first, lwIP hands a pointer to this back to us on free:
+    custom: sys::pbuf_custom,
+    next_free: AtomicU32,
+    // u64 for 8 byte alignment of packet data:
+    data: [u64; POOL_BUFFER_SIZE / 8],
+}
+
+struct Pool {
+    slots: UnsafeCell<MaybeUninit<[Slot; POOL_BUFFERS]>>,
+    /// Head of the free list. Low 16 bits are the slot index, high 16 bits
+    /// a generation count, bumped on every update to avoid ABA problems
+    free_head: AtomicU32,
+    /// Slots at or above this index have never been handed out. Saves us
+    /// having to initialize the free list before first use.
+    untouched: AtomicU32,
+}
+
+unsafe impl Sync for Pool {}
+
+static POOL: Pool = Pool {
+    slots: UnsafeCell::new(MaybeUninit::uninit()),
+    free_head: AtomicU32::new(NIL),
+    untouched: AtomicU32::new(0),
+};
+
+impl Pool {
+    fn slot(&self, index: u32) -> *mut Slot {
+        self.slots.get().cast::<Slot>().wrapping_add(index as usize)
+    }
+
+    fn index_of(&self, slot: *const Slot) -> u32 {
+        let base = self.slots.get().cast::<Slot>() as usize;
+        ((slot as usize - base) / core::mem::size_of::<Slot>()) as u32
+    }
+
+    fn take(&self) -> Option<u32> {
+        let mut head = self.free_head.load(Ordering::Acquire);
+
+        loop {
+            let index = head & 0xffff;
+
+            if index == NIL {
+                break;
+            }
+
+            let next = unsafe { (*self.slot(index)).next_free.load(Ordering::Relaxed) };
+            let new_head = next_generation(head) | next;
+
+            match self.free_head.compare_exchange_weak(head, new_head, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => { return Some(index); }
+                Err(current) => { head = current; }
+            }
+        }
+
+        // free list is empty, try a slot that's never been used:
+        self.untouched.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |untouched| {
+            ((untouched as usize) < POOL_BUFFERS).then(|| untouched + 1)
+        }).ok()
+    }
+
+    fn put(&self, index: u32) {
+        let mut head = self.free_head.load(Ordering::Relaxed);
+
+        loop {
+            unsafe { (*self.slot(index)).next_free.store(head & 0xffff, Ordering::Relaxed); }
+            let new_head = next_generation(head) | index;
+
+            match self.free_head.compare_exchange_weak(head, new_head, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => { return; }
+                Err(current) => { head = current; }
+            }
+        }
+    }
+}
+
+fn next_generation(head: u32) -> u32 {
+    (head & 0xffff_0000).wrapping_add(0x1_0000)
+}
+
+/// Copies data into an aligned buffer from the pool. Returns `None` if data
+/// is too large or the pool is exhausted.
+pub fn alloc_copy(data: &[u8]) -> Option<PbufMut> {
+    if data.len() > POOL_BUFFER_SIZE {
+        return None;
+    }
+
+    let index = POOL.take()?;
+    let slot = POOL.slot(index);
+
+    unsafe {
+        // copy packet into slot before handing it to lwIP:
+        let payload = addr_of_mut!((*slot).data).cast::<u8>();
+        ptr::copy_nonoverlapping(data.as_ptr(), payload, data.len());
+
+        let custom = addr_of_mut!((*slot).custom);
+        (*custom).custom_free_function = Some(free_custom);
+
+        let pbuf = sys::pbuf_alloced_custom(
+            sys::pbuf_layer_PBUF_RAW,
+            data.len() as u16,
+            sys::pbuf_type_PBUF_REF,
+            custom,
+            payload.cast(),
+            POOL_BUFFER_SIZE as u16,
+        );
+
+        let Some(pbuf) = NonNull::new(pbuf) else {
+            POOL.put(index);
+            return None;
+        };
+
+        // freshly allocated, refcount is always 1:
+        PbufMut::try_from_ptr(PbufPtr::new(pbuf)).ok()
+    }
+}
+
+/// Called by lwIP when the last reference to one of our pbufs is dropped
+unsafe extern "C" fn free_custom(pbuf: *mut sys::pbuf) {
+    let slot = pbuf.cast::<Slot>();
+    POOL.put(POOL.index_of(slot));
+}
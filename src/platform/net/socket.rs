@@ -0,0 +1,69 @@
+//! Minimal send-only UDP socket over the lwIP sockets API, which unlike the
+//! raw API behind [`super::udp::Udp`] is safe to use from any task and
+//! doesn't need a pbuf.
+
+use core::mem;
+use core::net::SocketAddrV4;
+
+use esp_idf_sys as sys;
+
+use super::NetError;
+
+pub struct UdpSocket {
+    fd: i32,
+}
+
+#[derive(Debug)]
+pub struct SendError {
+    #[allow(unused)]
+    pub errno: i32,
+}
+
+impl UdpSocket {
+    pub fn new() -> Result<Self, NetError> {
+        let fd = unsafe {
+            sys::lwip_socket(sys::AF_INET as i32, sys::SOCK_DGRAM as i32, sys::IPPROTO_UDP as i32)
+        };
+
+        if fd < 0 {
+            return Err(NetError::NewSocket);
+        }
+
+        Ok(UdpSocket { fd })
+    }
+
+    /// Sends without blocking. Fails rather than waiting if lwIP is out of
+    /// buffers or there is no route to `addr`.
+    pub fn send_to(&self, data: &[u8], addr: SocketAddrV4) -> Result<(), SendError> {
+        let sockaddr = sys::sockaddr_in {
+            sin_len: mem::size_of::<sys::sockaddr_in>() as u8,
+            sin_family: sys::AF_INET as sys::sa_family_t,
+            sin_port: addr.port().to_be(),
+            sin_addr: sys::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) },
+            sin_zero: Default::default(),
+        };
+
+        let rc = unsafe {
+            sys::lwip_sendto(
+                self.fd,
+                data.as_ptr().cast(),
+                data.len(),
+                sys::MSG_DONTWAIT as i32,
+                (&sockaddr as *const sys::sockaddr_in).cast(),
+                mem::size_of::<sys::sockaddr_in>() as sys::socklen_t,
+            )
+        };
+
+        if rc < 0 {
+            return Err(SendError { errno: unsafe { *sys::__errno() } });
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        unsafe { sys::lwip_close(self.fd); }
+    }
+}
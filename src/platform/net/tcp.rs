@@ -0,0 +1,157 @@
+//! Blocking TCP over the lwIP sockets API, for low traffic services such as
+//! the HTTP server. Callers block, so should run on a task spawned
+//! with `no_watchdog`.
+
+use core::mem;
+use core::time::Duration;
+
+use esp_idf_sys as sys;
+
+use super::http::Connection;
+
+/// Failed socket call, with lwIP's errno
+#[derive(Debug)]
+pub struct TcpError {
+    #[allow(unused)]
+    pub errno: i32,
+}
+
+impl TcpError {
+    fn check(rc: i32) -> Result<i32, TcpError> {
+        if rc < 0 {
+            Err(TcpError { errno: unsafe { *sys::__errno() } })
+        } else {
+            Ok(rc)
+        }
+    }
+}
+
+pub struct TcpListener {
+    fd: i32,
+}
+
+impl TcpListener {
+    /// Listens on `port` on all interfaces
+    pub fn bind(port: u16, backlog: i32) -> Result<Self, TcpError> {
+        let fd = TcpError::check(unsafe {
+            sys::lwip_socket(sys::AF_INET as i32, sys::SOCK_STREAM as i32, sys::IPPROTO_TCP as i32)
+        })?;
+
+        // close the socket on any error from here on:
+        let listener = TcpListener { fd };
+
+        let reuse: i32 = 1;
+        TcpError::check(unsafe {
+            sys::lwip_setsockopt(
+                fd,
+                sys::SOL_SOCKET as i32,
+                sys::SO_REUSEADDR as i32,
+                (&reuse as *const i32).cast(),
+                mem::size_of::<i32>() as sys::socklen_t,
+            )
+        })?;
+
+        let sockaddr = sys::sockaddr_in {
+            sin_len: mem::size_of::<sys::sockaddr_in>() as u8,
+            sin_family: sys::AF_INET as sys::sa_family_t,
+            sin_port: port.to_be(),
+            sin_addr: sys::in_addr { s_addr: 0 },
+            sin_zero: Default::default(),
+        };
+
+        TcpError::check(unsafe {
+            sys::lwip_bind(
+                fd,
+                (&sockaddr as *const sys::sockaddr_in).cast(),
+                mem::size_of::<sys::sockaddr_in>() as sys::socklen_t,
+            )
+        })?;
+
+        TcpError::check(unsafe { sys::lwip_listen(fd, backlog) })?;
+
+        Ok(listener)
+    }
+
+    /// Blocks until a client connects
+    pub fn accept(&self) -> Result<TcpStream, TcpError> {
+        let fd = TcpError::check(unsafe {
+            sys::lwip_accept(self.fd, core::ptr::null_mut(), core::ptr::null_mut())
+        })?;
+
+        Ok(TcpStream { fd })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        unsafe { sys::lwip_close(self.fd); }
+    }
+}
+
+pub struct TcpStream {
+    fd: i32,
+}
+
+impl TcpStream {
+    /// Bounds how long reads and writes may block, so a stalled peer can't
+    /// wedge the calling task
+    pub fn set_timeout(&self, timeout: Duration) -> Result<(), TcpError> {
+        let timeval = sys::timeval {
+            tv_sec: timeout.as_secs() as _,
+            tv_usec: timeout.subsec_micros() as _,
+        };
+
+        for option in [sys::SO_RCVTIMEO, sys::SO_SNDTIMEO] {
+            TcpError::check(unsafe {
+                sys::lwip_setsockopt(
+                    self.fd,
+                    sys::SOL_SOCKET as i32,
+                    option as i32,
+                    (&timeval as *const sys::timeval).cast(),
+                    mem::size_of::<sys::timeval>() as sys::socklen_t,
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns 0 once the peer has closed its end
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        let n = TcpError::check(unsafe {
+            sys::lwip_recv(self.fd, buf.as_mut_ptr().cast(), buf.len(), 0) as i32
+        })?;
+
+        Ok(n as usize)
+    }
+
+    pub fn write_all(&mut self, mut data: &[u8]) -> Result<(), TcpError> {
+        while !data.is_empty() {
+            let n = TcpError::check(unsafe {
+                sys::lwip_send(self.fd, data.as_ptr().cast(), data.len(), 0) as i32
+            })?;
+
+            data = &data[n as usize..];
+        }
+
+        Ok(())
+    }
+}
+
+impl Connection for TcpStream {
+    type Error = TcpError;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        TcpStream::read(self, buf)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), TcpError> {
+        TcpStream::write_all(self, data)
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        unsafe { sys::lwip_close(self.fd); }
+    }
+}
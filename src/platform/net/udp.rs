@@ -3,25 +3,60 @@ use core::ptr::{self, NonNull};
 use core::net::SocketAddrV4;
 use core::ffi::c_void;
 use core::mem::ManuallyDrop;
+#[cfg(feature = "static-alloc")]
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use bitflags::bitflags;
+use derive_more::From;
 use esp_idf_sys as sys;
 
 use bark_protocol::buffer::PacketBuffer;
 use esp_pbuf::PbufMut;
 use esp_pbuf::raw::PbufPtr;
 
-use crate::system::heap::{HeapBox, UntypedHeapBox, MallocError};
+use crate::system::heap::{UntypedHeapBox, MallocError};
+use crate::system::heap::slot::SlotError;
+#[cfg(not(feature = "static-alloc"))]
+use crate::system::heap::{HeapBox, Subsystem};
+#[cfg(feature = "static-alloc")]
+use crate::system::heap::{StaticBox, StaticSlot};
 use crate::sync::EventGroup;
 
 use super::{NetError, LwipError, esp_to_rust_ipv4_addr, rust_to_esp_ip_addr};
 
 pub struct Udp {
     udp: UdpPtr,
-    eventgroup: Pin<HeapBox<EventGroup<Flags>>>,
+    eventgroup: EventGroupRef,
     receive_cb: Option<ManuallyDrop<UntypedHeapBox>>,
 }
 
+#[cfg(not(feature = "static-alloc"))]
+type EventGroupRef = Pin<HeapBox<EventGroup<Flags>>>;
+
+#[cfg(feature = "static-alloc")]
+type EventGroupRef = Pin<&'static EventGroup<Flags>>;
+
+/// In static allocation mode, the state behind the one and only socket
+#[cfg(feature = "static-alloc")]
+mod statics {
+    use super::*;
+
+    /// Room for the event group pointer plus a closure capturing a few
+    /// pointers' worth of state
+    const CALLBACK_WORDS: usize = 8;
+
+    pub static TAKEN: AtomicBool = AtomicBool::new(false);
+    pub static EVENTGROUP_INIT: AtomicBool = AtomicBool::new(false);
+    pub static EVENTGROUP: EventGroup<Flags> = EventGroup::declare();
+    pub static RECEIVE_CALLBACK: StaticSlot<CALLBACK_WORDS> = StaticSlot::new();
+}
+
+#[derive(Debug, From)]
+pub enum CallbackError {
+    Alloc(MallocError),
+    Slot(SlotError),
+}
+
 struct HeapCallback<F> {
     eventgroup: *const EventGroup<Flags>,
     func: F,
@@ -30,7 +65,7 @@ struct HeapCallback<F> {
 impl<F> HeapCallback<F> {
     pub fn eventgroup(&self) -> Pin<&EventGroup<Flags>> {
         // SAFETY: this event group always lives longer than HeapCallback.
-        // it is safely pinned behind a HeapBox too.
+        // it is safely pinned behind a HeapBox or in a static too.
         let eventgroup = unsafe { &*self.eventgroup };
         unsafe { Pin::new_unchecked(eventgroup) }
     }
@@ -51,13 +86,53 @@ bitflags! {
 
 impl Udp {
     pub fn new() -> Result<Udp, NetError> {
+        let eventgroup = Self::new_eventgroup()?;
+
         Ok(Udp {
             udp: UdpPtr::new(sys::lwip_ip_addr_type_IPADDR_TYPE_V4)?,
-            eventgroup: EventGroup::boxed(Flags::empty())?,
+            eventgroup,
             receive_cb: None,
         })
     }
 
+    #[cfg(not(feature = "static-alloc"))]
+    fn new_eventgroup() -> Result<EventGroupRef, NetError> {
+        Ok(EventGroup::boxed(Flags::empty())?)
+    }
+
+    #[cfg(feature = "static-alloc")]
+    fn new_eventgroup() -> Result<EventGroupRef, NetError> {
+        if statics::TAKEN.swap(true, Ordering::SeqCst) {
+            return Err(NetError::SocketInUse);
+        }
+
+        let eventgroup = Pin::static_ref(&statics::EVENTGROUP);
+
+        if statics::EVENTGROUP_INIT.swap(true, Ordering::SeqCst) {
+            // left over from a previous socket, just reset it:
+            eventgroup.clear(Flags::all());
+        } else {
+            unsafe { eventgroup.init_with(Flags::empty()); }
+        }
+
+        Ok(eventgroup)
+    }
+
+    /// Returns the erased callback along with a pointer to pass to lwIP
+    #[cfg(not(feature = "static-alloc"))]
+    fn alloc_callback<F>(callback: HeapCallback<F>) -> Result<(UntypedHeapBox, *mut c_void), CallbackError> {
+        let callback = HeapBox::alloc_tagged(Subsystem::Net, callback)?;
+        let ptr = HeapBox::as_borrowed_mut_ptr(&callback).cast();
+        Ok((HeapBox::erase_type(callback), ptr))
+    }
+
+    #[cfg(feature = "static-alloc")]
+    fn alloc_callback<F>(callback: HeapCallback<F>) -> Result<(UntypedHeapBox, *mut c_void), CallbackError> {
+        let callback = statics::RECEIVE_CALLBACK.put(callback)?;
+        let ptr = StaticBox::as_borrowed_mut_ptr(&callback).cast();
+        Ok((StaticBox::erase_type(callback), ptr))
+    }
+
     pub fn bind(&mut self, addr: SocketAddrV4) -> Result<(), NetError> {
         let port = addr.port();
         let ip_addr = rust_to_esp_ip_addr(*addr.ip());
@@ -114,10 +189,15 @@ impl Udp {
         Ok(())
     }
 
-    pub fn on_receive<F: FnMut(PbufMut, SocketAddrV4)>(&mut self, func: F) -> Result<(), MallocError> {
+    pub fn on_receive<F: FnMut(PbufMut, SocketAddrV4)>(&mut self, func: F) -> Result<(), CallbackError> {
+        // if there's a pre-existing callback, do the whole quiesce process.
+        // this must happen first, in static allocation mode the new callback
+        // goes in the same slot as the old one:
+        self.safely_free_receive_callback();
+
         let eventgroup = self.eventgroup.as_ref().get_ref();
 
-        let callback = HeapBox::alloc(HeapCallback {
+        let (callback, callback_ptr) = Self::alloc_callback(HeapCallback {
             eventgroup: eventgroup as *const _,
             func,
         })?;
@@ -164,15 +244,10 @@ impl Udp {
             }
         }
 
-        // if there's a pre-existing callback, do the whole quiesce process
-        self.safely_free_receive_callback();
-
         // set flags ready for initial callback run:
         self.eventgroup.as_ref().set(Flags::CALLBACK_SAFE);
 
         // set the callback:
-        let callback_ptr = HeapBox::as_borrowed_mut_ptr(&callback);
-        let callback_ptr = callback_ptr.cast::<c_void>();
         unsafe {
             sys::udp_recv(
                 self.udp.0.as_ptr(),
@@ -182,7 +257,7 @@ impl Udp {
         }
 
         // save the box on self and return:
-        self.receive_cb = Some(ManuallyDrop::new(HeapBox::erase_type(callback)));
+        self.receive_cb = Some(ManuallyDrop::new(callback));
         Ok(())
     }
 }
@@ -190,6 +265,9 @@ impl Udp {
 impl Drop for Udp {
     fn drop(&mut self) {
         self.safely_free_receive_callback();
+
+        #[cfg(feature = "static-alloc")]
+        statics::TAKEN.store(false, Ordering::SeqCst);
     }
 }
 
@@ -1,7 +1,149 @@
+use core::ffi::CStr;
+use core::str::FromStr;
+
+use cstr::cstr;
 use esp_idf_sys as sys;
 
+/// Namespace holding our persisted config, as opposed to data such as
+/// counters which should survive a config reset
+pub const CONFIG_NAMESPACE: &CStr = cstr!("bark");
+
+/// Namespace holding lifetime counters
+pub const COUNTERS_NAMESPACE: &CStr = cstr!("bark_counters");
+
 pub unsafe fn init() {
     if let Err(e) = sys::esp!(sys::nvs_flash_init()) {
         log::warn!("nvs_flash_init failed: {e:?}");
     }
 }
+
+/// Erases all persisted config, reverting to defaults
+pub unsafe fn erase_config() -> Result<(), sys::EspError> {
+    erase_namespace(CONFIG_NAMESPACE)
+}
+
+/// Erases all lifetime counters
+pub unsafe fn erase_counters() -> Result<(), sys::EspError> {
+    erase_namespace(COUNTERS_NAMESPACE)
+}
+
+unsafe fn erase_namespace(namespace: &CStr) -> Result<(), sys::EspError> {
+    let mut handle = 0;
+    sys::esp!(sys::nvs_open(namespace.as_ptr(), sys::nvs_open_mode_t_NVS_READWRITE, &mut handle))?;
+
+    let result = sys::esp!(sys::nvs_erase_all(handle))
+        .and_then(|()| sys::esp!(sys::nvs_commit(handle)));
+
+    sys::nvs_close(handle);
+    result
+}
+
+/// Longest string accepted by write_config_str
+const MAX_STR_LEN: usize = 512;
+
+/// Reads a string from the config namespace. Returns None if it has never
+/// been set, or doesn't fit in `N - 1` bytes.
+pub unsafe fn read_config_str<const N: usize>(key: &CStr) -> Result<Option<heapless::String<N>>, sys::EspError> {
+    let mut handle = 0;
+    let rc = sys::nvs_open(CONFIG_NAMESPACE.as_ptr(), sys::nvs_open_mode_t_NVS_READONLY, &mut handle);
+
+    // namespace doesn't exist until something is first written to it:
+    if rc == sys::ESP_ERR_NVS_NOT_FOUND as sys::esp_err_t {
+        return Ok(None);
+    }
+
+    sys::esp!(rc)?;
+
+    // includes the nul terminator:
+    let mut buff = [0u8; N];
+    let mut len = N;
+    let rc = sys::nvs_get_str(handle, key.as_ptr(), buff.as_mut_ptr().cast(), &mut len);
+    sys::nvs_close(handle);
+
+    if rc == sys::ESP_ERR_NVS_NOT_FOUND as sys::esp_err_t {
+        return Ok(None);
+    }
+
+    if rc == sys::ESP_ERR_NVS_INVALID_LENGTH as sys::esp_err_t {
+        log::warn!("config value {key:?} too long, ignoring");
+        return Ok(None);
+    }
+
+    sys::esp!(rc)?;
+
+    let value = CStr::from_bytes_until_nul(&buff).ok()
+        .and_then(|s| s.to_str().ok())
+        .and_then(|s| heapless::String::from_str(s).ok());
+
+    Ok(value)
+}
+
+/// Writes a string to the config namespace
+pub unsafe fn write_config_str(key: &CStr, value: &str) -> Result<(), sys::EspError> {
+    let mut value_cstr = heapless::Vec::<u8, { MAX_STR_LEN + 1 }>::new();
+
+    if value.contains('\0') {
+        return sys::esp!(sys::ESP_ERR_INVALID_ARG as sys::esp_err_t);
+    }
+
+    if value_cstr.extend_from_slice(value.as_bytes()).is_err() {
+        return sys::esp!(sys::ESP_ERR_NVS_VALUE_TOO_LONG as sys::esp_err_t);
+    }
+
+    let _ = value_cstr.push(0);
+
+    let mut handle = 0;
+    sys::esp!(sys::nvs_open(CONFIG_NAMESPACE.as_ptr(), sys::nvs_open_mode_t_NVS_READWRITE, &mut handle))?;
+
+    let result = sys::esp!(sys::nvs_set_str(handle, key.as_ptr(), value_cstr.as_ptr().cast()))
+        .and_then(|()| sys::esp!(sys::nvs_commit(handle)));
+
+    sys::nvs_close(handle);
+    result
+}
+
+/// Reads counters from the counters namespace into `values`, leaving any
+/// which have never been written at zero
+pub unsafe fn read_counters(keys: &[&CStr], values: &mut [u64]) -> Result<(), sys::EspError> {
+    values.fill(0);
+
+    let mut handle = 0;
+    let rc = sys::nvs_open(COUNTERS_NAMESPACE.as_ptr(), sys::nvs_open_mode_t_NVS_READONLY, &mut handle);
+
+    if rc == sys::ESP_ERR_NVS_NOT_FOUND as sys::esp_err_t {
+        return Ok(());
+    }
+
+    sys::esp!(rc)?;
+
+    let mut result = Ok(());
+
+    for (key, value) in keys.iter().zip(values.iter_mut()) {
+        let rc = sys::nvs_get_u64(handle, key.as_ptr(), value);
+
+        if rc == sys::ESP_ERR_NVS_NOT_FOUND as sys::esp_err_t {
+            continue;
+        }
+
+        if let Err(e) = sys::esp!(rc) {
+            *value = 0;
+            result = Err(e);
+        }
+    }
+
+    sys::nvs_close(handle);
+    result
+}
+
+/// Writes counters to the counters namespace in a single commit
+pub unsafe fn write_counters(counters: &[(&CStr, u64)]) -> Result<(), sys::EspError> {
+    let mut handle = 0;
+    sys::esp!(sys::nvs_open(COUNTERS_NAMESPACE.as_ptr(), sys::nvs_open_mode_t_NVS_READWRITE, &mut handle))?;
+
+    let result = counters.iter()
+        .try_for_each(|(key, value)| sys::esp!(sys::nvs_set_u64(handle, key.as_ptr(), *value)))
+        .and_then(|()| sys::esp!(sys::nvs_commit(handle)));
+
+    sys::nvs_close(handle);
+    result
+}
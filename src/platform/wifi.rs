@@ -1,5 +1,6 @@
 use core::ffi::c_void;
 use core::mem::MaybeUninit;
+use core::net::Ipv4Addr;
 use core::ptr;
 use core::sync::atomic::Ordering;
 
@@ -7,6 +8,8 @@ use atomic_enum::atomic_enum;
 use esp_idf_sys::{self as sys, EspError};
 
 use crate::platform::{self, PlatformEvent};
+use crate::stats::STATS;
+use crate::system::journal::{self, Event};
 
 const SSID: &str = env!("BARK_WIFI_SSID");
 const PASSWORD: &str = env!("BARK_WIFI_PASS");
@@ -153,6 +156,37 @@ fn fixed<const N: usize>(s: &str) -> [u8; N] {
     buff
 }
 
+/// Signal strength of the access point we're associated with, in dBm
+pub fn rssi() -> Option<i8> {
+    let mut info = MaybeUninit::<sys::wifi_ap_record_t>::zeroed();
+
+    let rc = unsafe { sys::esp_wifi_sta_get_ap_info(info.as_mut_ptr()) };
+    if rc != sys::ESP_OK as sys::esp_err_t {
+        return None;
+    }
+
+    Some(unsafe { info.assume_init() }.rssi)
+}
+
+/// Names for the disconnect reasons we're most likely to see
+pub fn disconnect_reason_name(reason: u16) -> Option<&'static str> {
+    let name = match u32::from(reason) {
+        sys::wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE => "auth expired",
+        sys::wifi_err_reason_t_WIFI_REASON_AUTH_LEAVE => "auth leave",
+        sys::wifi_err_reason_t_WIFI_REASON_ASSOC_LEAVE => "assoc leave",
+        sys::wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT => "4way handshake timeout",
+        sys::wifi_err_reason_t_WIFI_REASON_BEACON_TIMEOUT => "beacon timeout",
+        sys::wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND => "no ap found",
+        sys::wifi_err_reason_t_WIFI_REASON_AUTH_FAIL => "auth failed",
+        sys::wifi_err_reason_t_WIFI_REASON_ASSOC_FAIL => "assoc failed",
+        sys::wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT => "handshake timeout",
+        sys::wifi_err_reason_t_WIFI_REASON_CONNECTION_FAIL => "connection failed",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
 type EventHandlerFunc = unsafe extern "C" fn(
     *mut c_void,
     sys::esp_event_base_t,
@@ -179,7 +213,7 @@ unsafe extern "C" fn on_wifi_event(
     _: *mut c_void,
     _: sys::esp_event_base_t,
     msg: i32,
-    _param: *mut c_void,
+    param: *mut c_void,
 ) {
     match msg as u32 {
         sys::wifi_event_t_WIFI_EVENT_STA_START => {
@@ -187,6 +221,14 @@ unsafe extern "C" fn on_wifi_event(
             platform::raise_event(PlatformEvent::WIFI);
         }
         sys::wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
+            STATS.wifi_disconnects.increment();
+
+            let event = &*param.cast::<sys::wifi_event_sta_disconnected_t>();
+            journal::record(Event::WifiDisconnected {
+                reason: event.reason as u16,
+                rssi: event.rssi,
+            });
+
             STATE.store(WifiState::Disconnected, Ordering::SeqCst);
             platform::raise_event(PlatformEvent::WIFI);
         }
@@ -199,10 +241,15 @@ unsafe extern "C" fn on_ip_event(
     _: *mut c_void,
     _: sys::esp_event_base_t,
     msg: i32,
-    _param: *mut c_void,
+    param: *mut c_void,
 ) {
     match msg as u32 {
         sys::ip_event_t_IP_EVENT_STA_GOT_IP => {
+            let event = &*param.cast::<sys::ip_event_got_ip_t>();
+            // lwip addresses are in network order:
+            let ip = Ipv4Addr::from(event.ip_info.ip.addr.to_le_bytes());
+            journal::record(Event::WifiConnected { ip });
+
             STATE.store(WifiState::Online, Ordering::SeqCst);
             platform::raise_event(PlatformEvent::WIFI);
         }
@@ -1,13 +1,33 @@
+use core::cell::SyncUnsafeCell;
 use core::sync::atomic::{AtomicU32, Ordering};
-use esp_idf_sys as sys;
-use esp_println::println;
+use core::time::Duration;
+use crate::sync::mutex::CriticalMutex;
+use crate::system::log::ratelimit;
 use crate::system::task;
 
+pub mod histogram;
+pub mod lifetime;
+pub mod prometheus;
+pub mod report;
+pub mod snapshot;
+
+pub use histogram::Histogram;
+pub use report::Reporter;
+pub use snapshot::Snapshot;
+
+use snapshot::History;
+
 pub static STATS: Stats = Stats::new();
 
+pub const NUM_COUNTERS: usize = 11;
+pub const NUM_GAUGES: usize = 2;
+pub const NUM_HISTOGRAMS: usize = 4;
+
 pub struct Stats {
+    pub wifi_disconnects: Counter,
     pub wifi_packets_received: Counter,
     pub packets_dropped_in_protocol_queue: Counter,
+    pub packet_pool_exhausted: Counter,
     pub audio_packets_received_on_time: Counter,
     pub audio_packets_received_late: Counter,
     pub audio_packets_received_early: Counter,
@@ -15,13 +35,28 @@ pub struct Stats {
     pub stream_miss: Counter,
     pub dac_frames_sent: Counter,
     pub dac_underruns: Counter,
+    /// Frames waiting in the DAC ring buffer
+    pub dac_buffer_fill: Gauge,
+    /// Packet slots spanned by the stream's packet queue
+    pub packet_queue_depth: Gauge,
+    /// Deviation of audio packet arrival times from their spacing in the
+    /// stream, in microseconds
+    pub arrival_jitter_us: Histogram,
+    /// Packet queue depth as seen by the stream task when it pops a packet
+    pub queue_depth_at_pop: Histogram,
+    /// Time the stream task spends waiting for room in the DAC buffer
+    pub dac_write_wait_us: Histogram,
+    /// One way network latency, estimated from time packets
+    pub network_latency_us: Histogram,
 }
 
 impl Stats {
     pub const fn new() -> Self {
         Stats {
+            wifi_disconnects: Counter::new(),
             wifi_packets_received: Counter::new(),
             packets_dropped_in_protocol_queue: Counter::new(),
+            packet_pool_exhausted: Counter::new(),
             audio_packets_received_on_time: Counter::new(),
             audio_packets_received_late: Counter::new(),
             audio_packets_received_early: Counter::new(),
@@ -29,10 +64,64 @@ impl Stats {
             stream_miss: Counter::new(),
             dac_frames_sent: Counter::new(),
             dac_underruns: Counter::new(),
+            dac_buffer_fill: Gauge::new(),
+            packet_queue_depth: Gauge::new(),
+            arrival_jitter_us: Histogram::new(),
+            queue_depth_at_pop: Histogram::new(),
+            dac_write_wait_us: Histogram::new(),
+            network_latency_us: Histogram::new(),
         }
     }
+
+    pub fn counters(&self) -> [(MetricName, &Counter); NUM_COUNTERS] {
+        [
+            (MetricName::new("WiFi", "disconnects", "Times the WiFi connection was lost"), &self.wifi_disconnects),
+            (MetricName::new("Network", "recv", "Packets received from the network"), &self.wifi_packets_received),
+            (MetricName::new("Network", "queue_drop", "Packets dropped because the protocol queue was full"), &self.packets_dropped_in_protocol_queue),
+            (MetricName::new("Network", "pool_exhausted", "Packets dropped because the packet buffer pool was empty"), &self.packet_pool_exhausted),
+            (MetricName::new("Queue", "on_time", "Audio packets queued in time to be played"), &self.audio_packets_received_on_time),
+            (MetricName::new("Queue", "late", "Audio packets received too late to be played"), &self.audio_packets_received_late),
+            (MetricName::new("Queue", "early", "Audio packets received too far ahead to be queued"), &self.audio_packets_received_early),
+            (MetricName::new("Stream", "hit", "Packets present when the stream went to play them"), &self.stream_hit),
+            (MetricName::new("Stream", "miss", "Packets missing when the stream went to play them"), &self.stream_miss),
+            (MetricName::new("DAC", "frames_sent", "Frames written to the DAC"), &self.dac_frames_sent),
+            (MetricName::new("DAC", "underruns", "Times the DAC ran out of frames to play"), &self.dac_underruns),
+        ]
+    }
+
+    pub fn gauges(&self) -> [(MetricName, &Gauge); NUM_GAUGES] {
+        [
+            (MetricName::new("DAC", "buffer_fill", "Frames waiting in the DAC ring buffer"), &self.dac_buffer_fill),
+            (MetricName::new("Queue", "depth", "Packet slots spanned by the stream's packet queue"), &self.packet_queue_depth),
+        ]
+    }
+
+    pub fn histograms(&self) -> [(MetricName, &Histogram); NUM_HISTOGRAMS] {
+        [
+            (MetricName::new("Network", "jitter_us", "Deviation of audio packet arrival times from their spacing in the stream"), &self.arrival_jitter_us),
+            (MetricName::new("Network", "latency_us", "One way network latency, estimated from time packets"), &self.network_latency_us),
+            (MetricName::new("Queue", "depth_at_pop", "Packet queue depth when the stream task pops a packet"), &self.queue_depth_at_pop),
+            (MetricName::new("DAC", "write_wait_us", "Time the stream task spends waiting for room in the DAC buffer"), &self.dac_write_wait_us),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MetricName {
+    pub group: &'static str,
+    pub name: &'static str,
+    /// One line description, for metrics exports
+    pub help: &'static str,
 }
 
+impl MetricName {
+    pub const fn new(group: &'static str, name: &'static str, help: &'static str) -> Self {
+        MetricName { group, name, help }
+    }
+}
+
+/// Monotonic event count since boot. Wraps at u32::MAX, readers should
+/// take differences with wrapping_sub.
 #[derive(Default)]
 pub struct Counter {
     value: AtomicU32,
@@ -51,46 +140,118 @@ impl Counter {
         self.value.fetch_add(n, Ordering::Relaxed);
     }
 
-    pub fn take(&self) -> u32 {
-        self.value.swap(0, Ordering::Relaxed)
+    pub fn total(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Current value of something, such as a buffer fill level, along with the
+/// lowest and highest values it was set to since the stats task last
+/// sampled it. May be set from interrupt context.
+pub struct Gauge {
+    value: AtomicU32,
+    min: AtomicU32,
+    max: AtomicU32,
+}
+
+/// Value of a gauge over one sampling window
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GaugeWindow {
+    pub value: u32,
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Gauge {
+            value: AtomicU32::new(0),
+            min: AtomicU32::new(u32::MAX),
+            max: AtomicU32::new(0),
+        }
+    }
+
+    pub fn set(&self, value: u32) {
+        self.value.store(value, Ordering::Relaxed);
+        self.min.fetch_min(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Starts a new window, returning the one just ended
+    fn take_window(&self) -> GaugeWindow {
+        let value = self.get();
+        let min = self.min.swap(u32::MAX, Ordering::Relaxed);
+        let max = self.max.swap(0, Ordering::Relaxed);
+
+        if min > max {
+            // not set during the window, so it held steady:
+            GaugeWindow { value, min: value, max: value }
+        } else {
+            GaugeWindow { value, min, max }
+        }
     }
 }
 
+static LATEST: CriticalMutex<Snapshot> = CriticalMutex::declare(Snapshot::EMPTY);
+
+/// Several KiB, too big for the stats task's stack. Only ever touched by
+/// the stats task
+static HISTORY: SyncUnsafeCell<History> = SyncUnsafeCell::new(History::new());
+
+/// Most recent snapshot taken by the stats task, updated every second
+#[allow(unused)]
+pub fn latest() -> Snapshot {
+    LATEST.lock().clone()
+}
+
 pub fn start() {
+    static CRASHES: report::CrashReporter = report::CrashReporter::new();
+    static JOURNAL: report::JournalReporter = report::JournalReporter::new();
+
+    let reporters: &[&'static dyn Reporter] = &[
+        &CRASHES,
+        &report::HeapReporter,
+        &report::SyslogReporter,
+        &JOURNAL,
+        &report::TextReporter,
+        #[cfg(feature = "stats-json")]
+        &report::JsonReporter,
+        #[cfg(feature = "stats-log")]
+        &report::LogReporter,
+    ];
+
+    for reporter in reporters {
+        report::register(*reporter)
+            .expect("register stats reporter");
+    }
+
     task::new("bark::stats")
         .spawn(task)
         .unwrap();
 }
 
 async fn task() {
+    let mut secs = 0u32;
+
+    // SAFETY: there is only one stats task
+    let history = unsafe { &mut *HISTORY.get() };
+
     loop {
-        unsafe { sys::vTaskDelay(1000); }
-
-        println!();
-
-        println!(
-            "Network:[recv:{}/s queue_drop:{}/s]",
-            STATS.wifi_packets_received.take(),
-            STATS.packets_dropped_in_protocol_queue.take(),
-        );
-
-        println!(
-            "Queue:[on_time:{}/s late:{}/s early:{}/s]",
-            STATS.audio_packets_received_on_time.take(),
-            STATS.audio_packets_received_late.take(),
-            STATS.audio_packets_received_early.take()
-        );
-
-        println!(
-            "Stream:[hit:{}/s miss:{}/s]",
-            STATS.stream_hit.take(),
-            STATS.stream_miss.take(),
-        );
-
-        println!(
-            "DAC:[frames_sent:{}/s underruns:{}/s]",
-            STATS.dac_frames_sent.take(),
-            STATS.dac_underruns.take(),
-        )
+        // sleep asynchronously rather than blocking, so that the executor
+        // can keep the task watchdog fed:
+        task::time::sleep(Duration::from_secs(1)).await;
+
+        secs = secs.wrapping_add(1);
+
+        ratelimit::flush();
+        lifetime::tick(secs);
+
+        let snapshot = history.sample(&STATS, secs);
+        *LATEST.lock() = snapshot.clone();
+        report::run_reporters(&snapshot);
     }
 }
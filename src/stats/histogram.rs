@@ -0,0 +1,108 @@
+//! Fixed bucket histograms, for distributions such as latency or queue
+//! depth. Recording is a couple of atomic adds, so is fine from interrupt
+//! handlers and callbacks.
+//!
+//! Buckets are powers of two: bucket 0 counts zeroes, bucket `n` counts
+//! values in `2^(n-1)..2^n`, and the last bucket counts everything larger.
+//! Percentiles are reported as the upper bound of the bucket they fall in,
+//! so are never an underestimate.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub const BUCKETS: usize = 20;
+
+pub type Buckets = [u32; BUCKETS];
+
+pub struct Histogram {
+    buckets: [AtomicU32; BUCKETS],
+    sum: AtomicU32,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU32::new(0) }; BUCKETS],
+            sum: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record(&self, value: u32) {
+        let bucket = (32 - value.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Counts since boot, wrapping
+    pub fn totals(&self) -> Buckets {
+        core::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+
+    /// Sum of all values recorded since boot, wrapping
+    pub fn sum(&self) -> u32 {
+        self.sum.load(Ordering::Relaxed)
+    }
+}
+
+/// Largest value counted in a bucket, or None for the last bucket
+pub fn upper_bound(bucket: usize) -> Option<u32> {
+    if bucket >= BUCKETS - 1 {
+        None
+    } else {
+        Some(((1u64 << bucket) - 1) as u32)
+    }
+}
+
+/// Bucket counts between two readings of [`Histogram::totals`]
+pub fn delta(now: &Buckets, before: &Buckets) -> Buckets {
+    core::array::from_fn(|i| now[i].wrapping_sub(before[i]))
+}
+
+/// Summary of a histogram's buckets. Values in the last bucket are
+/// reported as `u32::MAX`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Distribution {
+    pub count: u32,
+    pub p50: u32,
+    pub p90: u32,
+    pub p99: u32,
+    pub max: u32,
+}
+
+impl Distribution {
+    pub const EMPTY: Distribution = Distribution { count: 0, p50: 0, p90: 0, p99: 0, max: 0 };
+
+    pub fn from_buckets(buckets: &Buckets) -> Self {
+        let count = buckets.iter().fold(0u32, |sum, n| sum.saturating_add(*n));
+
+        if count == 0 {
+            return Distribution::EMPTY;
+        }
+
+        let bound = |bucket: usize| upper_bound(bucket).unwrap_or(u32::MAX);
+
+        let percentile = |pct: u64| {
+            // smallest bucket reaching pct% of samples, rounding up:
+            let target = (u64::from(count) * pct).div_ceil(100);
+            let mut seen = 0u64;
+
+            for (bucket, n) in buckets.iter().enumerate() {
+                seen += u64::from(*n);
+                if seen >= target {
+                    return bound(bucket);
+                }
+            }
+
+            bound(BUCKETS - 1)
+        };
+
+        let max = buckets.iter().rposition(|n| *n > 0).map(bound).unwrap_or(0);
+
+        Distribution {
+            count,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Lifetime totals of selected stats, checkpointed to NVS so they survive
+//! reboots and config resets. Useful for spotting hardware which is
+//! degrading over weeks rather than minutes.
+//!
+//! Each total is the value persisted as of boot plus whatever has been
+//! counted since. Checkpoints start out frequent, so that boots which crash
+//! early are still counted, and back off to spare the flash. Anything
+//! counted after the last checkpoint is lost on reset.
+//!
+//! Nothing is written until the persisted totals have been read, so a
+//! failed read can't clobber them. Checkpoints retry the read until it
+//! succeeds.
+
+use core::ffi::CStr;
+use core::fmt::{self, Display};
+
+use cstr::cstr;
+use esp_idf_sys as sys;
+
+use crate::platform::nvs;
+use crate::system::crash;
+use crate::sync::mutex::CriticalMutex;
+
+use super::STATS;
+
+const FIRST_CHECKPOINT_SECS: u32 = 60;
+const MAX_CHECKPOINT_INTERVAL_SECS: u32 = 60 * 60;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Total {
+    Boots,
+    UptimeSecs,
+    /// Resets caused by a panic, watchdog or brownout
+    Crashes,
+    Underruns,
+    /// Packets missing when the stream went to play them
+    PacketsLost,
+    WifiDisconnects,
+}
+
+impl Total {
+    pub const COUNT: usize = 6;
+
+    pub const ALL: [Total; Self::COUNT] = [
+        Total::Boots,
+        Total::UptimeSecs,
+        Total::Crashes,
+        Total::Underruns,
+        Total::PacketsLost,
+        Total::WifiDisconnects,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Total::Boots => "boots",
+            Total::UptimeSecs => "uptime_secs",
+            Total::Crashes => "crashes",
+            Total::Underruns => "underruns",
+            Total::PacketsLost => "packets_lost",
+            Total::WifiDisconnects => "wifi_disconnects",
+        }
+    }
+
+    pub fn help(self) -> &'static str {
+        match self {
+            Total::Boots => "Boots since the totals were last reset",
+            Total::UptimeSecs => "Seconds of uptime",
+            Total::Crashes => "Resets caused by a crash, watchdog or brownout",
+            Total::Underruns => "Times the DAC ran out of frames to play",
+            Total::PacketsLost => "Packets missing when the stream went to play them",
+            Total::WifiDisconnects => "Times the WiFi connection was lost",
+        }
+    }
+
+    /// NVS keys are limited to 15 characters
+    fn key(self) -> &'static CStr {
+        match self {
+            Total::Boots => cstr!("boots"),
+            Total::UptimeSecs => cstr!("uptime"),
+            Total::Crashes => cstr!("crashes"),
+            Total::Underruns => cstr!("underruns"),
+            Total::PacketsLost => cstr!("packets_lost"),
+            Total::WifiDisconnects => cstr!("wifi_disc"),
+        }
+    }
+
+    /// Count since boot
+    fn live(self) -> u64 {
+        match self {
+            Total::Boots | Total::Crashes => 0,
+            Total::UptimeSecs => uptime_secs().into(),
+            Total::Underruns => STATS.dac_underruns.total().into(),
+            Total::PacketsLost => STATS.stream_miss.total().into(),
+            Total::WifiDisconnects => STATS.wifi_disconnects.total().into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Totals([u64; Total::COUNT]);
+
+impl Totals {
+    pub const ZERO: Totals = Totals([0; Total::COUNT]);
+
+    pub fn get(&self, total: Total) -> u64 {
+        self.0[total as usize]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Total, u64)> + '_ {
+        Total::ALL.iter().map(|total| (*total, self.get(*total)))
+    }
+}
+
+impl Display for Totals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (total, value)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+
+            write!(f, "{}:{}", total.name(), value)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct State {
+    /// Totals as of boot, or as of the last reset
+    base: [u64; Total::COUNT],
+    /// Live counts at the last reset, which are not part of the totals
+    offset: [u64; Total::COUNT],
+    /// Values as of the last checkpoint, so unchanged ones aren't rewritten
+    written: [u64; Total::COUNT],
+    next_checkpoint_secs: u32,
+    interval_secs: u32,
+    /// Set once the persisted totals have been read into base, or reset
+    loaded: bool,
+}
+
+static STATE: CriticalMutex<State> = CriticalMutex::declare(State {
+    base: [0; Total::COUNT],
+    offset: [0; Total::COUNT],
+    written: [0; Total::COUNT],
+    next_checkpoint_secs: FIRST_CHECKPOINT_SECS,
+    interval_secs: FIRST_CHECKPOINT_SECS,
+    loaded: false,
+});
+
+impl State {
+    fn totals(&self) -> Totals {
+        Totals(core::array::from_fn(|i| {
+            let live = Total::ALL[i].live().wrapping_sub(self.offset[i]);
+            self.base[i].wrapping_add(live)
+        }))
+    }
+}
+
+pub fn totals() -> Totals {
+    STATE.lock().totals()
+}
+
+/// Call once at boot, after NVS is initialised. Counts this boot, and
+/// checkpoints straight away so that it's counted even if we crash soon.
+pub fn load() -> Result<(), sys::EspError> {
+    let crashed = crash::crashed_before_reset();
+
+    {
+        // until the persisted totals are read, base is just this boot:
+        let mut state = STATE.lock();
+        state.base[Total::Boots as usize] = 1;
+        state.base[Total::Crashes as usize] = crashed.into();
+    }
+
+    let result = read();
+    log::info!("lifetime totals: {}", totals());
+
+    result.and_then(|()| checkpoint())
+}
+
+/// Adds the persisted totals to base, unless they've already been read
+fn read() -> Result<(), sys::EspError> {
+    let keys = Total::ALL.map(Total::key);
+    let mut persisted = [0; Total::COUNT];
+    unsafe { nvs::read_counters(&keys, &mut persisted)?; }
+
+    let mut state = STATE.lock();
+
+    // a reset may have beaten us to it:
+    if !state.loaded {
+        for (base, persisted) in state.base.iter_mut().zip(persisted) {
+            *base = base.wrapping_add(persisted);
+        }
+
+        state.loaded = true;
+    }
+
+    Ok(())
+}
+
+/// Called by the stats task every second, checkpoints when due. Each
+/// checkpoint doubles the interval to the next, up to an hour.
+pub(super) fn tick(uptime_secs: u32) {
+    {
+        let mut state = STATE.lock();
+
+        if uptime_secs < state.next_checkpoint_secs {
+            return;
+        }
+
+        state.interval_secs = (state.interval_secs * 2).min(MAX_CHECKPOINT_INTERVAL_SECS);
+        state.next_checkpoint_secs = uptime_secs + state.interval_secs;
+    }
+
+    if let Err(e) = checkpoint() {
+        log::warn!("failed to checkpoint lifetime totals: {e:?}");
+    }
+}
+
+/// Writes any totals which have changed since the last checkpoint. If the
+/// persisted totals haven't been read yet, tries again to read them first.
+pub fn checkpoint() -> Result<(), sys::EspError> {
+    if !STATE.lock().loaded {
+        read()?;
+    }
+
+    let totals = totals();
+    let written = STATE.lock().written;
+
+    let mut changed = heapless::Vec::<(&CStr, u64), { Total::COUNT }>::new();
+
+    for (total, value) in totals.iter() {
+        if value != written[total as usize] {
+            let _ = changed.push((total.key(), value));
+        }
+    }
+
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    // writing flash stalls anything running from it, including non-IRAM
+    // interrupt handlers, which is why checkpoints are infrequent:
+    unsafe { nvs::write_counters(&changed)?; }
+
+    STATE.lock().written = totals.0;
+    Ok(())
+}
+
+/// Zeroes every total, both in NVS and for the rest of this boot
+pub fn reset() -> Result<(), sys::EspError> {
+    {
+        let mut state = STATE.lock();
+        state.base = [0; Total::COUNT];
+        state.offset = Total::ALL.map(Total::live);
+        state.written = [0; Total::COUNT];
+        // whatever was persisted is about to be erased, no need to read it:
+        state.loaded = true;
+    }
+
+    log::info!("resetting lifetime totals");
+
+    unsafe { nvs::erase_counters() }
+}
+
+fn uptime_secs() -> u32 {
+    let micros = unsafe { sys::esp_timer_get_time() };
+    (micros / 1_000_000) as u32
+}
@@ -0,0 +1,168 @@
+//! Stats in the Prometheus text format, for scraping by a monitoring stack.
+//! Served at `/metrics` by [`crate::platform::net::http`].
+//!
+//! Counters and histograms are exported as lifetime totals, leave rates and
+//! windows to the scraper.
+
+use core::fmt::{self, Display, Write};
+
+use crate::app::timing;
+use crate::platform::wifi;
+use crate::system::crash;
+use crate::system::heap::{self, accounting, caps, Caps, HeapInfo, Subsystem};
+use crate::system::task::top;
+
+use super::lifetime;
+use super::{MetricName, STATS};
+
+use self::text::{describe, write_histogram, Label};
+
+pub mod text;
+
+pub fn write_metrics(out: &mut impl Write) -> fmt::Result {
+    write_stats(out)?;
+    write_wifi(out)?;
+    write_heap(out)?;
+    write_tasks(out)?;
+    write_timing(out)?;
+    write_crashes(out)?;
+    write_lifetime(out)
+}
+
+fn write_stats(out: &mut impl Write) -> fmt::Result {
+    for (name, counter) in STATS.counters() {
+        describe(out, format_args!("{}_total", Name(name)), "counter", name.help)?;
+        writeln!(out, "{}_total {}", Name(name), counter.total())?;
+    }
+
+    for (name, gauge) in STATS.gauges() {
+        describe(out, Name(name), "gauge", name.help)?;
+        writeln!(out, "{} {}", Name(name), gauge.get())?;
+    }
+
+    for (name, histogram) in STATS.histograms() {
+        write_histogram(out, Name(name), name.help, histogram)?;
+    }
+
+    Ok(())
+}
+
+fn write_wifi(out: &mut impl Write) -> fmt::Result {
+    if let Some(rssi) = wifi::rssi() {
+        describe(out, "bark_wifi_rssi_dbm", "gauge", "Signal strength of the access point we're associated with")?;
+        writeln!(out, "bark_wifi_rssi_dbm {rssi}")?;
+    }
+
+    Ok(())
+}
+
+fn write_heap(out: &mut impl Write) -> fmt::Result {
+    let mut regions: heapless::Vec<(&str, HeapInfo), 2> = heapless::Vec::new();
+    let _ = regions.push(("default", heap::info()));
+
+    if caps::spiram_available() {
+        let _ = regions.push(("spiram", heap::info_caps(Caps::SPIRAM)));
+    }
+
+    let fields: [(&str, &str, fn(&HeapInfo) -> usize); 3] = [
+        ("bark_heap_free_bytes", "Free heap", |info| info.free),
+        ("bark_heap_largest_free_block_bytes", "Largest block which could be allocated", |info| info.largest_free_block),
+        ("bark_heap_minimum_free_bytes", "Lowest free heap since boot", |info| info.minimum_free),
+    ];
+
+    for (metric, help, field) in fields {
+        describe(out, metric, "gauge", help)?;
+        for (region, info) in &regions {
+            writeln!(out, "{metric}{{region=\"{region}\"}} {}", field(info))?;
+        }
+    }
+
+    let subsystems = Subsystem::ALL.map(|subsystem| (subsystem, accounting::stats(subsystem)));
+
+    let fields: [(&str, &str, &str, fn(&accounting::SubsystemStats) -> u32); 6] = [
+        ("bark_heap_live_bytes", "gauge", "Bytes currently allocated", |stats| stats.live_bytes),
+        ("bark_heap_peak_bytes", "gauge", "Most bytes allocated at once since boot", |stats| stats.peak_bytes),
+        ("bark_heap_allocs_total", "counter", "Allocations made", |stats| stats.allocs),
+        ("bark_heap_frees_total", "counter", "Allocations freed", |stats| stats.frees),
+        ("bark_heap_failures_total", "counter", "Allocations which failed for lack of memory", |stats| stats.failures),
+        ("bark_heap_faults_total", "counter", "Allocations made on the audio path after init", |stats| stats.faults),
+    ];
+
+    for (metric, kind, help, field) in fields {
+        describe(out, metric, kind, help)?;
+        for (subsystem, stats) in &subsystems {
+            writeln!(out, "{metric}{{subsystem=\"{}\"}} {}", subsystem.name(), field(stats))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_tasks(out: &mut impl Write) -> fmt::Result {
+    let mut result = Ok(());
+
+    describe(out, "bark_task_runtime_ticks_total", "counter", "Time each task has spent running")?;
+    let elapsed = top::for_each_task(|name, runtime, _| {
+        result = result.and_then(|()| {
+            writeln!(out, "bark_task_runtime_ticks_total{{task=\"{}\"}} {runtime}", Label(name.as_str()))
+        });
+    });
+    result?;
+
+    describe(out, "bark_task_elapsed_ticks_total", "counter", "Time elapsed, in the same units as task runtime")?;
+    writeln!(out, "bark_task_elapsed_ticks_total {elapsed}")?;
+
+    describe(out, "bark_task_stack_headroom_bytes", "gauge", "Least stack each task has had spare")?;
+    top::for_each_task(|name, _, headroom| {
+        result = result.and_then(|()| {
+            writeln!(out, "bark_task_stack_headroom_bytes{{task=\"{}\"}} {headroom}", Label(name.as_str()))
+        });
+    });
+
+    result
+}
+
+fn write_timing(out: &mut impl Write) -> fmt::Result {
+    let estimate = timing::latest();
+
+    if let Some(latency) = estimate.network_latency {
+        describe(out, "bark_timing_network_latency_us", "gauge", "Latest estimate of one way network latency")?;
+        writeln!(out, "bark_timing_network_latency_us {}", latency.as_micros())?;
+    }
+
+    if let Some(delta) = estimate.clock_delta {
+        describe(out, "bark_timing_clock_delta_us", "gauge", "Latest estimate of our clock's offset from the server's")?;
+        writeln!(out, "bark_timing_clock_delta_us {}", delta.as_micros())?;
+    }
+
+    Ok(())
+}
+
+fn write_crashes(out: &mut impl Write) -> fmt::Result {
+    describe(out, "bark_crashes_total", "counter", "Crashes since power on")?;
+    writeln!(out, "bark_crashes_total {}", crash::total())
+}
+
+fn write_lifetime(out: &mut impl Write) -> fmt::Result {
+    for (total, value) in lifetime::totals().iter() {
+        describe(out, format_args!("bark_lifetime_{}_total", total.name()), "counter", total.help())?;
+        writeln!(out, "bark_lifetime_{}_total {value}", total.name())?;
+    }
+
+    Ok(())
+}
+
+/// Prometheus metric name for a stat, eg. `bark_dac_underruns`
+struct Name(MetricName);
+
+impl Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bark_")?;
+
+        for c in self.0.group.chars() {
+            f.write_char(c.to_ascii_lowercase())?;
+        }
+
+        write!(f, "_{}", self.0.name)
+    }
+}
@@ -0,0 +1,100 @@
+//! Pieces of the Prometheus text exposition format shared by every metric
+
+use core::fmt::{self, Display, Write};
+
+use crate::stats::histogram::{self, Histogram, BUCKETS};
+
+/// Writes the `# HELP` and `# TYPE` lines which introduce a metric
+pub fn describe(out: &mut impl Write, name: impl Display, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} {kind}")
+}
+
+pub fn write_histogram(out: &mut impl Write, name: impl Display, help: &str, histogram: &Histogram) -> fmt::Result {
+    let totals = histogram.totals();
+    describe(out, &name, "histogram", help)?;
+
+    // prometheus buckets are cumulative:
+    let mut count = 0u64;
+
+    for (bucket, n) in totals.iter().enumerate().take(BUCKETS - 1) {
+        count += u64::from(*n);
+        if let Some(bound) = histogram::upper_bound(bucket) {
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}")?;
+        }
+    }
+
+    count += u64::from(totals[BUCKETS - 1]);
+    writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}")?;
+    writeln!(out, "{name}_sum {}", histogram.sum())?;
+    writeln!(out, "{name}_count {count}")
+}
+
+/// Escapes a label value
+pub struct Label<'a>(pub &'a str);
+
+impl Display for Label<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::format;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn histogram_has_cumulative_buckets_sum_and_count() {
+        let histogram = Histogram::new();
+
+        for value in [0, 1, 3, 3, 100, 1_000_000] {
+            histogram.record(value);
+        }
+
+        let mut out = String::new();
+        write_histogram(&mut out, "bark_test_us", "Test values", &histogram).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[..2], [
+            "# HELP bark_test_us Test values",
+            "# TYPE bark_test_us histogram",
+        ]);
+
+        for line in [
+            "bark_test_us_bucket{le=\"0\"} 1",
+            "bark_test_us_bucket{le=\"1\"} 2",
+            "bark_test_us_bucket{le=\"3\"} 4",
+            "bark_test_us_bucket{le=\"7\"} 4",
+            "bark_test_us_bucket{le=\"127\"} 5",
+            "bark_test_us_bucket{le=\"262143\"} 5",
+        ] {
+            assert!(lines.contains(&line), "missing {line}");
+        }
+
+        assert_eq!(lines.iter().filter(|line| line.contains("_bucket")).count(), BUCKETS);
+
+        assert_eq!(lines[lines.len() - 3..], [
+            "bark_test_us_bucket{le=\"+Inf\"} 6",
+            "bark_test_us_sum 1000107",
+            "bark_test_us_count 6",
+        ]);
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(format!("{}", Label("a \"b\" \\c\nd")), "a \\\"b\\\" \\\\c\\nd");
+    }
+}
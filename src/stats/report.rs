@@ -0,0 +1,304 @@
+//! Stats reporters. The stats task hands each registered reporter a fresh
+//! snapshot every `interval_secs` seconds.
+//!
+//! Besides the text summary on the console, snapshots can be reported as
+//! JSON with the `stats-json` feature, and through the logger with the
+//! `stats-log` feature.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use esp_println::println;
+
+use crate::sync::mutex::CriticalMutex;
+use crate::system::crash;
+use crate::system::heap::{self, accounting, caps, Caps, Subsystem};
+use crate::system::journal;
+use crate::system::log::syslog;
+
+use super::Snapshot;
+
+#[cfg(feature = "stats-json")]
+pub use json::JsonReporter;
+
+#[cfg(feature = "stats-json")]
+mod json;
+
+pub const MAX_REPORTERS: usize = 8;
+
+/// Seconds between reports of heap, syslog and journal activity
+const ACTIVITY_INTERVAL_SECS: u32 = 10;
+
+pub trait Reporter: Sync {
+    /// Seconds between reports
+    fn interval_secs(&self) -> u32 {
+        1
+    }
+
+    /// Called on the stats task, so must not block for long
+    fn report(&self, snapshot: &Snapshot);
+}
+
+static REPORTERS: CriticalMutex<heapless::Vec<&'static dyn Reporter, MAX_REPORTERS>> =
+    CriticalMutex::declare(heapless::Vec::new());
+
+#[derive(Debug)]
+pub struct TooManyReporters;
+
+pub fn register(reporter: &'static dyn Reporter) -> Result<(), TooManyReporters> {
+    REPORTERS.lock().push(reporter).map_err(|_| TooManyReporters)
+}
+
+#[allow(unused)]
+pub fn unregister(reporter: &'static dyn Reporter) {
+    REPORTERS.lock().retain(|registered| !core::ptr::addr_eq(*registered, reporter));
+}
+
+pub(super) fn run_reporters(snapshot: &Snapshot) {
+    // copy out so reporters don't run inside a critical section:
+    let reporters = REPORTERS.lock().clone();
+
+    for reporter in reporters {
+        let interval = reporter.interval_secs().max(1);
+
+        if snapshot.uptime_secs % interval == 0 {
+            reporter.report(snapshot);
+        }
+    }
+}
+
+/// Human readable summary on the console, one line per group
+pub struct TextReporter;
+
+impl Reporter for TextReporter {
+    fn report(&self, snapshot: &Snapshot) {
+        println!();
+        let mut line = heapless::String::<160>::new();
+        let mut group = "";
+
+        for counter in &snapshot.counters {
+            if counter.name.group != group {
+                flush_line(&mut line);
+                group = counter.name.group;
+                let _ = write!(&mut line, "{group}:[");
+            } else {
+                let _ = line.push(' ');
+            }
+
+            let _ = write!(&mut line, "{}:{}/s", counter.name.name, counter.last_second);
+        }
+
+        flush_line(&mut line);
+
+        for gauge in &snapshot.gauges {
+            let _ = write!(&mut line, "{}:[{}:{} min:{} max:{}",
+                gauge.name.group,
+                gauge.name.name,
+                gauge.last_second.value,
+                gauge.last_second.min,
+                gauge.last_second.max);
+
+            flush_line(&mut line);
+        }
+
+        for histogram in &snapshot.histograms {
+            let dist = &histogram.last_second;
+
+            let _ = write!(&mut line, "{}:[{} n:{} p50:{} p90:{} p99:{} max:{}",
+                histogram.name.group,
+                histogram.name.name,
+                dist.count,
+                dist.p50,
+                dist.p90,
+                dist.p99,
+                dist.max);
+
+            flush_line(&mut line);
+        }
+
+        println!("Lifetime:[{}]", snapshot.lifetime);
+    }
+}
+
+fn flush_line(line: &mut heapless::String<160>) {
+    if !line.is_empty() {
+        println!("{line}]");
+        line.clear();
+    }
+}
+
+/// Summary of rates over the rolling window through the logger, so that it
+/// reaches the syslog collector when one is configured
+#[cfg(feature = "stats-log")]
+pub struct LogReporter;
+
+#[cfg(feature = "stats-log")]
+impl Reporter for LogReporter {
+    fn interval_secs(&self) -> u32 {
+        super::snapshot::HISTORY_SECS as u32
+    }
+
+    fn report(&self, snapshot: &Snapshot) {
+        let mut line = heapless::String::<256>::new();
+
+        for counter in &snapshot.counters {
+            let _ = write!(&mut line, "{}.{}={:.1}/s ",
+                counter.name.group, counter.name.name, snapshot.rate(counter));
+        }
+
+        for gauge in &snapshot.gauges {
+            let _ = write!(&mut line, "{}.{}={}..{} ",
+                gauge.name.group, gauge.name.name, gauge.last_window.min, gauge.last_window.max);
+        }
+
+        log::info!("stats over {}s: {}", snapshot.window_secs, line.trim_end());
+
+        // separate line, log records are only so long:
+        line.clear();
+
+        for histogram in &snapshot.histograms {
+            let dist = &histogram.last_window;
+            let _ = write!(&mut line, "{}.{}=p50:{},p99:{},max:{} ",
+                histogram.name.group, histogram.name.name, dist.p50, dist.p99, dist.max);
+        }
+
+        if let Some(histogram) = snapshot.histograms.first() {
+            log::info!("distributions over {}s: {}", histogram.window_secs, line.trim_end());
+        }
+
+        log::info!("lifetime: {}", snapshot.lifetime);
+    }
+}
+
+/// Crashes before the last reset, reported once. Any later crash resets us,
+/// so there is never anything new to report.
+#[derive(Default)]
+pub struct CrashReporter {
+    reported: AtomicBool,
+}
+
+impl CrashReporter {
+    pub const fn new() -> Self {
+        CrashReporter { reported: AtomicBool::new(false) }
+    }
+}
+
+impl Reporter for CrashReporter {
+    fn report(&self, _: &Snapshot) {
+        if self.reported.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        println!();
+        println!("Crashes:[since_power_on:{}]", crash::total());
+
+        for record in &crash::history() {
+            println!("  {record}");
+        }
+    }
+}
+
+/// Free memory, and allocations by each subsystem
+pub struct HeapReporter;
+
+impl Reporter for HeapReporter {
+    fn interval_secs(&self) -> u32 {
+        ACTIVITY_INTERVAL_SECS
+    }
+
+    fn report(&self, _: &Snapshot) {
+        let info = heap::info();
+
+        println!();
+
+        println!(
+            "Heap:[free:{} largest_block:{} min_free:{}]",
+            info.free,
+            info.largest_free_block,
+            info.minimum_free,
+        );
+
+        if caps::spiram_available() {
+            let spiram = heap::info_caps(Caps::SPIRAM);
+            println!(
+                "PSRAM:[free:{} largest_block:{} min_free:{}]",
+                spiram.free,
+                spiram.largest_free_block,
+                spiram.minimum_free,
+            );
+        }
+
+        for subsystem in Subsystem::ALL {
+            let stats = accounting::stats(subsystem);
+            println!(
+                "  {}:[live:{}B peak:{}B allocs:{} frees:{} failed:{} faults:{}]",
+                subsystem.name(),
+                stats.live_bytes,
+                stats.peak_bytes,
+                stats.allocs,
+                stats.frees,
+                stats.failures,
+                stats.faults,
+            );
+
+            if stats.faults > 0 {
+                log::warn!("{} allocations on the audio path after init!", subsystem.name());
+            }
+        }
+
+        heap::check_low_memory(&info);
+    }
+}
+
+/// Records forwarded to the syslog collector, if one is configured
+pub struct SyslogReporter;
+
+impl Reporter for SyslogReporter {
+    fn interval_secs(&self) -> u32 {
+        ACTIVITY_INTERVAL_SECS
+    }
+
+    fn report(&self, _: &Snapshot) {
+        if !syslog::is_enabled() {
+            return;
+        }
+
+        let stats = syslog::stats();
+
+        println!(
+            "Syslog:[sent:{} dropped_queue_full:{} dropped_send_failed:{}]",
+            stats.sent,
+            stats.dropped_queue_full,
+            stats.dropped_send_failed,
+        );
+    }
+}
+
+/// Journal entries recorded since the last report
+#[derive(Default)]
+pub struct JournalReporter {
+    seq: AtomicU32,
+}
+
+impl JournalReporter {
+    pub const fn new() -> Self {
+        JournalReporter { seq: AtomicU32::new(0) }
+    }
+}
+
+impl Reporter for JournalReporter {
+    fn interval_secs(&self) -> u32 {
+        ACTIVITY_INTERVAL_SECS
+    }
+
+    fn report(&self, _: &Snapshot) {
+        let mut seq = self.seq.load(Ordering::Relaxed);
+
+        journal::read(seq, |entry| {
+            println!("Journal: {entry}");
+            seq = entry.seq + 1;
+        });
+
+        self.seq.store(seq, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,88 @@
+//! Snapshots as JSON, turned on with the `stats-json` feature
+
+use core::fmt;
+
+use esp_println::println;
+
+use crate::stats::Snapshot;
+use crate::stats::histogram::Distribution;
+
+use super::Reporter;
+
+/// One JSON object per line on the console, for machines watching the
+/// serial port
+pub struct JsonReporter;
+
+impl Reporter for JsonReporter {
+    fn report(&self, snapshot: &Snapshot) {
+        println!("{}", Json(snapshot));
+    }
+}
+
+struct Json<'a>(&'a Snapshot);
+
+impl fmt::Display for Json<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let snapshot = self.0;
+
+        write!(f, "{{\"uptime_secs\":{},\"window_secs\":{},\"counters\":{{",
+            snapshot.uptime_secs, snapshot.window_secs)?;
+
+        for (i, counter) in snapshot.counters.iter().enumerate() {
+            write!(f, "{}\"{}.{}\":{{\"total\":{},\"last_second\":{},\"last_window\":{}}}",
+                if i == 0 { "" } else { "," },
+                counter.name.group,
+                counter.name.name,
+                counter.total,
+                counter.last_second,
+                counter.last_window)?;
+        }
+
+        write!(f, "}},\"gauges\":{{")?;
+
+        for (i, gauge) in snapshot.gauges.iter().enumerate() {
+            write!(f, "{}\"{}.{}\":{{\"value\":{},\"min\":{},\"max\":{},\"window_min\":{},\"window_max\":{}}}",
+                if i == 0 { "" } else { "," },
+                gauge.name.group,
+                gauge.name.name,
+                gauge.last_second.value,
+                gauge.last_second.min,
+                gauge.last_second.max,
+                gauge.last_window.min,
+                gauge.last_window.max)?;
+        }
+
+        write!(f, "}},\"histograms\":{{")?;
+
+        for (i, histogram) in snapshot.histograms.iter().enumerate() {
+            write!(f, "{}\"{}.{}\":{{\"last_second\":{},\"last_window\":{},\"window_secs\":{}}}",
+                if i == 0 { "" } else { "," },
+                histogram.name.group,
+                histogram.name.name,
+                JsonDistribution(&histogram.last_second),
+                JsonDistribution(&histogram.last_window),
+                histogram.window_secs)?;
+        }
+
+        write!(f, "}},\"lifetime\":{{")?;
+
+        for (i, (total, value)) in snapshot.lifetime.iter().enumerate() {
+            write!(f, "{}\"{}\":{}",
+                if i == 0 { "" } else { "," },
+                total.name(),
+                value)?;
+        }
+
+        write!(f, "}}}}")
+    }
+}
+
+struct JsonDistribution<'a>(&'a Distribution);
+
+impl fmt::Display for JsonDistribution<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let dist = self.0;
+        write!(f, "{{\"count\":{},\"p50\":{},\"p90\":{},\"p99\":{},\"max\":{}}}",
+            dist.count, dist.p50, dist.p90, dist.p99, dist.max)
+    }
+}
@@ -0,0 +1,244 @@
+use super::{GaugeWindow, MetricName, Stats, NUM_COUNTERS, NUM_GAUGES, NUM_HISTOGRAMS};
+use super::histogram::{self, Buckets, Distribution, BUCKETS};
+use super::lifetime::{self, Totals};
+
+/// How far back rolling windows reach
+pub const HISTORY_SECS: usize = 60;
+
+/// Histograms are too big to keep every second of, so their rolling window
+/// moves in steps of this many seconds
+pub const HISTOGRAM_CHECKPOINT_SECS: u32 = 10;
+
+const HISTOGRAM_CHECKPOINTS: usize = HISTORY_SECS / HISTOGRAM_CHECKPOINT_SECS as usize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CounterSample {
+    pub name: MetricName,
+    /// Since boot, wrapping
+    pub total: u32,
+    pub last_second: u32,
+    /// Over the last `Snapshot::window_secs` seconds
+    pub last_window: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GaugeSample {
+    pub name: MetricName,
+    pub last_second: GaugeWindow,
+    /// Over the last `Snapshot::window_secs` seconds
+    pub last_window: GaugeWindow,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HistogramSample {
+    pub name: MetricName,
+    pub last_second: Distribution,
+    pub last_window: Distribution,
+    /// Length of this histogram's rolling window, which lags the others
+    /// by up to `HISTOGRAM_CHECKPOINT_SECS`
+    pub window_secs: u32,
+}
+
+/// Every stat at one point in time, plus rolling windows leading up to it
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub uptime_secs: u32,
+    /// Length of the rolling window, up to `HISTORY_SECS` once the stats
+    /// task has been running that long
+    pub window_secs: u32,
+    pub counters: [CounterSample; NUM_COUNTERS],
+    pub gauges: [GaugeSample; NUM_GAUGES],
+    pub histograms: [HistogramSample; NUM_HISTOGRAMS],
+    /// Totals across every boot, see [`lifetime`]
+    pub lifetime: Totals,
+}
+
+const EMPTY_NAME: MetricName = MetricName::new("", "", "");
+const EMPTY_WINDOW: GaugeWindow = GaugeWindow { value: 0, min: 0, max: 0 };
+
+const EMPTY_COUNTER: CounterSample = CounterSample {
+    name: EMPTY_NAME,
+    total: 0,
+    last_second: 0,
+    last_window: 0,
+};
+
+const EMPTY_GAUGE: GaugeSample = GaugeSample {
+    name: EMPTY_NAME,
+    last_second: EMPTY_WINDOW,
+    last_window: EMPTY_WINDOW,
+};
+
+const EMPTY_HISTOGRAM: HistogramSample = HistogramSample {
+    name: EMPTY_NAME,
+    last_second: Distribution::EMPTY,
+    last_window: Distribution::EMPTY,
+    window_secs: 0,
+};
+
+impl Snapshot {
+    pub const EMPTY: Snapshot = Snapshot {
+        uptime_secs: 0,
+        window_secs: 0,
+        counters: [EMPTY_COUNTER; NUM_COUNTERS],
+        gauges: [EMPTY_GAUGE; NUM_GAUGES],
+        histograms: [EMPTY_HISTOGRAM; NUM_HISTOGRAMS],
+        lifetime: Totals::ZERO,
+    };
+
+    /// Average per second rate of a counter over the rolling window
+    pub fn rate(&self, counter: &CounterSample) -> f32 {
+        if self.window_secs == 0 {
+            return 0.0;
+        }
+
+        counter.last_window as f32 / self.window_secs as f32
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Second {
+    totals: [u32; NUM_COUNTERS],
+    gauges: [GaugeWindow; NUM_GAUGES],
+}
+
+const EMPTY_SECOND: Second = Second {
+    totals: [0; NUM_COUNTERS],
+    gauges: [EMPTY_WINDOW; NUM_GAUGES],
+};
+
+#[derive(Clone, Copy)]
+struct Checkpoint {
+    uptime_secs: u32,
+    buckets: [Buckets; NUM_HISTOGRAMS],
+}
+
+const EMPTY_CHECKPOINT: Checkpoint = Checkpoint {
+    uptime_secs: 0,
+    buckets: [[0; BUCKETS]; NUM_HISTOGRAMS],
+};
+
+/// Per second samples over the last `HISTORY_SECS`, owned by the stats task
+pub struct History {
+    seconds: [Second; HISTORY_SECS + 1],
+    /// Index the next sample is written to
+    next: usize,
+    len: usize,
+    /// Histogram buckets as of the last sample
+    histograms: [Buckets; NUM_HISTOGRAMS],
+    checkpoints: [Checkpoint; HISTOGRAM_CHECKPOINTS + 1],
+    next_checkpoint: usize,
+    checkpoints_len: usize,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        // everything starts at zero, so the first baselines are zero:
+        History {
+            seconds: [EMPTY_SECOND; HISTORY_SECS + 1],
+            next: 1,
+            len: 1,
+            histograms: [[0; BUCKETS]; NUM_HISTOGRAMS],
+            checkpoints: [EMPTY_CHECKPOINT; HISTOGRAM_CHECKPOINTS + 1],
+            next_checkpoint: 1,
+            checkpoints_len: 1,
+        }
+    }
+
+    fn ago(&self, secs: usize) -> &Second {
+        let secs = secs.min(self.len - 1);
+        let index = (self.next + self.seconds.len() - 1 - secs) % self.seconds.len();
+        &self.seconds[index]
+    }
+
+    /// Samples every stat, starting a new second
+    pub fn sample(&mut self, stats: &Stats, uptime_secs: u32) -> Snapshot {
+        let counters = stats.counters();
+        let gauges = stats.gauges();
+
+        let second = Second {
+            totals: counters.map(|(_, counter)| counter.total()),
+            gauges: gauges.map(|(_, gauge)| gauge.take_window()),
+        };
+
+        self.seconds[self.next] = second;
+        self.next = (self.next + 1) % self.seconds.len();
+        self.len = (self.len + 1).min(self.seconds.len());
+
+        let window_secs = self.len - 1;
+        let previous = *self.ago(1);
+        let oldest = *self.ago(window_secs);
+
+        let mut snapshot = Snapshot {
+            uptime_secs,
+            window_secs: window_secs as u32,
+            lifetime: lifetime::totals(),
+            ..Snapshot::EMPTY
+        };
+
+        for (i, (name, _)) in counters.iter().enumerate() {
+            let total = second.totals[i];
+
+            snapshot.counters[i] = CounterSample {
+                name: *name,
+                total,
+                last_second: total.wrapping_sub(previous.totals[i]),
+                last_window: total.wrapping_sub(oldest.totals[i]),
+            };
+        }
+
+        for (i, (name, _)) in gauges.iter().enumerate() {
+            let mut window = second.gauges[i];
+
+            for secs in 1..window_secs {
+                let earlier = self.ago(secs).gauges[i];
+                window.min = window.min.min(earlier.min);
+                window.max = window.max.max(earlier.max);
+            }
+
+            snapshot.gauges[i] = GaugeSample {
+                name: *name,
+                last_second: second.gauges[i],
+                last_window: window,
+            };
+        }
+
+        self.sample_histograms(stats, &mut snapshot);
+
+        snapshot
+    }
+
+    fn sample_histograms(&mut self, stats: &Stats, snapshot: &mut Snapshot) {
+        let histograms = stats.histograms();
+        let now = histograms.map(|(_, histogram)| histogram.totals());
+
+        if snapshot.uptime_secs % HISTOGRAM_CHECKPOINT_SECS == 0 {
+            self.checkpoints[self.next_checkpoint] = Checkpoint {
+                uptime_secs: snapshot.uptime_secs,
+                buckets: now,
+            };
+
+            self.next_checkpoint = (self.next_checkpoint + 1) % self.checkpoints.len();
+            self.checkpoints_len = (self.checkpoints_len + 1).min(self.checkpoints.len());
+        }
+
+        // the oldest checkpoint is the next to be overwritten, or the zero
+        // baseline if the ring hasn't filled yet:
+        let oldest = if self.checkpoints_len < self.checkpoints.len() {
+            &self.checkpoints[0]
+        } else {
+            &self.checkpoints[self.next_checkpoint]
+        };
+
+        for (i, (name, _)) in histograms.iter().enumerate() {
+            snapshot.histograms[i] = HistogramSample {
+                name: *name,
+                last_second: Distribution::from_buckets(&histogram::delta(&now[i], &self.histograms[i])),
+                last_window: Distribution::from_buckets(&histogram::delta(&now[i], &oldest.buckets[i])),
+                window_secs: snapshot.uptime_secs.wrapping_sub(oldest.uptime_secs),
+            };
+        }
+
+        self.histograms = now;
+    }
+}
@@ -0,0 +1,64 @@
+//! Compares [`super::spsc`] against the FreeRTOS queue in [`super::queue`]
+//! on target. Built with the `bench` feature, runs once at boot and logs
+//! the cost per item of each.
+
+use core::hint::black_box;
+
+use esp_idf_sys as sys;
+
+use super::{queue, spsc};
+
+const ITEMS: usize = 100_000;
+
+/// Fill then drain in batches, as the protocol task does on a burst of
+/// packets
+const BATCH: usize = 16;
+
+pub fn run() {
+    let spsc = measure_spsc();
+    let queue = measure_queue();
+
+    log::info!("bench: spsc {spsc} ns/item, freertos queue {queue} ns/item");
+}
+
+fn measure_spsc() -> u64 {
+    let (mut tx, mut rx) = spsc::channel::<usize, { BATCH + 1 }>()
+        .expect("allocate spsc channel");
+
+    measure(|round| {
+        for i in 0..BATCH {
+            let _ = tx.try_send(black_box(round + i));
+        }
+
+        for _ in 0..BATCH {
+            black_box(rx.try_receive());
+        }
+    })
+}
+
+fn measure_queue() -> u64 {
+    let (mut tx, mut rx) = queue::channel::<usize>(BATCH)
+        .expect("allocate queue");
+
+    measure(|round| {
+        for i in 0..BATCH {
+            let _ = tx.try_send(black_box(round + i));
+        }
+
+        for _ in 0..BATCH {
+            black_box(rx.try_receive());
+        }
+    })
+}
+
+/// Nanoseconds per item
+fn measure(mut batch: impl FnMut(usize)) -> u64 {
+    let start = unsafe { sys::esp_timer_get_time() };
+
+    for round in 0..ITEMS / BATCH {
+        batch(round);
+    }
+
+    let elapsed_us = unsafe { sys::esp_timer_get_time() } - start;
+    elapsed_us as u64 * 1000 / ITEMS as u64
+}
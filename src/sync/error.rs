@@ -0,0 +1,26 @@
+//! Errors shared by the channels in this module
+
+/// Returned when the other side of the channel has been dropped
+#[derive(Debug)]
+pub struct Closed;
+
+/// Returned by `send` when the receiver has been dropped, gives back the
+/// item that could not be sent
+#[derive(Debug)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    #[allow(unused)]
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(item) => item,
+            TrySendError::Disconnected(item) => item,
+        }
+    }
+}
@@ -7,7 +7,7 @@ use core::pin::Pin;
 use esp_idf_sys as sys;
 use bitflags::Flags;
 
-use crate::system::heap::{MallocError, HeapBox};
+use crate::system::heap::{MallocError, HeapBox, Subsystem};
 
 pub struct EventGroup<T: Flags> {
     cell: UnsafeCell<Inner>,
@@ -46,8 +46,9 @@ impl<T: Flags<Bits = u32> + Copy> EventGroup<T> {
         cell.handle = Some(handle);
     }
 
+    #[cfg_attr(feature = "static-alloc", allow(unused))]
     pub fn boxed(value: T) -> Result<Pin<HeapBox<EventGroup<T>>>, MallocError> {
-        let eventgroup = HeapBox::pin(EventGroup::declare())?;
+        let eventgroup = HeapBox::pin_tagged(Subsystem::Sync, EventGroup::declare())?;
         unsafe { eventgroup.as_ref().init_with(value); }
         Ok(eventgroup)
     }
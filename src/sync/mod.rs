@@ -1,8 +1,21 @@
+#[cfg(not(test))]
 pub mod eventgroup;
+#[cfg(not(test))]
 pub use eventgroup::EventGroup;
 
+#[cfg(all(feature = "bench", not(test)))]
+pub mod bench;
+pub mod error;
 pub mod isr;
+pub mod mpsc;
 pub mod mutex;
+pub mod notify;
+#[cfg(not(test))]
 pub mod queue;
 pub mod ringbuffer;
-// pub mod streambuffer;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spsc;
+pub mod streambuffer;
+mod waitlist;
+pub mod watch;
@@ -0,0 +1,106 @@
+//! Lock-free, fixed capacity multiple producer, single consumer queue, for
+//! use in statics.
+//!
+//! Producers claim a position by advancing a shared counter, then publish
+//! their item through a per-slot state. Each slot's state holds the start
+//! position of a lap of the ring: equal to the lap start means the slot is
+//! free for the producer on that lap, one more means it holds that lap's
+//! item. Nothing ever waits on another party, so pushing is safe from
+//! interrupts on either core.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub struct MpscQueue<T, const N: usize> {
+    /// Next position to push to
+    head: AtomicU32,
+    /// Next position to pop from, only touched by the consumer
+    tail: AtomicU32,
+    states: [AtomicU32; N],
+    slots: UnsafeCell<[MaybeUninit<T>; N]>,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for MpscQueue<T, N> {}
+
+impl<T, const N: usize> MpscQueue<T, N> {
+    pub const fn new() -> Self {
+        // so that positions divide evenly into laps when they wrap, and a
+        // full slot can't be mistaken for the next lap:
+        assert!(N.is_power_of_two() && N >= 2);
+
+        MpscQueue {
+            head: AtomicU32::new(0),
+            tail: AtomicU32::new(0),
+            // every slot free on lap 0:
+            states: [const { AtomicU32::new(0) }; N],
+            // SAFETY: an array of MaybeUninit needs no initialization
+            slots: UnsafeCell::new(unsafe { MaybeUninit::uninit().assume_init() }),
+        }
+    }
+
+    /// Position at the start of the lap containing pos
+    fn lap_start(pos: u32) -> u32 {
+        pos & !(N as u32 - 1)
+    }
+
+    fn slot(&self, pos: u32) -> (&AtomicU32, *mut MaybeUninit<T>) {
+        let index = pos as usize % N;
+        let slot = self.slots.get().cast::<MaybeUninit<T>>().wrapping_add(index);
+        (&self.states[index], slot)
+    }
+
+    /// Never blocks. Gives the item back if the queue is full. May be
+    /// called from any task or interrupt.
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+
+        loop {
+            let (state, slot) = self.slot(pos);
+            let free = Self::lap_start(pos);
+            let diff = state.load(Ordering::Acquire).wrapping_sub(free) as i32;
+
+            if diff < 0 {
+                // slot still holds an item from the previous lap:
+                return Err(item);
+            }
+
+            if diff > 0 {
+                // another producer claimed this position, try the next:
+                pos = self.head.load(Ordering::Relaxed);
+                continue;
+            }
+
+            match self.head.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    unsafe { (*slot).write(item); }
+                    state.store(free.wrapping_add(1), Ordering::Release);
+                    return Ok(());
+                }
+                Err(current) => {
+                    pos = current;
+                }
+            }
+        }
+    }
+
+    /// SAFETY: only one task may be popping at any given time
+    pub unsafe fn pop(&self) -> Option<T> {
+        let pos = self.tail.load(Ordering::Relaxed);
+        let (state, slot) = self.slot(pos);
+        let full = Self::lap_start(pos).wrapping_add(1);
+
+        if state.load(Ordering::Acquire) != full {
+            // empty, or the producer at this position hasn't finished yet:
+            return None;
+        }
+
+        let item = (*slot).assume_init_read();
+        self.tail.store(pos.wrapping_add(1), Ordering::Relaxed);
+
+        // free for the producer on the next lap:
+        state.store(Self::lap_start(pos).wrapping_add(N as u32), Ordering::Release);
+
+        Some(item)
+    }
+}
@@ -1,9 +1,18 @@
-use core::{cell::UnsafeCell, ops::{DerefMut, Deref}, sync::atomic::{AtomicBool, Ordering}, task::{Context, Poll}, future::poll_fn};
+use core::{cell::UnsafeCell, ops::{DerefMut, Deref}, sync::atomic::{AtomicBool, AtomicU32, Ordering}, task::{Context, Poll}};
+use core::convert::Infallible;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::time::Duration;
 
-use esp_idf_sys as sys;
+use crate::sys;
+use crate::system::task::{self, TaskWaker, TaskWakerSet, TimedOut};
 
-use crate::system::task::TaskWakerSet;
+use super::isr::IsrResult;
+use super::waitlist::{Ticket, WaitList};
 
+/// Spinlock based mutex which masks interrupts while held. May be used from
+/// both task and interrupt context.
 pub struct CriticalMutex<T> {
     spinlock: sys::portMUX_TYPE,
     inner: UnsafeCell<T>,
@@ -20,6 +29,17 @@ impl<T> CriticalMutex<T> {
         }
     }
 
+    /// For use in statics. Equivalent to `portMUX_INITIALIZER_UNLOCKED`
+    pub const fn declare(value: T) -> Self {
+        CriticalMutex {
+            spinlock: sys::portMUX_TYPE {
+                owner: sys::SPINLOCK_FREE,
+                count: 0,
+            },
+            inner: UnsafeCell::new(value),
+        }
+    }
+
     pub fn lock(&self) -> CriticalMutexGuard<'_, T> {
         unsafe { sys::rtos_taskENTER_CRITICAL(&self.spinlock); }
         CriticalMutexGuard { mutex: self }
@@ -52,9 +72,18 @@ impl<'a, T> Drop for CriticalMutexGuard<'a, T> {
     }
 }
 
+/// Async mutex for use between tasks. Waiters acquire the lock in the order
+/// they started waiting: on unlock, the lock is handed directly to the
+/// longest waiting task rather than being released for everyone to race for.
 pub struct TaskMutex<T> {
-    flag: AtomicBool,
-    notify: TaskWakerSet,
+    locked: AtomicBool,
+    /// Mirrors `waiters.waiting()`, so that try_lock and unlock can skip the
+    /// critical section when there are no waiters
+    queued: AtomicU32,
+    waiters: CriticalMutex<WaitList<()>>,
+    /// Tasks turned away because the wait list was full
+    room: TaskWakerSet,
+    stats: LockCounters,
     inner: UnsafeCell<T>,
 }
 
@@ -63,28 +92,191 @@ unsafe impl<T: Send> Sync for TaskMutex<T> {}
 impl<T> TaskMutex<T> {
     pub fn new(value: T) -> Self {
         TaskMutex {
-            flag: AtomicBool::new(false),
-            notify: TaskWakerSet::new(),
+            locked: AtomicBool::new(false),
+            queued: AtomicU32::new(0),
+            waiters: CriticalMutex::new(WaitList::new()),
+            room: TaskWakerSet::new(),
+            stats: LockCounters::new(),
             inner: UnsafeCell::new(value),
         }
     }
 
-    fn poll_lock(&self, cx: &Context) -> Poll<TaskMutexGuard<'_, T>> {
-        if self.flag.swap(true, Ordering::SeqCst) {
-            self.notify.add_task(cx);
-            Poll::Pending
-        } else {
-            Poll::Ready(TaskMutexGuard { mutex: self })
+    /// Takes the lock if it is free and nobody is waiting for it. Never
+    /// blocks, may be called from an ISR. A guard taken in an ISR must be
+    /// released with [`TaskMutexGuard::unlock_from_isr`].
+    #[allow(unused)]
+    pub fn try_lock(&self) -> Option<TaskMutexGuard<'_, T>> {
+        if self.queued.load(Ordering::SeqCst) != 0 {
+            // don't barge in front of waiting tasks
+            return None;
         }
+
+        self.try_acquire().then(|| self.guard())
     }
 
     pub async fn lock(&self) -> TaskMutexGuard<'_, T> {
-        poll_fn(|cx| self.poll_lock(cx)).await
+        Acquire { mutex: self, ticket: None }.await;
+        self.guard()
+    }
+
+    /// Like [`TaskMutex::lock`], but gives up if the lock could not be
+    /// acquired within timeout
+    #[allow(unused)]
+    pub async fn lock_timeout(&self, timeout: Duration) -> Result<TaskMutexGuard<'_, T>, TimedOut> {
+        let result = task::timeout(timeout, self.lock()).await;
+
+        if result.is_err() {
+            self.stats.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    #[allow(unused)]
+    pub fn stats(&self) -> LockStats {
+        self.stats.snapshot()
+    }
+
+    fn guard(&self) -> TaskMutexGuard<'_, T> {
+        self.stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        TaskMutexGuard { mutex: self, acquired_at: now_micros() }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+    fn poll_acquire(&self, ticket: &mut Option<Ticket>, cx: &Context) -> Poll<()> {
+        let mut waiters = self.waiters.lock();
+
+        if let Some(t) = *ticket {
+            if !waiters.poll_ticket(t, cx) {
+                return Poll::Pending;
+            }
+
+            drop(waiters);
+            *ticket = None;
+
+            // we've left the wait list, making room for anyone turned away
+            self.room.wake_all();
+            return Poll::Ready(());
+        }
+
+        if waiters.waiting() == 0 && self.try_acquire() {
+            return Poll::Ready(());
+        }
+
+        let Some(t) = waiters.enqueue(cx, ()) else {
+            self.room.add_task(cx);
+            return Poll::Pending;
+        };
+
+        self.queued.store(waiters.waiting() as u32, Ordering::SeqCst);
+        self.stats.contended.fetch_add(1, Ordering::Relaxed);
+
+        // the holder may have unlocked after our attempt above but before we
+        // published queued, in which case it skipped the wait list. try once
+        // more now that we're visible to unlock:
+        if self.try_acquire() {
+            waiters.cancel(t);
+            self.queued.store(waiters.waiting() as u32, Ordering::SeqCst);
+            return Poll::Ready(());
+        }
+
+        *ticket = Some(t);
+        Poll::Pending
+    }
+
+    fn cancel(&self, ticket: Ticket) {
+        let granted = {
+            let mut waiters = self.waiters.lock();
+            let granted = waiters.cancel(ticket);
+            self.queued.store(waiters.waiting() as u32, Ordering::SeqCst);
+            granted
+        };
+
+        self.room.wake_all();
+
+        if granted {
+            // we were handed the lock but never took it, pass it on
+            if let Some(waker) = self.release() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Releases the lock, handing it over to the next waiter if there is one.
+    /// Returns that waiter's waker, which the caller must wake.
+    fn release(&self) -> Option<TaskWaker> {
+        self.locked.store(false, Ordering::SeqCst);
+
+        if self.queued.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+
+        let mut waiters = self.waiters.lock();
+
+        if !self.try_acquire() {
+            // somebody else took the lock in the meantime, handing it over to
+            // the next waiter is now their responsibility
+            return None;
+        }
+
+        match waiters.grant_front() {
+            Some(waker) => {
+                // lock remains held, on behalf of the granted waiter
+                self.queued.store(waiters.waiting() as u32, Ordering::SeqCst);
+                Some(waker)
+            }
+            None => {
+                self.locked.store(false, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+}
+
+struct Acquire<'a, T> {
+    mutex: &'a TaskMutex<T>,
+    ticket: Option<Ticket>,
+}
+
+impl<'a, T> Future for Acquire<'a, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        this.mutex.poll_acquire(&mut this.ticket, cx)
+    }
+}
+
+impl<'a, T> Drop for Acquire<'a, T> {
+    fn drop(&mut self) {
+        // only set if we're dropped while still waiting, eg. on timeout
+        if let Some(ticket) = self.ticket.take() {
+            self.mutex.cancel(ticket);
+        }
     }
 }
 
 pub struct TaskMutexGuard<'a, T> {
-    mutex: &'a TaskMutex<T>
+    mutex: &'a TaskMutex<T>,
+    acquired_at: u32,
+}
+
+impl<'a, T> TaskMutexGuard<'a, T> {
+    /// Releases a guard from interrupt context
+    #[allow(unused)]
+    pub unsafe fn unlock_from_isr(guard: Self) -> IsrResult<(), Infallible> {
+        let mutex = guard.mutex;
+        mutex.stats.record_release(guard.acquired_at);
+        mem::forget(guard);
+
+        match mutex.release() {
+            Some(waker) => waker.wake_from_isr(),
+            None => IsrResult::default(),
+        }
+    }
 }
 
 impl<'a, T> Deref for TaskMutexGuard<'a, T> {
@@ -105,7 +297,245 @@ impl<'a, T> DerefMut for TaskMutexGuard<'a, T> {
 
 impl<'a, T> Drop for TaskMutexGuard<'a, T> {
     fn drop(&mut self) {
-        self.mutex.flag.store(false, Ordering::SeqCst);
-        self.mutex.notify.wake_all();
+        self.mutex.stats.record_release(self.acquired_at);
+
+        if let Some(waker) = self.mutex.release() {
+            waker.wake();
+        }
+    }
+}
+
+/// Snapshot of lock usage statistics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockStats {
+    pub acquisitions: u32,
+    /// Number of acquisitions which had to wait
+    pub contended: u32,
+    pub timeouts: u32,
+    pub max_hold_micros: u32,
+    /// Wraps on overflow
+    pub total_hold_micros: u32,
+}
+
+pub(super) struct LockCounters {
+    pub acquisitions: AtomicU32,
+    pub contended: AtomicU32,
+    pub timeouts: AtomicU32,
+    pub max_hold_micros: AtomicU32,
+    pub total_hold_micros: AtomicU32,
+}
+
+impl LockCounters {
+    pub const fn new() -> Self {
+        LockCounters {
+            acquisitions: AtomicU32::new(0),
+            contended: AtomicU32::new(0),
+            timeouts: AtomicU32::new(0),
+            max_hold_micros: AtomicU32::new(0),
+            total_hold_micros: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record_release(&self, acquired_at: u32) {
+        let held = now_micros().wrapping_sub(acquired_at);
+        self.max_hold_micros.fetch_max(held, Ordering::Relaxed);
+        self.total_hold_micros.fetch_add(held, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            max_hold_micros: self.max_hold_micros.load(Ordering::Relaxed),
+            total_hold_micros: self.total_hold_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Microsecond timestamp for measuring lock hold times, wraps every ~71 mins
+pub(super) fn now_micros() -> u32 {
+    unsafe { sys::esp_timer_get_time() as u32 }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::poll_fn;
+    use core::pin::pin;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    use futures::task::noop_waker_ref;
+
+    use crate::host::clock;
+    use crate::system::task::{time::sleep, Sim};
+    use crate::sync::waitlist::MAX_WAITERS;
+
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn waiters_acquire_in_order() {
+        let mutex = TaskMutex::new(Vec::new());
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let _guard = mutex.lock().await;
+            sleep(millis(10)).await;
+        });
+
+        for id in 1..=4 {
+            let mutex = &mutex;
+            sim.spawn(async move {
+                let mut order = mutex.lock().await;
+                order.push(id);
+                sleep(millis(1)).await;
+            });
+        }
+
+        sim.run();
+
+        assert_eq!(*mutex.try_lock().unwrap(), [1, 2, 3, 4]);
+
+        let stats = mutex.stats();
+        assert_eq!(stats.acquisitions, 6);
+        assert_eq!(stats.contended, 4);
+        assert_eq!(stats.max_hold_micros, 10_000);
+    }
+
+    #[test]
+    fn try_lock_does_not_barge_past_waiters() {
+        let mutex = TaskMutex::new(());
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let guard = mutex.lock().await;
+            sleep(millis(10)).await;
+            drop(guard);
+
+            // handed straight to the waiter, never released
+            assert!(mutex.try_lock().is_none());
+        });
+
+        sim.spawn(async {
+            let _guard = mutex.lock().await;
+        });
+
+        sim.run();
+    }
+
+    #[test]
+    fn lock_timeout_gives_up() {
+        let mutex = TaskMutex::new(());
+        let acquired_at = RefCell::new(None);
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let _guard = mutex.lock().await;
+            sleep(millis(50)).await;
+        });
+
+        sim.spawn(async {
+            assert!(mutex.lock_timeout(millis(10)).await.is_err());
+            assert_eq!(clock::now(), 10_000);
+
+            let _guard = mutex.lock_timeout(millis(100)).await.unwrap();
+            *acquired_at.borrow_mut() = Some(clock::now());
+        });
+
+        sim.run();
+
+        assert_eq!(*acquired_at.borrow(), Some(50_000));
+        assert_eq!(mutex.stats().timeouts, 1);
+    }
+
+    #[test]
+    fn lock_timeout_max_waits_for_holder() {
+        let mutex = TaskMutex::new(());
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let _guard = mutex.lock().await;
+            sleep(Duration::from_secs(3600)).await;
+        });
+
+        sim.spawn(async {
+            let _guard = mutex.lock_timeout(Duration::MAX).await.unwrap();
+            assert_eq!(clock::now(), 3_600_000_000);
+        });
+
+        sim.run();
+
+        assert_eq!(mutex.stats().timeouts, 0);
+    }
+
+    #[test]
+    fn cancelled_grant_passes_to_next_waiter() {
+        let mutex = TaskMutex::new(());
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let guard = mutex.try_lock().unwrap();
+
+        let mut first = Box::pin(mutex.lock());
+        let mut second = pin!(mutex.lock());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+
+        // grants the lock to first, which gives up before collecting it:
+        drop(guard);
+        drop(first);
+
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn more_waiters_than_fit_in_wait_list() {
+        const WAITERS: usize = MAX_WAITERS + 8;
+
+        let mutex = TaskMutex::new(Vec::new());
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let _guard = mutex.lock().await;
+            sleep(millis(10)).await;
+        });
+
+        // all on one task, so the task count doesn't limit us:
+        sim.spawn(async {
+            let mut waiters = (0..WAITERS)
+                .map(|id| {
+                    let mutex = &mutex;
+                    Some(Box::pin(async move { mutex.lock().await.push(id) }))
+                })
+                .collect::<Vec<_>>();
+
+            poll_fn(|cx| {
+                for slot in waiters.iter_mut() {
+                    if let Some(waiter) = slot {
+                        if waiter.as_mut().poll(cx).is_ready() {
+                            *slot = None;
+                        }
+                    }
+                }
+
+                if waiters.iter().all(Option::is_none) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }).await;
+        });
+
+        sim.run();
+
+        let order = mutex.try_lock().unwrap();
+        assert_eq!(order.len(), WAITERS);
+
+        // the ones which fit are served in order:
+        assert_eq!(order[..MAX_WAITERS], (0..MAX_WAITERS).collect::<Vec<_>>());
     }
 }
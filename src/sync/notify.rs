@@ -0,0 +1,149 @@
+use core::convert::Infallible;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+use crate::system::task::TaskWakerSet;
+
+use super::isr::IsrResult;
+
+/// Wakes tasks waiting on [`Notify::notified`]. Supports two styles:
+///
+/// * [`Notify::notify`] stores a single permit, which is consumed by exactly
+///   one waiter - either one already waiting, or the next to call
+///   `notified`. Repeated calls before the permit is consumed coalesce.
+/// * [`Notify::notify_waiters`] is edge triggered: it wakes every task
+///   currently waiting, but stores nothing for later callers.
+pub struct Notify {
+    permit: AtomicBool,
+    generation: AtomicU32,
+    waiters: TaskWakerSet,
+}
+
+#[allow(unused)]
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            permit: AtomicBool::new(false),
+            generation: AtomicU32::new(0),
+            waiters: TaskWakerSet::new(),
+        }
+    }
+
+    pub fn notify(&self) {
+        self.permit.store(true, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
+
+    pub unsafe fn notify_from_isr(&self) -> IsrResult<(), Infallible> {
+        self.permit.store(true, Ordering::SeqCst);
+        self.waiters.wake_from_isr()
+    }
+
+    pub fn notify_waiters(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
+
+    pub unsafe fn notify_waiters_from_isr(&self) -> IsrResult<(), Infallible> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_from_isr()
+    }
+
+    /// Resolves on the next call to [`Notify::notify_waiters`], or once a
+    /// permit stored by [`Notify::notify`] can be taken
+    pub async fn notified(&self) {
+        let generation = self.generation.load(Ordering::SeqCst);
+        poll_fn(|cx| self.poll_notified(cx, generation)).await
+    }
+
+    fn poll_notified(&self, cx: &Context, generation: u32) -> Poll<()> {
+        if self.check(generation) {
+            return Poll::Ready(());
+        }
+
+        self.waiters.add_task(cx);
+
+        // check again now that we're registered for wakeups:
+        if self.check(generation) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn check(&self, generation: u32) -> bool {
+        self.generation.load(Ordering::SeqCst) != generation
+            || self.permit.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::Future;
+    use core::pin::pin;
+    use core::time::Duration;
+    use std::cell::Cell;
+
+    use futures::task::noop_waker_ref;
+
+    use crate::system::task::{self, time::sleep, Sim};
+
+    use super::*;
+
+    #[test]
+    fn permit_is_stored_for_next_waiter() {
+        let notify = Notify::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // repeated notifies coalesce into one permit:
+        notify.notify();
+        notify.notify();
+        task::block_on(notify.notified());
+
+        assert!(pin!(notify.notified()).poll(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn notify_releases_one_waiter() {
+        let notify = Notify::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut first = pin!(notify.notified());
+        let mut second = pin!(notify.notified());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+
+        notify.notify();
+
+        assert!(first.as_mut().poll(&mut cx).is_ready());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn notify_waiters_wakes_everyone_waiting() {
+        let notify = Notify::new();
+        let woken = Cell::new(0);
+        let mut sim = Sim::new();
+
+        for _ in 0..3 {
+            sim.spawn(async {
+                notify.notified().await;
+                woken.set(woken.get() + 1);
+            });
+        }
+
+        sim.spawn(async {
+            sleep(Duration::from_millis(1)).await;
+            notify.notify_waiters();
+        });
+
+        sim.run();
+
+        assert_eq!(woken.get(), 3);
+
+        // and stores nothing for later:
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(pin!(notify.notified()).poll(&mut cx).is_pending());
+    }
+}
@@ -1,4 +1,3 @@
-use core::convert::Infallible;
 use core::future::poll_fn;
 use core::marker::PhantomData;
 use core::mem::{MaybeUninit, size_of};
@@ -9,21 +8,37 @@ use core::task::{Poll, Context};
 use derive_more::From;
 use esp_idf_sys as sys;
 
-use crate::system::heap::{MallocError, HeapBox};
+use crate::system::heap::{MallocError, HeapBox, Subsystem};
 use crate::system::task::TaskWakerSet;
 
 use super::isr::IsrResult;
 
+pub use super::error::{Closed, SendError, TrySendError};
+
 const RX_ALIVE: u32 = 0x01;
 const TX_ALIVE: u32 = 0x02;
 
 struct Shared<T> {
     handle: QueueHandle<T>,
     flags: AtomicU32,
+    /// Number of live `QueueSender`s. TX_ALIVE is cleared when this hits 0
+    senders: AtomicU32,
+    /// Number of live `SharedRef`s, the allocation is freed when this hits 0
+    refcount: AtomicU32,
     notify_rx: TaskWakerSet,
     notify_tx: TaskWakerSet,
 }
 
+impl<T> Shared<T> {
+    fn is_rx_alive(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & RX_ALIVE != 0
+    }
+
+    fn is_tx_alive(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & TX_ALIVE != 0
+    }
+}
+
 pub struct QueueSender<T> {
     shared: SharedRef<T>,
 }
@@ -44,9 +59,11 @@ pub fn channel<T>(capacity: usize)
 {
     let handle = QueueHandle::alloc(capacity)?;
 
-    let shared = HeapBox::alloc(Shared {
+    let shared = HeapBox::alloc_tagged(Subsystem::Sync, Shared {
         handle,
         flags: AtomicU32::new(TX_ALIVE | RX_ALIVE),
+        senders: AtomicU32::new(1),
+        refcount: AtomicU32::new(2),
         notify_rx: TaskWakerSet::new(),
         notify_tx: TaskWakerSet::new(),
     })?;
@@ -83,23 +100,50 @@ impl<T: Send> QueueReceiver<T> {
         }
     }
 
-    pub fn poll_receive(&mut self, cx: &Context) -> Poll<T> {
+    /// Returns true once all senders have been dropped. There may still be
+    /// items left in the queue to receive.
+    #[allow(unused)]
+    pub fn is_closed(&self) -> bool {
+        !self.shared.as_ref().is_tx_alive()
+    }
+
+    /// Resolves to `None` once all senders have been dropped and the queue
+    /// has been drained.
+    pub fn poll_receive(&mut self, cx: &Context) -> Poll<Option<T>> {
         if let Some(item) = self.try_receive() {
-            Poll::Ready(item)
-        } else {
-            self.shared.as_ref().notify_rx.add_task(cx);
-            Poll::Pending
+            return Poll::Ready(Some(item));
+        }
+
+        self.shared.as_ref().notify_rx.add_task(cx);
+
+        // check again now that we're registered for wakeups, in case an item
+        // was sent or the last sender was dropped in the meantime:
+        if let Some(item) = self.try_receive() {
+            return Poll::Ready(Some(item));
+        }
+
+        if !self.shared.as_ref().is_tx_alive() {
+            // all senders are gone, but one may have sent an item just
+            // before it dropped. take one last look:
+            return Poll::Ready(self.try_receive());
         }
+
+        Poll::Pending
     }
 
-    pub async fn receive(&mut self) -> T {
+    pub async fn receive(&mut self) -> Option<T> {
         poll_fn(|cx| self.poll_receive(cx)).await
     }
 }
 
 impl<T: Send> QueueSender<T> {
-    pub fn try_send(&mut self, item: T) -> Result<(), T> {
+    pub fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
         let shared = self.shared.as_ref();
+
+        if !shared.is_rx_alive() {
+            return Err(TrySendError::Disconnected(item));
+        }
+
         let item = MaybeUninit::new(item);
 
         unsafe {
@@ -113,14 +157,19 @@ impl<T: Send> QueueSender<T> {
                 shared.notify_rx.wake_all();
                 Ok(())
             } else {
-                Err(item.assume_init())
+                Err(TrySendError::Full(item.assume_init()))
             }
         }
     }
 
     #[allow(unused)]
-    pub unsafe fn send_from_isr(&mut self, item: T) -> IsrResult<(), T> {
+    pub unsafe fn send_from_isr(&mut self, item: T) -> IsrResult<(), TrySendError<T>> {
         let shared = self.shared.as_ref();
+
+        if !shared.is_rx_alive() {
+            return IsrResult::err(TrySendError::Disconnected(item), false);
+        }
+
         let item = MaybeUninit::new(item);
         let mut need_wake = false;
 
@@ -134,13 +183,18 @@ impl<T: Send> QueueSender<T> {
             let result = shared.notify_rx.wake_from_isr();
             result.chain(IsrResult::ok((), need_wake))
         } else {
-            IsrResult::err(item.assume_init(), need_wake)
+            IsrResult::err(TrySendError::Full(item.assume_init()), need_wake)
         }
     }
 
     #[allow(unused)]
-    pub unsafe fn send_overwriting_from_isr(&mut self, item: T) -> IsrResult<(), Infallible> {
+    pub unsafe fn send_overwriting_from_isr(&mut self, item: T) -> IsrResult<(), SendError<T>> {
         let shared = self.shared.as_ref();
+
+        if !shared.is_rx_alive() {
+            return IsrResult::err(SendError(item), false);
+        }
+
         let item = MaybeUninit::new(item);
         let mut need_wake_receive = false;
         let mut need_wake_send_to_back = false;
@@ -177,34 +231,82 @@ impl<T: Send> QueueSender<T> {
         unsafe { sys::uxQueueSpacesAvailable(shared.handle.as_ptr()) as usize }
     }
 
-    pub fn poll_reserve(&mut self, cx: &Context) -> Poll<()> {
+    /// Returns true once the receiver has been dropped
+    #[allow(unused)]
+    pub fn is_closed(&self) -> bool {
+        !self.shared.as_ref().is_rx_alive()
+    }
+
+    pub fn poll_reserve(&mut self, cx: &Context) -> Poll<Result<(), Closed>> {
+        let shared = self.shared.as_ref();
+
+        if !shared.is_rx_alive() {
+            return Poll::Ready(Err(Closed));
+        }
+
         if self.available() > 0 {
-            Poll::Ready(())
+            return Poll::Ready(Ok(()));
+        }
+
+        shared.notify_tx.add_task(cx);
+
+        // check again now that we're registered for wakeups:
+        if !shared.is_rx_alive() {
+            Poll::Ready(Err(Closed))
+        } else if self.available() > 0 {
+            Poll::Ready(Ok(()))
         } else {
-            let shared = self.shared.as_ref();
-            shared.notify_tx.add_task(cx);
             Poll::Pending
         }
     }
 
     #[allow(unused)]
-    pub async fn send(&mut self, value: T) {
-        poll_fn(|cx| self.poll_reserve(cx)).await;
-        if let Err(_) = self.try_send(value) {
-            panic!("queue still full even after poll_reserve ready");
+    pub async fn send(&mut self, mut item: T) -> Result<(), SendError<T>> {
+        loop {
+            if let Err(Closed) = poll_fn(|cx| self.poll_reserve(cx)).await {
+                return Err(SendError(item));
+            }
+
+            match self.try_send(item) {
+                Ok(()) => { return Ok(()); }
+                Err(TrySendError::Disconnected(item)) => { return Err(SendError(item)); }
+                Err(TrySendError::Full(returned)) => {
+                    // another sender beat us to the free slot, wait again
+                    item = returned;
+                }
+            }
         }
     }
 }
 
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.as_ref().senders.fetch_add(1, Ordering::SeqCst);
+        QueueSender { shared: self.shared.clone_ref() }
+    }
+}
+
 impl<T> Drop for QueueSender<T> {
     fn drop(&mut self) {
-        unsafe { self.shared.drop_tx(); }
+        let shared = self.shared.as_ref();
+
+        if shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // we were the last sender, let the receiver know:
+            shared.flags.fetch_and(!TX_ALIVE, Ordering::SeqCst);
+            shared.notify_rx.wake_all();
+        }
+
+        unsafe { self.shared.release(); }
     }
 }
 
 impl<T> Drop for QueueReceiver<T> {
     fn drop(&mut self) {
-        unsafe { self.shared.drop_rx(); }
+        let shared = self.shared.as_ref();
+        shared.flags.fetch_and(!RX_ALIVE, Ordering::SeqCst);
+        shared.notify_tx.wake_all();
+
+        unsafe { self.shared.release(); }
     }
 }
 
@@ -217,18 +319,14 @@ impl<T> SharedRef<T> {
         unsafe { self.ptr.as_ref() }
     }
 
-    unsafe fn drop_rx(&mut self) {
-        self.drop_side(RX_ALIVE);
-    }
-
-    unsafe fn drop_tx(&mut self) {
-        self.drop_side(TX_ALIVE);
+    fn clone_ref(&self) -> Self {
+        self.as_ref().refcount.fetch_add(1, Ordering::SeqCst);
+        SharedRef { ptr: self.ptr }
     }
 
-    unsafe fn drop_side(&mut self, flags: u32) {
-        let prev = self.as_ref().flags.fetch_and(!flags, Ordering::SeqCst);
-        let current = prev & !flags;
-        if current == 0 {
+    unsafe fn release(&mut self) {
+        let prev = self.as_ref().refcount.fetch_sub(1, Ordering::SeqCst);
+        if prev == 1 {
             // return ptr back to HeapBox to drop:
             HeapBox::from_raw(self.ptr);
         }
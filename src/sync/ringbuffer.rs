@@ -32,6 +32,19 @@ impl<T: Copy, const N: usize> RingBuffer<T, N> {
         self.writer.store(0, Ordering::Relaxed);
     }
 
+    /// False if the indices are out of range, eg. when the ring buffer
+    /// lives in memory which is not initialized on boot
+    pub fn is_consistent(&self) -> bool {
+        self.reader.load(Ordering::Relaxed) < N && self.writer.load(Ordering::Relaxed) < N
+    }
+
+    /// Number of items waiting to be read
+    pub fn len(&self) -> usize {
+        let reader = self.reader.load(Ordering::Acquire);
+        let writer = self.writer.load(Ordering::Acquire);
+        (writer + N - reader) % N
+    }
+
     fn buffer_ptr(&self) -> *mut T {
         self.buffer.get().cast()
     }
@@ -40,14 +53,6 @@ impl<T: Copy, const N: usize> RingBuffer<T, N> {
         N
     }
 
-    /// SAFETY: only one task may be reading at any given time
-    #[allow(unused)]
-    pub unsafe fn read(&self, data: &mut [T]) -> usize {
-        self.read_in_place(|left, right| {
-            copy_from_split(left, right, data)
-        })
-    }
-
     /// SAFETY: only one task may be reading at any given time
     pub unsafe fn read_in_place(&self, func: impl FnOnce(&[T], &[T]) -> usize) -> usize {
         let reader = self.reader.load(Ordering::Acquire);
@@ -101,6 +106,30 @@ impl<T: Copy, const N: usize> RingBuffer<T, N> {
         copied
     }
 
+    /// Like [`RingBuffer::write`], but makes room for data by discarding
+    /// the oldest data in the buffer rather than truncating. If data is
+    /// longer than the buffer, only the end of it is kept.
+    ///
+    /// SAFETY: the caller must be the only reader and the only writer, since
+    /// this moves the read index
+    pub unsafe fn write_overwriting(&self, data: &[T]) -> usize {
+        // one slot always stays empty, see write:
+        let capacity = N - 1;
+        let data = &data[data.len().saturating_sub(capacity)..];
+
+        let reader = self.reader.load(Ordering::Acquire);
+        let writer = self.writer.load(Ordering::Acquire);
+        let used = (writer + N - reader) % N;
+        let free = capacity - used;
+
+        if data.len() > free {
+            let reader = (reader + data.len() - free) % N;
+            self.reader.store(reader, Ordering::Release);
+        }
+
+        self.write(data)
+    }
+
     // Fills all available space for writing in buffer with copies of T
     pub unsafe fn fill(&self, value: T) {
         let reader = self.reader.load(Ordering::Acquire);
@@ -122,7 +151,7 @@ impl<T: Copy, const N: usize> RingBuffer<T, N> {
     }
 }
 
-fn copy_to_split<T: Copy>(src: &[T], dst_left: &mut [T], dst_right: &mut [T]) -> usize {
+pub(super) fn copy_to_split<T: Copy>(src: &[T], dst_left: &mut [T], dst_right: &mut [T]) -> usize {
     let nleft = cmp::min(src.len(), dst_left.len());
     dst_left[..nleft].copy_from_slice(&src[..nleft]);
 
@@ -134,7 +163,7 @@ fn copy_to_split<T: Copy>(src: &[T], dst_left: &mut [T], dst_right: &mut [T]) ->
     nleft + nright
 }
 
-fn copy_from_split<T: Copy>(src_left: &[T], src_right: &[T], dst: &mut [T]) -> usize {
+pub(super) fn copy_from_split<T: Copy>(src_left: &[T], src_right: &[T], dst: &mut [T]) -> usize {
     let nleft = cmp::min(dst.len(), src_left.len());
     dst[..nleft].copy_from_slice(&src_left[..nleft]);
 
@@ -146,7 +175,7 @@ fn copy_from_split<T: Copy>(src_left: &[T], src_right: &[T], dst: &mut [T]) -> u
     nleft + nright
 }
 
-unsafe fn reader_slices<'a, T>(
+pub(super) unsafe fn reader_slices<'a, T>(
     ring: *const T,
     length: usize,
     start: usize,
@@ -167,7 +196,7 @@ unsafe fn reader_slices<'a, T>(
     }
 }
 
-unsafe fn writer_slices<'a, T>(
+pub(super) unsafe fn writer_slices<'a, T>(
     ring: *mut T,
     length: usize,
     start: usize,
@@ -185,3 +214,49 @@ unsafe fn writer_slices<'a, T>(
         (left, right)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn contents<const N: usize>(ring: &RingBuffer<u8, N>) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        // reads nothing, so the contents are left in place:
+        unsafe {
+            ring.read_in_place(|left, right| {
+                out.extend_from_slice(left);
+                out.extend_from_slice(right);
+                0
+            });
+        }
+
+        out
+    }
+
+    #[test]
+    fn write_overwriting_wraps_and_keeps_newest() {
+        let ring = RingBuffer::<u8, 8>::new();
+
+        unsafe {
+            assert_eq!(ring.write_overwriting(b"abcde"), 5);
+            assert_eq!(contents(&ring), b"abcde");
+
+            // one slot always stays empty, so 7 fit and the oldest goes:
+            assert_eq!(ring.write_overwriting(b"fgh"), 3);
+            assert_eq!(contents(&ring), b"bcdefgh");
+
+            // now split across the end of the buffer:
+            assert_eq!(ring.write_overwriting(b"ij"), 2);
+            assert_eq!(contents(&ring), b"defghij");
+
+            // longer than the buffer, only the end is kept:
+            assert_eq!(ring.write_overwriting(b"0123456789"), 7);
+            assert_eq!(contents(&ring), b"3456789");
+        }
+
+        assert_eq!(ring.len(), 7);
+    }
+}
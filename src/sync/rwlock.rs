@@ -0,0 +1,455 @@
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::Ordering;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use heapless::Vec;
+
+use crate::system::task::{self, TaskWaker, TaskWakerSet, TimedOut};
+
+use super::mutex::{CriticalMutex, LockCounters, LockStats, now_micros};
+use super::waitlist::{Ticket, WaitList, MAX_WAITERS};
+
+/// Async reader-writer lock for use between tasks. Like
+/// [`super::mutex::TaskMutex`], waiters acquire the lock in the order they
+/// started waiting, so a steady stream of readers cannot starve a writer.
+pub struct RwLock<T> {
+    state: CriticalMutex<State>,
+    /// Tasks turned away because the wait list was full
+    room: TaskWakerSet,
+    stats: LockCounters,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+struct State {
+    readers: u32,
+    writer: bool,
+    waiters: WaitList<Access>,
+}
+
+type Wakers = Vec<TaskWaker, MAX_WAITERS>;
+
+impl State {
+    fn can_acquire(&self, access: Access) -> bool {
+        match access {
+            Access::Read => !self.writer,
+            Access::Write => !self.writer && self.readers == 0,
+        }
+    }
+
+    fn acquire(&mut self, access: Access) {
+        match access {
+            Access::Read => { self.readers += 1; }
+            Access::Write => { self.writer = true; }
+        }
+    }
+
+    fn release(&mut self, access: Access) {
+        match access {
+            Access::Read => { self.readers -= 1; }
+            Access::Write => { self.writer = false; }
+        }
+    }
+
+    /// Grants the lock to as many waiters at the front of the queue as can
+    /// hold it at once
+    fn grant_waiters(&mut self, wakers: &mut Wakers) {
+        while let Some(access) = self.waiters.front_waiting() {
+            if !self.can_acquire(access) {
+                break;
+            }
+
+            self.acquire(access);
+
+            if let Some(waker) = self.waiters.grant_front() {
+                // wakers has the same capacity as the wait list:
+                let _ = wakers.push(waker);
+            }
+        }
+    }
+}
+
+#[allow(unused)]
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> Self {
+        RwLock {
+            state: CriticalMutex::new(State {
+                readers: 0,
+                writer: false,
+                waiters: WaitList::new(),
+            }),
+            room: TaskWakerSet::new(),
+            stats: LockCounters::new(),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.try_acquire(Access::Read).then(|| self.read_guard())
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.try_acquire(Access::Write).then(|| self.write_guard())
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        Acquire { lock: self, access: Access::Read, ticket: None }.await;
+        self.read_guard()
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        Acquire { lock: self, access: Access::Write, ticket: None }.await;
+        self.write_guard()
+    }
+
+    pub async fn read_timeout(&self, timeout: Duration) -> Result<RwLockReadGuard<'_, T>, TimedOut> {
+        let result = task::timeout(timeout, self.read()).await;
+        self.record_timeout(result.is_err());
+        result
+    }
+
+    pub async fn write_timeout(&self, timeout: Duration) -> Result<RwLockWriteGuard<'_, T>, TimedOut> {
+        let result = task::timeout(timeout, self.write()).await;
+        self.record_timeout(result.is_err());
+        result
+    }
+
+    pub fn stats(&self) -> LockStats {
+        self.stats.snapshot()
+    }
+
+    fn record_timeout(&self, timed_out: bool) {
+        if timed_out {
+            self.stats.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn read_guard(&self) -> RwLockReadGuard<'_, T> {
+        self.stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        RwLockReadGuard { lock: self, acquired_at: now_micros() }
+    }
+
+    fn write_guard(&self) -> RwLockWriteGuard<'_, T> {
+        self.stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        RwLockWriteGuard { lock: self, acquired_at: now_micros() }
+    }
+
+    fn try_acquire(&self, access: Access) -> bool {
+        let mut state = self.state.lock();
+
+        // don't barge in front of waiting tasks
+        if state.waiters.waiting() == 0 && state.can_acquire(access) {
+            state.acquire(access);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn poll_acquire(&self, access: Access, ticket: &mut Option<Ticket>, cx: &Context) -> Poll<()> {
+        let mut state = self.state.lock();
+
+        if let Some(t) = *ticket {
+            if !state.waiters.poll_ticket(t, cx) {
+                return Poll::Pending;
+            }
+
+            drop(state);
+            *ticket = None;
+
+            // we've left the wait list, making room for anyone turned away
+            self.room.wake_all();
+            return Poll::Ready(());
+        }
+
+        if state.waiters.waiting() == 0 && state.can_acquire(access) {
+            state.acquire(access);
+            return Poll::Ready(());
+        }
+
+        let Some(t) = state.waiters.enqueue(cx, access) else {
+            self.room.add_task(cx);
+            return Poll::Pending;
+        };
+
+        *ticket = Some(t);
+        self.stats.contended.fetch_add(1, Ordering::Relaxed);
+        Poll::Pending
+    }
+
+    fn cancel(&self, access: Access, ticket: Ticket) {
+        let mut wakers = Wakers::new();
+
+        {
+            let mut state = self.state.lock();
+
+            if state.waiters.cancel(ticket) {
+                // we were granted the lock but never took it, give it back
+                state.release(access);
+            }
+
+            // whether or not we held it, our leaving the queue may let
+            // whoever was queued behind us in
+            state.grant_waiters(&mut wakers);
+        }
+
+        wake(wakers);
+        self.room.wake_all();
+    }
+
+    fn release(&self, access: Access) {
+        let mut wakers = Wakers::new();
+
+        {
+            let mut state = self.state.lock();
+            state.release(access);
+            state.grant_waiters(&mut wakers);
+        }
+
+        wake(wakers);
+    }
+}
+
+fn wake(wakers: Wakers) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+struct Acquire<'a, T> {
+    lock: &'a RwLock<T>,
+    access: Access,
+    ticket: Option<Ticket>,
+}
+
+impl<'a, T> Future for Acquire<'a, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        this.lock.poll_acquire(this.access, &mut this.ticket, cx)
+    }
+}
+
+impl<'a, T> Drop for Acquire<'a, T> {
+    fn drop(&mut self) {
+        // only set if we're dropped while still waiting, eg. on timeout
+        if let Some(ticket) = self.ticket.take() {
+            self.lock.cancel(self.access, ticket);
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    acquired_at: u32,
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: no writers while any read guard is held
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.stats.record_release(self.acquired_at);
+        self.lock.release(Access::Read);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    acquired_at: u32,
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the only write guard and there are no readers
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the only write guard and there are no readers
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.stats.record_release(self.acquired_at);
+        self.lock.release(Access::Write);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::poll_fn;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    use crate::host::clock;
+    use crate::system::task::{time::sleep, Sim};
+
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn readers_share_and_writers_exclude() {
+        let lock = RwLock::new(0);
+        let log = RefCell::new(Vec::new());
+        let mut sim = Sim::new();
+
+        for _ in 0..2 {
+            sim.spawn(async {
+                let value = lock.read().await;
+                log.borrow_mut().push(("read", *value, clock::now()));
+                sleep(millis(10)).await;
+            });
+        }
+
+        sim.spawn(async {
+            let mut value = lock.write().await;
+            log.borrow_mut().push(("write", *value, clock::now()));
+            *value += 1;
+            sleep(millis(10)).await;
+        });
+
+        sim.run();
+
+        assert_eq!(*log.borrow(), [
+            ("read", 0, 0),
+            ("read", 0, 0),
+            ("write", 0, 10_000),
+        ]);
+        assert_eq!(*lock.try_read().unwrap(), 1);
+    }
+
+    #[test]
+    fn queued_writer_holds_back_later_readers() {
+        let lock = RwLock::new(());
+        let log = RefCell::new(Vec::new());
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let _guard = lock.read().await;
+            sleep(millis(10)).await;
+        });
+
+        sim.spawn(async {
+            let _guard = lock.write().await;
+            log.borrow_mut().push(("write", clock::now()));
+            sleep(millis(10)).await;
+        });
+
+        sim.spawn(async {
+            // the lock is only held for reading, but a writer is waiting:
+            assert!(lock.try_read().is_none());
+
+            let _guard = lock.read().await;
+            log.borrow_mut().push(("read", clock::now()));
+        });
+
+        sim.run();
+
+        assert_eq!(*log.borrow(), [("write", 10_000), ("read", 20_000)]);
+        assert_eq!(lock.stats().contended, 2);
+    }
+
+    #[test]
+    fn timed_out_writer_lets_readers_behind_it_in() {
+        let lock = RwLock::new(());
+        let acquired_at = RefCell::new(None);
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let _guard = lock.read().await;
+            sleep(millis(50)).await;
+        });
+
+        sim.spawn(async {
+            assert!(lock.write_timeout(millis(10)).await.is_err());
+        });
+
+        sim.spawn(async {
+            let _guard = lock.read().await;
+            *acquired_at.borrow_mut() = Some(clock::now());
+        });
+
+        sim.run();
+
+        assert_eq!(*acquired_at.borrow(), Some(10_000));
+        assert_eq!(lock.stats().timeouts, 1);
+    }
+
+    #[test]
+    fn more_waiters_than_fit_in_wait_list() {
+        const WAITERS: usize = MAX_WAITERS + 8;
+
+        let lock = RwLock::new(0);
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let _guard = lock.write().await;
+            sleep(millis(10)).await;
+        });
+
+        // all on one task, so the task count doesn't limit us:
+        sim.spawn(async {
+            let mut waiters = (0..WAITERS)
+                .map(|id| {
+                    let lock = &lock;
+                    Some(Box::pin(async move {
+                        if id % 2 == 0 {
+                            *lock.write().await += 1;
+                        } else {
+                            let _guard = lock.read().await;
+                        }
+                    }))
+                })
+                .collect::<Vec<_>>();
+
+            poll_fn(|cx| {
+                for slot in waiters.iter_mut() {
+                    if let Some(waiter) = slot {
+                        if waiter.as_mut().poll(cx).is_ready() {
+                            *slot = None;
+                        }
+                    }
+                }
+
+                if waiters.iter().all(Option::is_none) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }).await;
+        });
+
+        sim.run();
+
+        assert_eq!(*lock.try_read().unwrap(), WAITERS / 2);
+    }
+}
@@ -0,0 +1,164 @@
+use core::convert::Infallible;
+use core::future::poll_fn;
+use core::mem;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+use crate::system::task::TaskWakerSet;
+
+use super::isr::IsrResult;
+
+/// Async counting semaphore. Permits are returned when the
+/// [`SemaphorePermit`] guard is dropped.
+pub struct Semaphore {
+    permits: AtomicU32,
+    waiters: TaskWakerSet,
+}
+
+#[allow(unused)]
+impl Semaphore {
+    pub const fn new(permits: u32) -> Self {
+        Semaphore {
+            permits: AtomicU32::new(permits),
+            waiters: TaskWakerSet::new(),
+        }
+    }
+
+    pub fn available_permits(&self) -> u32 {
+        self.permits.load(Ordering::SeqCst)
+    }
+
+    /// Never blocks, may be called from an ISR
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Never blocks, may be called from an ISR
+    pub fn try_acquire_many(&self, count: u32) -> Option<SemaphorePermit<'_>> {
+        self.permits.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |permits| {
+            permits.checked_sub(count)
+        }).ok()?;
+
+        Some(SemaphorePermit { semaphore: self, count })
+    }
+
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.acquire_many(1).await
+    }
+
+    pub async fn acquire_many(&self, count: u32) -> SemaphorePermit<'_> {
+        poll_fn(|cx| self.poll_acquire(cx, count)).await
+    }
+
+    fn poll_acquire(&self, cx: &Context, count: u32) -> Poll<SemaphorePermit<'_>> {
+        if let Some(permit) = self.try_acquire_many(count) {
+            return Poll::Ready(permit);
+        }
+
+        self.waiters.add_task(cx);
+
+        // check again now that we're registered for wakeups:
+        match self.try_acquire_many(count) {
+            Some(permit) => Poll::Ready(permit),
+            None => Poll::Pending,
+        }
+    }
+
+    pub fn add_permits(&self, count: u32) {
+        self.permits.fetch_add(count, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
+
+    pub unsafe fn add_permits_from_isr(&self, count: u32) -> IsrResult<(), Infallible> {
+        self.permits.fetch_add(count, Ordering::SeqCst);
+        self.waiters.wake_from_isr()
+    }
+}
+
+#[must_use = "permit is released immediately if not held"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    count: u32,
+}
+
+#[allow(unused)]
+impl<'a> SemaphorePermit<'a> {
+    /// Consumes the permit without returning it to the semaphore
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+
+    /// Returns the permit to the semaphore from interrupt context
+    pub unsafe fn release_from_isr(self) -> IsrResult<(), Infallible> {
+        let result = self.semaphore.add_permits_from_isr(self.count);
+        mem::forget(self);
+        result
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::cell::RefCell;
+
+    use crate::host::clock;
+    use crate::system::task::{time::sleep, Sim};
+
+    use super::*;
+
+    #[test]
+    fn permits_return_on_drop() {
+        let semaphore = Semaphore::new(3);
+
+        let two = semaphore.try_acquire_many(2).unwrap();
+        assert_eq!(semaphore.available_permits(), 1);
+        assert!(semaphore.try_acquire_many(2).is_none());
+
+        drop(two);
+        assert_eq!(semaphore.available_permits(), 3);
+    }
+
+    #[test]
+    fn forgotten_permits_stay_taken() {
+        let semaphore = Semaphore::new(2);
+
+        semaphore.try_acquire().unwrap().forget();
+        assert_eq!(semaphore.available_permits(), 1);
+
+        semaphore.add_permits(1);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn acquire_waits_for_enough_permits() {
+        let semaphore = Semaphore::new(2);
+        let acquired_at = RefCell::new(None);
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            let first = semaphore.acquire().await;
+            sleep(Duration::from_millis(10)).await;
+            drop(first);
+
+            let _second = semaphore.acquire().await;
+            sleep(Duration::from_millis(10)).await;
+        });
+
+        sim.spawn(async {
+            let _permits = semaphore.acquire_many(2).await;
+            *acquired_at.borrow_mut() = Some(clock::now());
+        });
+
+        sim.run();
+
+        // one permit came back at 10ms, but the other was held until 20ms:
+        assert_eq!(*acquired_at.borrow(), Some(20_000));
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+}
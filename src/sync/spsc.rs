@@ -0,0 +1,457 @@
+//! Lock-free single producer, single consumer channel.
+//!
+//! Uses the same index scheme as [`super::ringbuffer::RingBuffer`]: reader
+//! and writer indices into a fixed size array, with reader == writer meaning
+//! empty. One slot is always left unused, so a channel with `N` slots holds
+//! at most `N - 1` items. Unlike the FreeRTOS queue in [`super::queue`],
+//! items are moved in place with no trip through C or the scheduler lock.
+
+use core::cell::UnsafeCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use crate::system::heap::{HeapBox, MallocError, Subsystem};
+use crate::system::task::TaskWakerSet;
+
+use super::isr::IsrResult;
+use super::error::{Closed, SendError, TrySendError};
+
+const RX_ALIVE: u32 = 0x01;
+const TX_ALIVE: u32 = 0x02;
+
+struct Shared<T, const N: usize> {
+    reader: AtomicUsize,
+    writer: AtomicUsize,
+    flags: AtomicU32,
+    /// Number of live `SharedRef`s, the allocation is freed when this hits 0
+    refcount: AtomicU32,
+    notify_rx: TaskWakerSet,
+    notify_tx: TaskWakerSet,
+    slots: UnsafeCell<[MaybeUninit<T>; N]>,
+}
+
+impl<T, const N: usize> Shared<T, N> {
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots.get().cast::<MaybeUninit<T>>().wrapping_add(index)
+    }
+
+    fn is_rx_alive(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & RX_ALIVE != 0
+    }
+
+    fn is_tx_alive(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & TX_ALIVE != 0
+    }
+}
+
+impl<T, const N: usize> Drop for Shared<T, N> {
+    fn drop(&mut self) {
+        // drop any items left in the channel
+        let mut reader = *self.reader.get_mut();
+        let writer = *self.writer.get_mut();
+
+        while reader != writer {
+            unsafe { (*self.slot(reader)).assume_init_drop(); }
+            reader = (reader + 1) % N;
+        }
+    }
+}
+
+pub struct SpscSender<T, const N: usize> {
+    shared: SharedRef<T, N>,
+}
+
+pub struct SpscReceiver<T, const N: usize> {
+    shared: SharedRef<T, N>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for SpscSender<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for SpscReceiver<T, N> {}
+
+/// Allocates a channel with `N` slots, holding at most `N - 1` items
+pub fn channel<T, const N: usize>()
+    -> Result<(SpscSender<T, N>, SpscReceiver<T, N>), MallocError>
+{
+    assert!(N > 1, "spsc channel must have at least 2 slots");
+
+    let shared = HeapBox::alloc_tagged(Subsystem::Sync, Shared {
+        reader: AtomicUsize::new(0),
+        writer: AtomicUsize::new(0),
+        flags: AtomicU32::new(TX_ALIVE | RX_ALIVE),
+        refcount: AtomicU32::new(2),
+        notify_rx: TaskWakerSet::new(),
+        notify_tx: TaskWakerSet::new(),
+        // SAFETY: an array of MaybeUninit needs no initialization
+        slots: UnsafeCell::new(unsafe { MaybeUninit::uninit().assume_init() }),
+    })?;
+
+    let ptr = HeapBox::into_raw(shared);
+    let sender = SpscSender { shared: SharedRef { ptr } };
+    let receiver = SpscReceiver { shared: SharedRef { ptr } };
+
+    Ok((sender, receiver))
+}
+
+impl<T: Send, const N: usize> SpscSender<T, N> {
+    /// Pushes item into the channel without waking the receiver
+    fn push(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        let shared = self.shared.as_ref();
+
+        if !shared.is_rx_alive() {
+            return Err(TrySendError::Disconnected(item));
+        }
+
+        // we are the only writer, relaxed is fine for our own index:
+        let writer = shared.writer.load(Ordering::Relaxed);
+        let reader = shared.reader.load(Ordering::Acquire);
+
+        let next = (writer + 1) % N;
+
+        if next == reader {
+            return Err(TrySendError::Full(item));
+        }
+
+        // SAFETY: the slot at writer is outside of reader..writer, so the
+        // receiver will not touch it until we publish the new writer index
+        unsafe { (*shared.slot(writer)).write(item); }
+
+        shared.writer.store(next, Ordering::Release);
+
+        Ok(())
+    }
+
+    pub fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        self.push(item)?;
+        self.shared.as_ref().notify_rx.wake_all();
+        Ok(())
+    }
+
+    #[allow(unused)]
+    pub unsafe fn send_from_isr(&mut self, item: T) -> IsrResult<(), TrySendError<T>> {
+        match self.push(item) {
+            Ok(()) => self.shared.as_ref().notify_rx.wake_from_isr()
+                .chain(IsrResult::ok((), false)),
+            Err(e) => IsrResult::err(e, false),
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        let shared = self.shared.as_ref();
+        let writer = shared.writer.load(Ordering::Relaxed);
+        let reader = shared.reader.load(Ordering::Acquire);
+        let len = (writer + N - reader) % N;
+        N - 1 - len
+    }
+
+    /// Returns true once the receiver has been dropped
+    #[allow(unused)]
+    pub fn is_closed(&self) -> bool {
+        !self.shared.as_ref().is_rx_alive()
+    }
+
+    pub fn poll_reserve(&mut self, cx: &Context) -> Poll<Result<(), Closed>> {
+        let shared = self.shared.as_ref();
+
+        if !shared.is_rx_alive() {
+            return Poll::Ready(Err(Closed));
+        }
+
+        if self.available() > 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        shared.notify_tx.add_task(cx);
+
+        // check again now that we're registered for wakeups:
+        if !shared.is_rx_alive() {
+            Poll::Ready(Err(Closed))
+        } else if self.available() > 0 {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    #[allow(unused)]
+    pub async fn send(&mut self, mut item: T) -> Result<(), SendError<T>> {
+        loop {
+            if let Err(Closed) = poll_fn(|cx| self.poll_reserve(cx)).await {
+                return Err(SendError(item));
+            }
+
+            match self.try_send(item) {
+                Ok(()) => { return Ok(()); }
+                Err(TrySendError::Disconnected(item)) => { return Err(SendError(item)); }
+                Err(TrySendError::Full(returned)) => {
+                    // we're the only sender, so nothing should have taken the
+                    // slot poll_reserve saw. if it did, just wait again
+                    item = returned;
+                }
+            }
+        }
+    }
+}
+
+impl<T: Send, const N: usize> SpscReceiver<T, N> {
+    pub fn try_receive(&mut self) -> Option<T> {
+        let shared = self.shared.as_ref();
+
+        // we are the only reader, relaxed is fine for our own index:
+        let reader = shared.reader.load(Ordering::Relaxed);
+        let writer = shared.writer.load(Ordering::Acquire);
+
+        if reader == writer {
+            return None;
+        }
+
+        // SAFETY: the slot at reader is within reader..writer, so it was
+        // initialized by the sender before it published the writer index
+        let item = unsafe { (*shared.slot(reader)).assume_init_read() };
+
+        shared.reader.store((reader + 1) % N, Ordering::Release);
+        shared.notify_tx.wake_all();
+
+        Some(item)
+    }
+
+    /// Returns true once the sender has been dropped. There may still be
+    /// items left in the channel to receive.
+    #[allow(unused)]
+    pub fn is_closed(&self) -> bool {
+        !self.shared.as_ref().is_tx_alive()
+    }
+
+    /// Resolves to `None` once the sender has been dropped and the channel
+    /// has been drained.
+    pub fn poll_receive(&mut self, cx: &Context) -> Poll<Option<T>> {
+        if let Some(item) = self.try_receive() {
+            return Poll::Ready(Some(item));
+        }
+
+        self.shared.as_ref().notify_rx.add_task(cx);
+
+        // check again now that we're registered for wakeups, in case an item
+        // was sent or the sender was dropped in the meantime:
+        if let Some(item) = self.try_receive() {
+            return Poll::Ready(Some(item));
+        }
+
+        if !self.shared.as_ref().is_tx_alive() {
+            // the sender is gone, but it may have sent an item just before
+            // it dropped. take one last look:
+            return Poll::Ready(self.try_receive());
+        }
+
+        Poll::Pending
+    }
+
+    pub async fn receive(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_receive(cx)).await
+    }
+}
+
+impl<T, const N: usize> Drop for SpscSender<T, N> {
+    fn drop(&mut self) {
+        let shared = self.shared.as_ref();
+        shared.flags.fetch_and(!TX_ALIVE, Ordering::SeqCst);
+        shared.notify_rx.wake_all();
+
+        unsafe { self.shared.release(); }
+    }
+}
+
+impl<T, const N: usize> Drop for SpscReceiver<T, N> {
+    fn drop(&mut self) {
+        let shared = self.shared.as_ref();
+        shared.flags.fetch_and(!RX_ALIVE, Ordering::SeqCst);
+        shared.notify_tx.wake_all();
+
+        unsafe { self.shared.release(); }
+    }
+}
+
+struct SharedRef<T, const N: usize> {
+    ptr: NonNull<Shared<T, N>>,
+}
+
+impl<T, const N: usize> SharedRef<T, N> {
+    fn as_ref(&self) -> &Shared<T, N> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    unsafe fn release(&mut self) {
+        let prev = self.as_ref().refcount.fetch_sub(1, Ordering::SeqCst);
+        if prev == 1 {
+            // return ptr back to HeapBox to drop:
+            HeapBox::from_raw(self.ptr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hint::black_box;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Instant;
+    use std::vec::Vec;
+
+    use crate::system::task::{self, Sim};
+
+    use super::*;
+
+    #[test]
+    fn holds_one_less_than_slots() {
+        let (mut tx, mut rx) = channel::<u32, 4>().unwrap();
+
+        assert_eq!(tx.available(), 3);
+
+        for i in 0..3 {
+            tx.try_send(i).unwrap();
+        }
+
+        assert_eq!(tx.available(), 0);
+        assert!(matches!(tx.try_send(3), Err(TrySendError::Full(3))));
+
+        for i in 0..3 {
+            assert_eq!(rx.try_receive(), Some(i));
+        }
+
+        assert_eq!(rx.try_receive(), None);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let (mut tx, mut rx) = channel::<u32, 3>().unwrap();
+
+        for i in 0..10 {
+            tx.try_send(i * 2).unwrap();
+            tx.try_send(i * 2 + 1).unwrap();
+            assert_eq!(rx.try_receive(), Some(i * 2));
+            assert_eq!(rx.try_receive(), Some(i * 2 + 1));
+        }
+    }
+
+    #[test]
+    fn send_fails_once_receiver_dropped() {
+        let (mut tx, rx) = channel::<u32, 4>().unwrap();
+        drop(rx);
+
+        assert!(tx.is_closed());
+        assert!(matches!(tx.try_send(1), Err(TrySendError::Disconnected(1))));
+        assert!(matches!(task::block_on(tx.send(2)), Err(SendError(2))));
+    }
+
+    #[test]
+    fn receiver_drains_after_sender_dropped() {
+        let (mut tx, mut rx) = channel::<u32, 4>().unwrap();
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        drop(tx);
+
+        assert!(rx.is_closed());
+        assert_eq!(task::block_on(rx.receive()), Some(1));
+        assert_eq!(task::block_on(rx.receive()), Some(2));
+        assert_eq!(task::block_on(rx.receive()), None);
+    }
+
+    #[test]
+    fn drops_items_left_in_channel() {
+        struct Counted(Arc<AtomicUsize>);
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (mut tx, mut rx) = channel::<Counted, 4>().unwrap();
+
+        for _ in 0..3 {
+            let _ = tx.try_send(Counted(drops.clone()));
+        }
+
+        drop(rx.try_receive());
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+
+        drop(tx);
+        drop(rx);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn send_waits_for_room() {
+        let (mut tx, mut rx) = channel::<u32, 3>().unwrap();
+        let mut received = Vec::new();
+
+        let mut sim = Sim::new();
+
+        sim.spawn(async move {
+            for i in 0..10 {
+                tx.send(i).await.unwrap();
+            }
+        });
+
+        sim.spawn(async {
+            while let Some(i) = rx.receive().await {
+                received.push(i);
+                task::yield_now().await;
+            }
+        });
+
+        sim.run();
+
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+
+    const BENCH_ITEMS: usize = 1_000_000;
+    const BENCH_BATCH: usize = 16;
+
+    /// Fills then drains the channel in batches, as the protocol task does
+    /// on a burst of packets. Run with `cargo test-host -- --ignored bench`.
+    /// There is no FreeRTOS queue on the host, so this compares against
+    /// std's bounded channel. See `sync::bench` for the on target numbers.
+    #[test]
+    #[ignore]
+    fn bench_against_std_sync_channel() {
+        let (mut tx, mut rx) = channel::<usize, { BENCH_BATCH + 1 }>().unwrap();
+
+        let start = Instant::now();
+
+        for round in 0..BENCH_ITEMS / BENCH_BATCH {
+            for i in 0..BENCH_BATCH {
+                let _ = tx.try_send(black_box(round + i));
+            }
+
+            for _ in 0..BENCH_BATCH {
+                black_box(rx.try_receive());
+            }
+        }
+
+        let spsc = start.elapsed();
+
+        let (tx, rx) = std::sync::mpsc::sync_channel::<usize>(BENCH_BATCH);
+
+        let start = Instant::now();
+
+        for round in 0..BENCH_ITEMS / BENCH_BATCH {
+            for i in 0..BENCH_BATCH {
+                let _ = tx.try_send(black_box(round + i));
+            }
+
+            for _ in 0..BENCH_BATCH {
+                let _ = black_box(rx.try_recv());
+            }
+        }
+
+        let std = start.elapsed();
+
+        std::println!("spsc: {:.1} ns/item, std sync_channel: {:.1} ns/item",
+            spsc.as_nanos() as f64 / BENCH_ITEMS as f64,
+            std.as_nanos() as f64 / BENCH_ITEMS as f64);
+    }
+}
@@ -1,25 +1,31 @@
 use core::alloc::Layout;
-use core::cmp;
-use core::convert::Infallible;
 use core::ffi::c_void;
 use core::future::poll_fn;
 use core::mem;
+use core::pin::Pin;
 use core::ptr::{self, NonNull};
-use core::slice;
 use core::sync::atomic::{AtomicUsize, AtomicU32, Ordering};
 use core::task::{Context, Poll};
 
 use futures::Stream;
-use static_assertions::const_assert_eq;
 
-use crate::system::heap::{MallocError, self};
+use crate::system::heap::{MallocError, Subsystem, self};
 use crate::system::task::TaskWakerSet;
 
 use super::isr::IsrResult;
+use super::error::Closed;
+use super::ringbuffer;
 
+/// Maximum size of chunks yielded by the [`Stream`] impl on [`StreamReceiver`]
+pub const STREAM_CHUNK_SIZE: usize = 64;
+
+pub type StreamChunk = heapless::Vec<u8, STREAM_CHUNK_SIZE>;
+
+/// Allocates a byte stream channel which can buffer up to `capacity` bytes
+#[allow(unused)]
 pub fn channel(capacity: usize) -> Result<(StreamSender, StreamReceiver), MallocError> {
     let shared = SharedRef::alloc(capacity)?;
-    let sender = StreamSender { shared: shared.clone() };
+    let sender = StreamSender { shared: shared.clone_ref() };
     let receiver = StreamReceiver { shared };
     Ok((sender, receiver))
 }
@@ -29,64 +35,91 @@ pub struct StreamSender {
     shared: SharedRef,
 }
 
+unsafe impl Send for StreamSender {}
+
+#[allow(unused)]
 impl StreamSender {
-    pub async fn write(&mut self, mut data: &[u8]) {
-        while data.len() > 0 {
-            let bytes = poll_fn(|cx| self.poll_write(cx, data)).await;
-            data = &data[bytes..];
+    /// Writes as much of data as fits in the buffer without waiting, returns
+    /// number of bytes written
+    pub fn try_write(&mut self, data: &[u8]) -> Result<usize, Closed> {
+        let header = self.shared.header();
+
+        if !header.is_rx_alive() {
+            return Err(Closed);
+        }
+
+        let nbytes = self.shared.write(data);
+
+        if nbytes > 0 {
+            header.notify_rx.wake_all();
         }
+
+        Ok(nbytes)
     }
 
-    pub fn poll_write(&mut self, cx: &Context, data: &[u8]) -> Poll<usize> {
-        // reader always moves forward
-        let header = self.shared.header();
-        let reader = header.reader.load(Ordering::Acquire);
-        let writer = header.writer.load(Ordering::Acquire);
+    pub fn poll_write(&mut self, cx: &Context, data: &[u8]) -> Poll<Result<usize, Closed>> {
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
 
-        if reader == writer {
-            // buffer is full
-            header.notify.add_task(cx);
-            return Poll::Pending;
+        match self.try_write(data)? {
+            0 => {}
+            nbytes => { return Poll::Ready(Ok(nbytes)); }
         }
 
-        let capacity =
-            if writer < reader {
-                // simple contiguous case, no wraparound
-                reader - writer
-            } else {
-                // write at most up to the wraparound point, a subsequent
-                // call can write the next chunk from 0
-                header.length - writer
-            };
-
-        let nbytes = cmp::min(capacity, data.len());
-
-        unsafe {
-            let ptr = self.shared.buffer().add(writer);
-            ptr::copy(data.as_ptr(), ptr, nbytes);
+        self.shared.header().notify_tx.add_task(cx);
+
+        // check again now that we're registered for wakeups, in case the
+        // receiver made space or went away in the meantime:
+        match self.try_write(data)? {
+            0 => Poll::Pending,
+            nbytes => Poll::Ready(Ok(nbytes)),
         }
+    }
 
-        let writer = (writer + nbytes) % header.length;
-        header.writer.store(writer, Ordering::Release);
+    /// Writes all of data, waiting for the receiver to make space as needed
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), Closed> {
+        while !data.is_empty() {
+            let nbytes = poll_fn(|cx| self.poll_write(cx, data)).await?;
+            data = &data[nbytes..];
+        }
 
-        return Poll::Ready(nbytes);
+        Ok(())
     }
-}
 
-impl Drop for StreamSender {
-    fn drop(&mut self) {
-        unsafe { self.shared.drop_tx(); }
+    /// Writes as much of data as fits in the buffer, returns number of bytes
+    /// written
+    pub unsafe fn write_from_isr(&mut self, data: &[u8]) -> IsrResult<usize, Closed> {
+        let header = self.shared.header();
+
+        if !header.is_rx_alive() {
+            return IsrResult::err(Closed, false);
+        }
+
+        let nbytes = self.shared.write(data);
+
+        if nbytes > 0 {
+            header.notify_rx.wake_from_isr()
+                .chain(IsrResult::ok(nbytes, false))
+        } else {
+            IsrResult::ok(nbytes, false)
+        }
     }
-}
 
-pub struct UnsafeStreamReceiver {
-    shared: SharedRef,
+    /// Returns true once the receiver has been dropped
+    pub fn is_closed(&self) -> bool {
+        !self.shared.header().is_rx_alive()
+    }
 }
 
-/// This type does not implement Drop. You gotta do it yourself!
-#[repr(transparent)]
-pub struct RawStreamReceiver {
-    shared: SharedRef,
+impl Drop for StreamSender {
+    fn drop(&mut self) {
+        let header = self.shared.header();
+        header.flags.fetch_and(!TX_ALIVE, Ordering::SeqCst);
+        header.notify_rx.wake_all();
+
+        unsafe { self.shared.release(); }
+    }
 }
 
 #[repr(transparent)]
@@ -94,53 +127,120 @@ pub struct StreamReceiver {
     shared: SharedRef,
 }
 
+unsafe impl Send for StreamReceiver {}
+
+#[allow(unused)]
 impl StreamReceiver {
-    pub fn into_raw(self) -> RawStreamReceiver {
-        self.shared.ptr.as_ptr().cast()
+    /// Leaks receiver into a raw pointer suitable for passing as C callback
+    /// state. Reclaim ownership with [`StreamReceiver::from_raw`].
+    pub fn into_raw(self) -> *mut c_void {
+        let ptr = self.shared.ptr;
+        mem::forget(self);
+        ptr.as_ptr().cast()
     }
 
-    pub unsafe fn borrow_from_raw_ptr<'a>(ptr: &'a mut *mut c_void) -> &'a mut StreamReceiver {
-        // SAFETY: this is safe because StreamReceiver and SharedRef are both repr(transparent) around a SharedRef
-        mem::transmute(ptr)
+    /// SAFETY: ptr must have come from [`StreamReceiver::into_raw`]
+    pub unsafe fn from_raw(ptr: *mut c_void) -> StreamReceiver {
+        let ptr = NonNull::new_unchecked(ptr).cast();
+        StreamReceiver { shared: SharedRef { ptr } }
     }
 
-    fn read_internal(&mut self, mut out: &mut [u8]) -> usize {
+    /// Reads as many bytes as are available into out without waiting.
+    /// Returns `Err(Closed)` only once the sender has been dropped and all
+    /// buffered bytes have been read.
+    pub fn try_read(&mut self, out: &mut [u8]) -> Result<usize, Closed> {
         let header = self.shared.header();
-        let buffer = self.shared.buffer();
 
-        let reader = header.reader.load(Ordering::Acquire);
-        let writer = header.writer.load(Ordering::Acquire);
+        // load flags before reading, so that we can't miss any bytes written
+        // just before the sender went away:
+        let tx_alive = header.is_tx_alive();
 
-        let mut total_bytes = 0;
+        let nbytes = self.shared.read(out);
 
-        // copy from first slice
-        let copy_len = cmp::min(out.len(), slices.0.len());
-        out[0..copy_len].copy_from_slice(&slices.0[0..copy_len]);
-        total_bytes += copy_len;
-        out = &mut out[copy_len..];
+        if nbytes > 0 {
+            header.notify_tx.wake_all();
+            Ok(nbytes)
+        } else if !tx_alive && !out.is_empty() {
+            Err(Closed)
+        } else {
+            Ok(0)
+        }
+    }
 
-        // copy from second slice
-        let copy_len = cmp::min(out.len(), slices.1.len());
-        out[0..copy_len].copy_from_slice(&slices.1[0..copy_len]);
-        total_bytes += copy_len;
+    pub fn poll_read(&mut self, cx: &Context, out: &mut [u8]) -> Poll<Result<usize, Closed>> {
+        if out.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
 
-        total_bytes
+        match self.try_read(out)? {
+            0 => {}
+            nbytes => { return Poll::Ready(Ok(nbytes)); }
+        }
+
+        self.shared.header().notify_rx.add_task(cx);
+
+        // check again now that we're registered for wakeups, in case the
+        // sender wrote more or went away in the meantime:
+        match self.try_read(out)? {
+            0 => Poll::Pending,
+            nbytes => Poll::Ready(Ok(nbytes)),
+        }
     }
 
-    pub unsafe fn read_from_isr(&mut self, out: &mut [u8]) -> IsrResult<usize, Infallible> {
-        let bytes = self.read_internal(out);
-        if bytes > 0 {
-            self.shared.header().notify.wake_from_isr()
-                .map(|()| bytes)
+    /// Waits for at least one byte to become available and reads as many as
+    /// fit into out
+    pub async fn read(&mut self, out: &mut [u8]) -> Result<usize, Closed> {
+        poll_fn(|cx| self.poll_read(cx, out)).await
+    }
+
+    pub unsafe fn read_from_isr(&mut self, out: &mut [u8]) -> IsrResult<usize, Closed> {
+        let header = self.shared.header();
+        let tx_alive = header.is_tx_alive();
+
+        let nbytes = self.shared.read(out);
+
+        if nbytes > 0 {
+            header.notify_tx.wake_from_isr()
+                .chain(IsrResult::ok(nbytes, false))
+        } else if !tx_alive && !out.is_empty() {
+            IsrResult::err(Closed, false)
         } else {
-            IsrResult::ok(bytes, false)
+            IsrResult::ok(nbytes, false)
+        }
+    }
+
+    /// Returns true once the sender has been dropped. There may still be
+    /// bytes left in the buffer to read.
+    pub fn is_closed(&self) -> bool {
+        !self.shared.header().is_tx_alive()
+    }
+}
+
+impl Stream for StreamReceiver {
+    type Item = StreamChunk;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<StreamChunk>> {
+        let mut buffer = [0u8; STREAM_CHUNK_SIZE];
+
+        match self.get_mut().poll_read(cx, &mut buffer) {
+            Poll::Ready(Ok(nbytes)) => {
+                let chunk = StreamChunk::from_slice(&buffer[..nbytes])
+                    .expect("read at most STREAM_CHUNK_SIZE bytes");
+                Poll::Ready(Some(chunk))
+            }
+            Poll::Ready(Err(Closed)) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
 impl Drop for StreamReceiver {
     fn drop(&mut self) {
-        unsafe { self.shared.drop_rx(); }
+        let header = self.shared.header();
+        header.flags.fetch_and(!RX_ALIVE, Ordering::SeqCst);
+        header.notify_tx.wake_all();
+
+        unsafe { self.shared.release(); }
     }
 }
 
@@ -148,50 +248,67 @@ const TX_ALIVE: u32 = 1 << 0;
 const RX_ALIVE: u32 = 1 << 1;
 
 struct Header {
-    notify: TaskWakerSet,
+    notify_rx: TaskWakerSet,
+    notify_tx: TaskWakerSet,
     reader: AtomicUsize,
     writer: AtomicUsize,
     flags: AtomicU32,
+    /// Number of live `SharedRef`s, the allocation is freed when this hits 0
+    refcount: AtomicU32,
+    /// Length of the byte buffer following the header. Always one more than
+    /// capacity, as reader == writer indicates that the buffer is empty
     length: usize,
 }
 
-const HEADER_SIZE: usize = 20;
-const HEADER_ALIGN: usize = 4;
-const_assert_eq!(HEADER_SIZE, mem::size_of::<Header>());
-const_assert_eq!(HEADER_ALIGN, mem::align_of::<Header>());
+impl Header {
+    fn is_rx_alive(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & RX_ALIVE != 0
+    }
+
+    fn is_tx_alive(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & TX_ALIVE != 0
+    }
+}
+
+// the byte buffer immediately follows the header, u8 needs no padding:
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+const HEADER_ALIGN: usize = mem::align_of::<Header>();
 
-#[derive(Clone)]
 #[repr(transparent)]
 struct SharedRef {
     ptr: NonNull<Header>
 }
 
 impl SharedRef {
-    fn layout(capacity: usize) -> Layout {
+    fn layout(length: usize) -> Layout {
         Layout::from_size_align(
-            HEADER_SIZE + capacity,
+            HEADER_SIZE + length,
             HEADER_ALIGN,
         ).unwrap()
     }
 
     pub fn alloc(capacity: usize) -> Result<Self, MallocError> {
+        let length = capacity + 1;
+
         let header = Header {
-            notify: TaskWakerSet::new(),
+            notify_rx: TaskWakerSet::new(),
+            notify_tx: TaskWakerSet::new(),
             reader: AtomicUsize::new(0),
             writer: AtomicUsize::new(0),
             flags: AtomicU32::new(TX_ALIVE | RX_ALIVE),
-            length: capacity,
+            refcount: AtomicU32::new(1),
+            length,
         };
 
-        let ptr = heap::alloc_layout(Self::layout(capacity))?.cast::<Header>();
+        let ptr = heap::alloc_layout_tagged(Self::layout(length), Subsystem::Sync)?.cast::<Header>();
         unsafe { ptr::write(ptr.as_ptr(), header); }
 
-        Ok(SharedRef { ptr: ptr.cast() })
+        Ok(SharedRef { ptr })
     }
 
-    unsafe fn dealloc(&mut self) {
-        let length = self.header().length;
-        heap::free_layout(self.ptr.cast(), Self::layout(length));
+    fn clone_ref(&self) -> Self {
+        self.header().refcount.fetch_add(1, Ordering::SeqCst);
+        SharedRef { ptr: self.ptr }
     }
 
     pub fn header(&self) -> &Header {
@@ -202,19 +319,153 @@ impl SharedRef {
         unsafe { self.ptr.as_ptr().cast::<u8>().add(HEADER_SIZE) }
     }
 
-    unsafe fn drop_rx(&mut self) {
-        self.drop_side(RX_ALIVE);
+    /// Only the sender may call this
+    fn write(&self, data: &[u8]) -> usize {
+        let header = self.header();
+        let reader = header.reader.load(Ordering::Acquire);
+        let writer = header.writer.load(Ordering::Relaxed);
+
+        let (left, right) = unsafe {
+            ringbuffer::writer_slices(self.buffer(), header.length, writer, reader)
+        };
+
+        // we need to never fill up the entire buffer, since reader == writer
+        // indicates that it is empty:
+        let available = (left.len() + right.len()).saturating_sub(1);
+        let data = &data[..data.len().min(available)];
+
+        let copied = ringbuffer::copy_to_split(data, left, right);
+
+        let writer = (writer + copied) % header.length;
+        header.writer.store(writer, Ordering::Release);
+
+        copied
     }
 
-    unsafe fn drop_tx(&mut self) {
-        self.drop_side(TX_ALIVE);
+    /// Only the receiver may call this
+    fn read(&self, out: &mut [u8]) -> usize {
+        let header = self.header();
+        let reader = header.reader.load(Ordering::Relaxed);
+        let writer = header.writer.load(Ordering::Acquire);
+
+        let (left, right) = unsafe {
+            ringbuffer::reader_slices(self.buffer(), header.length, reader, writer)
+        };
+
+        let copied = ringbuffer::copy_from_split(left, right, out);
+
+        let reader = (reader + copied) % header.length;
+        header.reader.store(reader, Ordering::Release);
+
+        copied
     }
 
-    unsafe fn drop_side(&mut self, flags: u32) {
-        let prev = self.header().flags.fetch_and(!flags, Ordering::SeqCst);
-        let current = prev & !flags;
-        if current == 0 {
-            self.dealloc();
+    unsafe fn release(&mut self) {
+        let prev = self.header().refcount.fetch_sub(1, Ordering::SeqCst);
+        if prev == 1 {
+            let length = self.header().length;
+            heap::free_layout(self.ptr.cast(), Self::layout(length));
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use crate::system::task::{self, Sim};
+
+    use super::*;
+
+    #[test]
+    fn wraps_around() {
+        let (mut tx, mut rx) = channel(5).unwrap();
+        let mut out = [0u8; 8];
+
+        // move the read and write positions near the end of the buffer:
+        assert_eq!(tx.try_write(&[1, 2, 3, 4]).unwrap(), 4);
+        assert_eq!(rx.try_read(&mut out).unwrap(), 4);
+
+        // so that the next write has to continue at the start:
+        assert_eq!(tx.try_write(&[5, 6, 7, 8, 9]).unwrap(), 5);
+        assert_eq!(rx.try_read(&mut out).unwrap(), 5);
+        assert_eq!(&out[..5], &[5, 6, 7, 8, 9]);
+
+        assert_eq!(rx.try_read(&mut out).unwrap(), 0);
+    }
+
+    #[test]
+    fn partial_write_when_nearly_full() {
+        let (mut tx, mut rx) = channel(5).unwrap();
+        let mut out = [0u8; 8];
+
+        assert_eq!(tx.try_write(&[1, 2, 3]).unwrap(), 3);
+        assert_eq!(tx.try_write(&[4, 5, 6, 7]).unwrap(), 2);
+        assert_eq!(tx.try_write(&[8]).unwrap(), 0);
+
+        assert_eq!(rx.try_read(&mut out).unwrap(), 5);
+        assert_eq!(&out[..5], &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn partial_read_into_short_buffer() {
+        let (mut tx, mut rx) = channel(8).unwrap();
+        let mut out = [0u8; 3];
+
+        assert_eq!(tx.try_write(&[1, 2, 3, 4, 5]).unwrap(), 5);
+
+        assert_eq!(rx.try_read(&mut out).unwrap(), 3);
+        assert_eq!(out, [1, 2, 3]);
+        assert_eq!(rx.try_read(&mut out).unwrap(), 2);
+        assert_eq!(&out[..2], &[4, 5]);
+    }
+
+    #[test]
+    fn write_fails_once_receiver_dropped() {
+        let (mut tx, rx) = channel(4).unwrap();
+        drop(rx);
+
+        assert!(tx.is_closed());
+        assert!(matches!(tx.try_write(&[1]), Err(Closed)));
+        assert!(matches!(task::block_on(tx.write(&[1])), Err(Closed)));
+    }
+
+    #[test]
+    fn receiver_drains_after_sender_dropped() {
+        let (mut tx, mut rx) = channel(4).unwrap();
+        let mut out = [0u8; 4];
+
+        assert_eq!(tx.try_write(&[1, 2]).unwrap(), 2);
+        drop(tx);
+
+        assert!(rx.is_closed());
+        assert_eq!(task::block_on(rx.read(&mut out)).unwrap(), 2);
+        assert!(matches!(task::block_on(rx.read(&mut out)), Err(Closed)));
+    }
+
+    #[test]
+    fn write_waits_for_room() {
+        let (mut tx, mut rx) = channel(4).unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        let mut received = Vec::new();
+
+        let mut sim = Sim::new();
+
+        sim.spawn(async {
+            tx.write(&data).await.unwrap();
+            drop(tx);
+        });
+
+        sim.spawn(async {
+            let mut out = [0u8; 3];
+            while let Ok(nbytes) = rx.read(&mut out).await {
+                received.extend_from_slice(&out[..nbytes]);
+            }
+        });
+
+        sim.run();
+
+        assert_eq!(received, data);
+    }
+}
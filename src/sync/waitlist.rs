@@ -0,0 +1,141 @@
+//! FIFO list of futures waiting to acquire a lock. Shared by
+//! [`super::mutex::TaskMutex`] and [`super::rwlock::RwLock`].
+//!
+//! Waiters are identified by ticket rather than by task, since several
+//! futures running on the same task may be waiting at once. The owning lock
+//! grants waiters in order, and the granted waiter collects its grant the
+//! next time it is polled.
+//!
+//! The list holds at most [`MAX_WAITERS`] entries. Once full, further
+//! waiters are turned away and the lock has them wait for room instead.
+
+use core::task::Context;
+
+use heapless::Deque;
+
+use crate::system::task::{TaskWaker, MAX_TASKS};
+
+pub const MAX_WAITERS: usize = MAX_TASKS;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Ticket(u32);
+
+struct Waiter<K> {
+    ticket: Ticket,
+    kind: K,
+    waker: TaskWaker,
+    granted: bool,
+    /// Left the list, but not yet popped off because others are queued in
+    /// front of it
+    removed: bool,
+}
+
+pub struct WaitList<K> {
+    next_ticket: u32,
+    /// Number of waiters in the queue which have not yet been granted
+    waiting: usize,
+    queue: Deque<Waiter<K>, MAX_WAITERS>,
+}
+
+impl<K: Copy> WaitList<K> {
+    pub const fn new() -> Self {
+        WaitList {
+            next_ticket: 0,
+            waiting: 0,
+            queue: Deque::new(),
+        }
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.waiting
+    }
+
+    /// Adds a waiter to the back of the list. Returns `None` if the list is
+    /// full, in which case the caller should wait for an entry to leave and
+    /// try again.
+    pub fn enqueue(&mut self, cx: &Context, kind: K) -> Option<Ticket> {
+        let ticket = Ticket(self.next_ticket);
+
+        let waiter = Waiter {
+            ticket,
+            kind,
+            waker: TaskWaker::from_context(cx),
+            granted: false,
+            removed: false,
+        };
+
+        self.queue.push_back(waiter).ok()?;
+
+        self.next_ticket = self.next_ticket.wrapping_add(1);
+        self.waiting += 1;
+        Some(ticket)
+    }
+
+    /// Returns true and removes the waiter if it has been granted the lock,
+    /// otherwise refreshes its waker
+    pub fn poll_ticket(&mut self, ticket: Ticket, cx: &Context) -> bool {
+        let waiter = self.queue.iter_mut()
+            .find(|waiter| waiter.ticket == ticket && !waiter.removed)
+            .expect("ticket not in wait list");
+
+        if waiter.granted {
+            self.remove(ticket);
+            true
+        } else {
+            waiter.waker = TaskWaker::from_context(cx);
+            false
+        }
+    }
+
+    /// Removes a waiter which is giving up. Returns true if it had already
+    /// been granted the lock, in which case the caller must release it.
+    pub fn cancel(&mut self, ticket: Ticket) -> bool {
+        match self.remove(ticket) {
+            Some(true) => true,
+            Some(false) => {
+                self.waiting -= 1;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Kind of the longest waiting, not yet granted waiter
+    pub fn front_waiting(&self) -> Option<K> {
+        self.queue.iter()
+            .find(|waiter| !waiter.granted && !waiter.removed)
+            .map(|waiter| waiter.kind)
+    }
+
+    /// Grants the lock to the longest waiting, not yet granted waiter.
+    /// Returns its waker, which the caller should wake once it has left any
+    /// critical section.
+    pub fn grant_front(&mut self) -> Option<TaskWaker> {
+        let waiter = self.queue.iter_mut().find(|waiter| !waiter.granted && !waiter.removed)?;
+        waiter.granted = true;
+        self.waiting -= 1;
+        Some(waiter.waker)
+    }
+
+    /// Marks a waiter as removed, returning whether it had been granted.
+    /// Deque has no remove, so rather than shuffle entries around with
+    /// interrupts masked, removed entries stay in place until they reach
+    /// either end of the list.
+    fn remove(&mut self, ticket: Ticket) -> Option<bool> {
+        let waiter = self.queue.iter_mut()
+            .find(|waiter| waiter.ticket == ticket && !waiter.removed)?;
+
+        waiter.removed = true;
+        let granted = waiter.granted;
+
+        while matches!(self.queue.front(), Some(waiter) if waiter.removed) {
+            self.queue.pop_front();
+        }
+
+        while matches!(self.queue.back(), Some(waiter) if waiter.removed) {
+            self.queue.pop_back();
+        }
+
+        Some(granted)
+    }
+}
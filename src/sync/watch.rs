@@ -0,0 +1,239 @@
+//! Single value channel which always holds the latest value sent. Receivers
+//! can read the current value at any time, or wait for it to change.
+//!
+//! Values are copied in and out with interrupts masked, so T is bound by
+//! `Copy` and should be kept small.
+
+use core::convert::Infallible;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+use crate::system::heap::{MallocError, SharedBox, Subsystem};
+use crate::system::task::TaskWakerSet;
+
+use super::isr::IsrResult;
+use super::mutex::CriticalMutex;
+use super::error::Closed;
+
+struct Shared<T> {
+    value: CriticalMutex<T>,
+    version: AtomicU32,
+    closed: AtomicBool,
+    notify: TaskWakerSet,
+}
+
+pub struct WatchSender<T> {
+    shared: SharedBox<Shared<T>>,
+}
+
+#[derive(Clone)]
+pub struct WatchReceiver<T> {
+    shared: SharedBox<Shared<T>>,
+    seen_version: u32,
+}
+
+#[allow(unused)]
+pub fn channel<T: Copy + Send>(initial: T)
+    -> Result<(WatchSender<T>, WatchReceiver<T>), MallocError>
+{
+    let shared = SharedBox::alloc_tagged(Subsystem::Sync, Shared {
+        value: CriticalMutex::new(initial),
+        version: AtomicU32::new(0),
+        closed: AtomicBool::new(false),
+        notify: TaskWakerSet::new(),
+    })?;
+
+    let receiver = WatchReceiver { shared: shared.clone(), seen_version: 0 };
+    let sender = WatchSender { shared };
+
+    Ok((sender, receiver))
+}
+
+#[allow(unused)]
+impl<T: Copy + Send> WatchSender<T> {
+    pub fn send(&self, value: T) {
+        self.replace(value);
+        self.shared.notify.wake_all();
+    }
+
+    pub unsafe fn send_from_isr(&self, value: T) -> IsrResult<(), Infallible> {
+        self.replace(value);
+        self.shared.notify.wake_from_isr()
+    }
+
+    /// Sends only if value differs from the current value, so receivers
+    /// aren't woken needlessly
+    pub fn send_if_changed(&self, value: T) where T: PartialEq {
+        let changed = {
+            let mut current = self.shared.value.lock();
+            if *current != value {
+                *current = value;
+                self.shared.version.fetch_add(1, Ordering::SeqCst);
+                true
+            } else {
+                false
+            }
+        };
+
+        if changed {
+            self.shared.notify.wake_all();
+        }
+    }
+
+    pub fn subscribe(&self) -> WatchReceiver<T> {
+        WatchReceiver {
+            shared: self.shared.clone(),
+            seen_version: self.shared.version.load(Ordering::SeqCst),
+        }
+    }
+
+    fn replace(&self, value: T) {
+        let mut current = self.shared.value.lock();
+        *current = value;
+        self.shared.version.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl<T> Drop for WatchSender<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.notify.wake_all();
+    }
+}
+
+#[allow(unused)]
+impl<T: Copy + Send> WatchReceiver<T> {
+    /// Returns the current value without marking it as seen
+    pub fn get(&self) -> T {
+        *self.shared.value.lock()
+    }
+
+    /// Returns the current value and marks it as seen
+    pub fn get_and_update(&mut self) -> T {
+        let value = self.shared.value.lock();
+        self.seen_version = self.shared.version.load(Ordering::SeqCst);
+        *value
+    }
+
+    /// Returns true if a value has been sent since this receiver last
+    /// marked a value as seen
+    pub fn has_changed(&self) -> bool {
+        self.shared.version.load(Ordering::SeqCst) != self.seen_version
+    }
+
+    /// Waits for a value that this receiver has not yet seen, and marks it
+    /// as seen. Returns `Err(Closed)` once the sender has been dropped.
+    pub async fn changed(&mut self) -> Result<(), Closed> {
+        poll_fn(|cx| self.poll_changed(cx)).await
+    }
+
+    fn poll_changed(&mut self, cx: &Context) -> Poll<Result<(), Closed>> {
+        if let Some(result) = self.check_changed() {
+            return Poll::Ready(result);
+        }
+
+        self.shared.notify.add_task(cx);
+
+        // check again now that we're registered for wakeups:
+        match self.check_changed() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+
+    fn check_changed(&mut self) -> Option<Result<(), Closed>> {
+        let version = self.shared.version.load(Ordering::SeqCst);
+
+        if version != self.seen_version {
+            self.seen_version = version;
+            Some(Ok(()))
+        } else if self.shared.closed.load(Ordering::SeqCst) {
+            Some(Err(Closed))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    use crate::system::task::{self, time::sleep, Sim};
+
+    use super::*;
+
+    #[test]
+    fn receiver_sees_latest_value() {
+        let (tx, mut rx) = channel(0).unwrap();
+        assert!(!rx.has_changed());
+
+        tx.send(1);
+        tx.send(2);
+        assert!(rx.has_changed());
+        assert_eq!(rx.get(), 2);
+
+        task::block_on(rx.changed()).unwrap();
+        assert!(!rx.has_changed());
+        assert_eq!(rx.get_and_update(), 2);
+    }
+
+    #[test]
+    fn send_if_changed_skips_equal_values() {
+        let (tx, mut rx) = channel(5).unwrap();
+
+        tx.send_if_changed(5);
+        assert!(!rx.has_changed());
+
+        tx.send_if_changed(6);
+        assert!(rx.has_changed());
+        assert_eq!(rx.get_and_update(), 6);
+    }
+
+    #[test]
+    fn subscribe_starts_from_current_value() {
+        let (tx, _rx) = channel(0).unwrap();
+        tx.send(1);
+
+        let rx = tx.subscribe();
+        assert!(!rx.has_changed());
+        assert_eq!(rx.get(), 1);
+    }
+
+    #[test]
+    fn changed_reports_closed_after_last_value() {
+        let (tx, mut rx) = channel(0).unwrap();
+        tx.send(1);
+        drop(tx);
+
+        assert!(task::block_on(rx.changed()).is_ok());
+        assert!(matches!(task::block_on(rx.changed()), Err(Closed)));
+    }
+
+    #[test]
+    fn changed_waits_for_sender() {
+        let (tx, mut rx) = channel(0).unwrap();
+        let seen = RefCell::new(Vec::new());
+        let mut sim = Sim::new();
+
+        sim.spawn(async move {
+            for value in 1..=3 {
+                sleep(Duration::from_millis(10)).await;
+                tx.send(value);
+            }
+        });
+
+        sim.spawn(async {
+            while rx.changed().await.is_ok() {
+                seen.borrow_mut().push(rx.get());
+            }
+        });
+
+        sim.run();
+
+        assert_eq!(*seen.borrow(), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,105 @@
+//! Crash loop detection.
+//!
+//! Every boot is counted as a quick boot in RTC memory until it has stayed
+//! up for `STABLE_UPTIME`, at which point the count is cleared. If too many
+//! boots in a row crash before that, we come up in safe mode: no app, just
+//! Wi-Fi and the console, with config reverted to defaults. Safe mode
+//! retries a normal boot once it has itself been up for a while.
+
+use core::cell::SyncUnsafeCell;
+use core::ffi::c_void;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use cstr::cstr;
+use esp_idf_sys as sys;
+
+/// Arbitrary, changed whenever the layout of BootState changes
+const MAGIC: u32 = 0xb007_0001;
+
+/// Enter safe mode after this many boots in a row that didn't reach a
+/// stable uptime
+const QUICK_BOOT_LIMIT: u32 = 3;
+
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+/// How long safe mode waits before retrying a normal boot
+const SAFE_MODE_RETRY_UPTIME: Duration = Duration::from_secs(10 * 60);
+
+#[repr(C)]
+struct BootState {
+    magic: u32,
+    /// Number of boots in a row which didn't reach a stable uptime,
+    /// including the current one until it does
+    quick_boots: u32,
+}
+
+#[link_section = ".rtc_noinit"]
+static STATE: SyncUnsafeCell<MaybeUninit<BootState>> = SyncUnsafeCell::new(MaybeUninit::uninit());
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Call once only, from app_main before anything that might crash on bad
+/// config is started
+pub unsafe fn init() {
+    // every field is valid for any bit pattern, so this is fine even when
+    // RTC memory is garbage after power on:
+    let state = (*STATE.get()).assume_init_mut();
+
+    if state.magic != MAGIC || sys::esp_reset_reason() == sys::esp_reset_reason_t_ESP_RST_POWERON {
+        state.magic = MAGIC;
+        state.quick_boots = 0;
+    }
+
+    let previous_quick_boots = state.quick_boots;
+    state.quick_boots = previous_quick_boots.saturating_add(1);
+
+    let (safe_mode, stable_after) = if previous_quick_boots >= QUICK_BOOT_LIMIT {
+        log::error!("{previous_quick_boots} crashes in a row shortly after boot, entering safe mode!");
+        (true, SAFE_MODE_RETRY_UPTIME)
+    } else {
+        (false, STABLE_UPTIME)
+    };
+
+    SAFE_MODE.store(safe_mode, Ordering::SeqCst);
+
+    if let Err(e) = start_stable_timer(stable_after) {
+        log::warn!("failed to start boot stability timer: {e:?}");
+    }
+}
+
+/// True if the app should not be started this boot
+pub fn is_safe_mode() -> bool {
+    SAFE_MODE.load(Ordering::SeqCst)
+}
+
+unsafe fn start_stable_timer(after: Duration) -> Result<(), sys::EspError> {
+    let args = sys::esp_timer_create_args_t {
+        callback: Some(on_stable),
+        arg: ptr::null_mut(),
+        dispatch_method: sys::esp_timer_dispatch_t_ESP_TIMER_TASK,
+        name: cstr!("bark::boot").as_ptr(),
+        skip_unhandled_events: false,
+    };
+
+    let mut timer = ptr::null_mut();
+    sys::esp!(sys::esp_timer_create(&args, &mut timer))?;
+    sys::esp!(sys::esp_timer_start_once(timer, after.as_micros() as u64))?;
+
+    // the timer fires once and is never needed again, leak it
+    Ok(())
+}
+
+unsafe extern "C" fn on_stable(_arg: *mut c_void) {
+    let state = (*STATE.get()).assume_init_mut();
+    state.quick_boots = 0;
+
+    if is_safe_mode() {
+        log::warn!("safe mode has been stable, restarting to retry normal boot");
+        sys::esp_restart();
+    }
+
+    log::info!("boot is stable, crash loop counter reset");
+}
@@ -0,0 +1,247 @@
+//! Crash history, kept in RTC memory which survives a software reset.
+//!
+//! The panic handler records what happened here before the device resets,
+//! and the next boot reports any crashes it hasn't seen yet. The last few
+//! crashes are kept around for anyone who asks later. Power loss clears
+//! RTC memory, so the history only covers crashes since power on.
+
+use core::cell::SyncUnsafeCell;
+use core::ffi::CStr;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use esp_idf_sys as sys;
+
+use super::log::history;
+
+/// Arbitrary, changed whenever the layout of CrashLog changes
+const MAGIC: u32 = 0xc4a5_0001;
+
+pub const HISTORY: usize = 4;
+
+#[repr(C)]
+struct CrashLog {
+    magic: u32,
+    /// Number of crashes recorded since power on
+    total: u32,
+    /// Value of total as of the last boot, crashes after this have not been
+    /// reported yet
+    reported: u32,
+    /// Ring buffer of the most recent crashes, indexed by total % HISTORY
+    entries: [CrashRecord; HISTORY],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CrashKind {
+    Panic,
+    OutOfMemory,
+}
+
+/// Every field is valid for any bit pattern, so that reading garbage left
+/// in RTC memory after power on is never undefined behaviour
+#[derive(Clone)]
+#[repr(C)]
+pub struct CrashRecord {
+    uptime_ms: u64,
+    kind: u8,
+    message: FixedStr<120>,
+    location: FixedStr<64>,
+    task: FixedStr<16>,
+}
+
+impl CrashRecord {
+    pub fn kind(&self) -> CrashKind {
+        match self.kind {
+            1 => CrashKind::OutOfMemory,
+            _ => CrashKind::Panic,
+        }
+    }
+
+    pub fn uptime_ms(&self) -> u64 {
+        self.uptime_ms
+    }
+
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+
+    pub fn location(&self) -> &str {
+        self.location.as_str()
+    }
+
+    pub fn task(&self) -> &str {
+        self.task.as_str()
+    }
+}
+
+impl fmt::Display for CrashRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} in task {} after {}ms at {}: {}",
+            self.kind(), self.task(), self.uptime_ms(), self.location(), self.message())
+    }
+}
+
+/// Set by init if the last reset was caused by a crash
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
+#[link_section = ".rtc_noinit"]
+static LOG: SyncUnsafeCell<MaybeUninit<CrashLog>> = SyncUnsafeCell::new(MaybeUninit::uninit());
+
+/// Only valid after init has run
+unsafe fn crash_log() -> &'static mut CrashLog {
+    (*LOG.get()).assume_init_mut()
+}
+
+/// Call once only, early in boot. Reports any crashes since the last boot.
+pub unsafe fn init() {
+    let log = (*LOG.get()).as_mut_ptr();
+
+    if ptr::addr_of!((*log).magic).read() != MAGIC {
+        // first boot since power on, RTC memory is garbage:
+        ptr::write_bytes(log, 0, 1);
+        (*log).magic = MAGIC;
+    }
+
+    let log = crash_log();
+
+    let reset_reason = sys::esp_reset_reason();
+    log::info!("reset reason: {reset_reason}, {} crashes since power on", log.total);
+
+    let unreported = log.total.wrapping_sub(log.reported).min(HISTORY as u32);
+
+    for seq in (log.total - unreported)..log.total {
+        let record = &log.entries[seq as usize % HISTORY];
+        log::error!("crashed before last reset: {record}");
+    }
+
+    log.reported = log.total;
+
+    let crashed = unreported > 0 || is_crash_reset(reset_reason);
+    CRASHED.store(crashed, Ordering::Relaxed);
+
+    // the log history carried over from before the reset shows what led up
+    // to the crash:
+    if crashed {
+        log::info!("log output before last reset:");
+        history::print();
+    }
+}
+
+/// Resets we didn't ask for. Crashes we caught ourselves, such as running
+/// out of memory, restart cleanly and are only known from the crash log.
+fn is_crash_reset(reason: sys::esp_reset_reason_t) -> bool {
+    matches!(reason,
+        sys::esp_reset_reason_t_ESP_RST_PANIC
+        | sys::esp_reset_reason_t_ESP_RST_INT_WDT
+        | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+        | sys::esp_reset_reason_t_ESP_RST_WDT
+        | sys::esp_reset_reason_t_ESP_RST_BROWNOUT)
+}
+
+/// Whether the last reset was caused by a crash, either one we recorded in
+/// the crash log or one we only know of from the reset reason. Only valid
+/// after init has run.
+pub fn crashed_before_reset() -> bool {
+    CRASHED.load(Ordering::Relaxed)
+}
+
+/// Most recent crashes since power on, oldest first
+pub fn history() -> heapless::Vec<CrashRecord, HISTORY> {
+    let log = unsafe { crash_log() };
+    let count = log.total.min(HISTORY as u32);
+
+    (log.total - count..log.total)
+        .map(|seq| log.entries[seq as usize % HISTORY].clone())
+        .collect()
+}
+
+pub fn total() -> u32 {
+    unsafe { crash_log() }.total
+}
+
+/// Called from the panic handler
+pub fn record_panic(info: &PanicInfo) {
+    let mut record = new_record(CrashKind::Panic);
+    let _ = write!(&mut record.message, "{info}");
+
+    if let Some(location) = info.location() {
+        let _ = write!(&mut record.location, "{}:{}", location.file(), location.line());
+    }
+
+    push(record);
+}
+
+/// Called from the panic handler when the global allocator ran out
+pub fn record_oom(bytes: usize) {
+    let mut record = new_record(CrashKind::OutOfMemory);
+    let _ = write!(&mut record.message, "failed to allocate {bytes} bytes");
+    push(record);
+}
+
+fn new_record(kind: CrashKind) -> CrashRecord {
+    let uptime_us = unsafe { sys::esp_timer_get_time() };
+
+    let mut record = CrashRecord {
+        uptime_ms: (uptime_us / 1000) as u64,
+        kind: kind as u8,
+        message: FixedStr::new(),
+        location: FixedStr::new(),
+        task: FixedStr::new(),
+    };
+
+    let task = unsafe { CStr::from_ptr(sys::pcTaskGetName(ptr::null_mut())) };
+    let _ = record.task.write_str(task.to_str().unwrap_or_default());
+
+    record
+}
+
+fn push(record: CrashRecord) {
+    let log = unsafe { crash_log() };
+
+    // skip if we're crashing before init, the log is not valid yet:
+    if log.magic != MAGIC {
+        return;
+    }
+
+    log.entries[log.total as usize % HISTORY] = record;
+    log.total = log.total.wrapping_add(1);
+}
+
+/// Fixed capacity string which silently truncates on overflow
+#[derive(Clone)]
+#[repr(C)]
+struct FixedStr<const N: usize> {
+    len: u8,
+    bytes: [u8; N],
+}
+
+impl<const N: usize> FixedStr<N> {
+    const fn new() -> Self {
+        FixedStr { len: 0, bytes: [0; N] }
+    }
+
+    fn as_str(&self) -> &str {
+        let len = usize::from(self.len).min(N);
+        let bytes = &self.bytes[..len];
+
+        // may have been truncated mid-character, or be garbage:
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+}
+
+impl<const N: usize> Write for FixedStr<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = usize::from(self.len).min(N);
+        let n = s.len().min(N - len).min(usize::from(u8::MAX) - len);
+        self.bytes[len..len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len = (len + n) as u8;
+        Ok(())
+    }
+}
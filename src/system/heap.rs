@@ -1,35 +1,116 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::ffi::c_void;
 use core::ptr::{NonNull, self};
-use esp_idf_sys as sys;
+
+use static_assertions::const_assert;
+
+use crate::sys;
+
+pub mod accounting;
+pub use accounting::Subsystem;
+
+pub mod caps;
+pub use caps::Caps;
 
 pub mod arc;
-pub use arc::SharedBox;
+pub use arc::{SharedBox, WeakBox};
+#[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+pub use arc::SharedSlot;
 
 pub mod boxed;
 pub use boxed::{HeapBox, UntypedHeapBox};
 
-// pub mod dma;
-// pub use dma::DmaBuffer;
+pub mod fallible;
+pub mod realtime;
+
+pub mod slot;
+#[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+pub use slot::{StaticBox, StaticSlot};
+
+pub mod dma;
+#[allow(unused)]
+pub use dma::DmaBuffer;
 
 // pub mod array;
 // pub use array::RawHeapArray;
 
+/// Warn when free heap drops below this many bytes
+const LOW_MEMORY_THRESHOLD: usize = 16 * 1024;
+
 #[derive(Debug)]
 pub struct MallocError {
     #[allow(unused)]
     bytes: usize,
+    #[allow(unused)]
+    tag: Subsystem,
+}
+
+/// Every allocation made through this module is prefixed with a header
+/// recording which subsystem made it, so that frees can be attributed
+/// correctly no matter how the pointer travelled in the meantime. The header
+/// sits immediately before the pointer handed out, see [`header_offset`].
+#[repr(C)]
+struct Header {
+    tag: Subsystem,
 }
 
+/// Space reserved in front of each allocation for the [`Header`]. 8 bytes
+/// so that layouts aligned up to 8 stay aligned.
+const HEADER_SIZE: usize = 8;
+
+const_assert!(core::mem::size_of::<Header>() <= HEADER_SIZE);
+
+/// Alignment of every pointer returned by malloc and heap_caps_malloc.
+/// Anything more strictly aligned goes through heap_caps_aligned_alloc.
+const MALLOC_ALIGN: usize = 4;
+
 /// Allocates uninitialized memory to fit a `T`
+#[allow(unused)]
 pub fn alloc<T>() -> Result<NonNull<T>, MallocError> {
-    alloc_layout(Layout::new::<T>()).map(NonNull::cast)
+    alloc_tagged(Subsystem::Other)
+}
+
+pub fn alloc_tagged<T>(tag: Subsystem) -> Result<NonNull<T>, MallocError> {
+    alloc_layout_tagged(Layout::new::<T>(), tag).map(NonNull::cast)
 }
 
+#[allow(unused)]
 pub fn alloc_layout(layout: Layout) -> Result<NonNull<c_void>, MallocError> {
-    NonNull::new(unsafe { SYSTEM_MALLOC.alloc(layout) })
-        .map(NonNull::cast)
-        .ok_or(MallocError { bytes: layout.size() })
+    alloc_layout_tagged(layout, Subsystem::Other)
+}
+
+pub fn alloc_layout_tagged(layout: Layout, tag: Subsystem) -> Result<NonNull<c_void>, MallocError> {
+    alloc_layout_caps(layout, tag, Caps::DEFAULT)
+}
+
+/// Allocates from memory satisfying caps. Memory allocated here is freed
+/// with [`free_layout`] like any other allocation.
+pub fn alloc_layout_caps(layout: Layout, tag: Subsystem, caps: Caps) -> Result<NonNull<c_void>, MallocError> {
+    let error = || {
+        accounting::record_failure(tag);
+        MallocError { bytes: layout.size(), tag }
+    };
+
+    let full_layout = with_header(layout).ok_or_else(error)?;
+
+    // the header means size is never zero, so no sentinel needed here:
+    let ptr = match caps {
+        Caps::DEFAULT => unsafe { SYSTEM_MALLOC.malloc(full_layout) },
+        _ if full_layout.align() > MALLOC_ALIGN => unsafe {
+            sys::heap_caps_aligned_alloc(full_layout.align(), full_layout.size(), caps.bits()).cast()
+        },
+        _ => unsafe { sys::heap_caps_malloc(full_layout.size(), caps.bits()).cast() },
+    };
+
+    let ptr = NonNull::new(ptr).ok_or_else(error)?;
+
+    accounting::record_alloc(tag, layout.size());
+
+    unsafe {
+        let ptr = ptr.as_ptr().add(header_offset(layout));
+        ptr.cast::<Header>().sub(1).write(Header { tag });
+        Ok(NonNull::new_unchecked(ptr).cast())
+    }
 }
 
 /// Frees underlying allocation without calling [`Drop::drop`] on `ptr`
@@ -39,42 +120,165 @@ pub unsafe fn free<T>(ptr: NonNull<T>) {
 }
 
 pub unsafe fn free_layout(ptr: NonNull<c_void>, layout: Layout) {
-    SYSTEM_MALLOC.dealloc(ptr.cast().as_ptr(), layout);
+    let header = ptr.as_ptr().cast::<Header>().sub(1).read();
+    let base = ptr.as_ptr().cast::<u8>().sub(header_offset(layout));
+
+    accounting::record_free(header.tag, layout.size());
+
+    // with_header succeeded on alloc, so it will succeed here. free matches
+    // heap_caps_malloc and heap_caps_aligned_alloc just as well:
+    let full_layout = with_header(layout).unwrap();
+    SYSTEM_MALLOC.free(base, full_layout);
+}
+
+/// Distance from the start of the underlying allocation to the pointer
+/// handed out. Padded past [`HEADER_SIZE`] for over-aligned layouts, so that
+/// the pointer keeps the alignment of the allocation.
+fn header_offset(layout: Layout) -> usize {
+    HEADER_SIZE.max(layout.align())
 }
 
+fn with_header(layout: Layout) -> Option<Layout> {
+    let size = layout.size().checked_add(header_offset(layout))?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapInfo {
+    pub free: usize,
+    pub largest_free_block: usize,
+    pub minimum_free: usize,
+}
+
+pub fn info() -> HeapInfo {
+    info_caps(Caps::DEFAULT)
+}
+
+pub fn info_caps(caps: Caps) -> HeapInfo {
+    unsafe {
+        HeapInfo {
+            free: sys::heap_caps_get_free_size(caps.bits()),
+            largest_free_block: sys::heap_caps_get_largest_free_block(caps.bits()),
+            minimum_free: sys::heap_caps_get_minimum_free_size(caps.bits()),
+        }
+    }
+}
+
+pub fn check_low_memory(info: &HeapInfo) {
+    if info.free < LOW_MEMORY_THRESHOLD {
+        log::warn!("low memory! free={} largest_free_block={} threshold={}",
+            info.free, info.largest_free_block, LOW_MEMORY_THRESHOLD);
+    }
+}
+
+#[cfg_attr(not(test), global_allocator)]
 static SYSTEM_MALLOC: SystemMalloc = SystemMalloc;
 
 struct SystemMalloc;
 
-unsafe impl GlobalAlloc for SystemMalloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // ISO C spec says that malloc always returns a memory address that's
-        // suitable for a pointer to any object that fits within the size
-        // specified. In practise, this means we can safely ignore the align
-        // requested, because we will always satisfy it anyway.
+impl SystemMalloc {
+    unsafe fn malloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() == 0 {
+            // never dereferenced, only needs to be non-null and aligned
+            return layout.align() as *mut u8;
+        }
+
+        if layout.align() > MALLOC_ALIGN {
+            return sys::heap_caps_aligned_alloc(layout.align(), layout.size(), sys::MALLOC_CAP_DEFAULT).cast();
+        }
 
         u32::try_from(layout.size())
-            .map(|bytes| match bytes {
-                0 => setinel().as_ptr(),
-                _ => sys::malloc(bytes),
-            })
+            .map(|bytes| sys::malloc(bytes))
             .unwrap_or(ptr::null_mut())
             .cast::<u8>()
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn free(&self, ptr: *mut u8, layout: Layout) {
         if layout.size() == 0 {
-            // treated specially in alloc
+            // treated specially in malloc
             return;
         }
 
-        sys::free(ptr.cast::<c_void>())
+        if layout.align() > MALLOC_ALIGN {
+            sys::heap_caps_aligned_free(ptr.cast());
+        } else {
+            sys::free(ptr.cast::<c_void>());
+        }
     }
 }
 
-fn setinel<T>() -> NonNull<T> {
-    unsafe {
-        static mut SENTINEL: u8 = 0;
-        NonNull::new_unchecked(&mut SENTINEL).cast()
+/// Allocations made through `alloc` collections land here. They carry no
+/// header, so they're all accounted against [`Subsystem::Global`].
+unsafe impl GlobalAlloc for SystemMalloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.malloc(layout);
+
+        if ptr.is_null() {
+            // infallible APIs go on to call the alloc error handler in
+            // system::panic, fallible ones return the error to the caller
+            accounting::record_failure(Subsystem::Global);
+        } else {
+            accounting::record_alloc(Subsystem::Global, layout.size());
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        accounting::record_free(Subsystem::Global, layout.size());
+        self.free(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_keep_their_alignment() {
+        for align in [1, 2, 4, 8, 16, 64, 256] {
+            for caps in [Caps::DEFAULT, Caps::INTERNAL] {
+                let layout = Layout::from_size_align(24, align).unwrap();
+                let ptr = alloc_layout_caps(layout, Subsystem::Net, caps).unwrap();
+
+                assert_eq!(ptr.as_ptr() as usize % align, 0, "align {align}");
+
+                unsafe {
+                    ptr.as_ptr().cast::<u8>().write_bytes(0xaa, layout.size());
+                    assert_eq!(ptr.as_ptr().cast::<Header>().sub(1).read().tag, Subsystem::Net);
+                    free_layout(ptr, layout);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn global_allocator_keeps_alignment() {
+        for align in [1, 4, 8, 32, 128] {
+            for size in [0, 1, 100] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+
+                unsafe {
+                    let ptr = SYSTEM_MALLOC.alloc(layout);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0, "align {align}");
+
+                    ptr.write_bytes(0xaa, size);
+                    SYSTEM_MALLOC.dealloc(ptr, layout);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn header_offset_rounds_up_to_alignment() {
+        let layout = Layout::from_size_align(10, 32).unwrap();
+        let full = with_header(layout).unwrap();
+
+        assert_eq!(header_offset(layout), 32);
+        assert_eq!(full.size(), 42);
+        assert_eq!(full.align(), 32);
+
+        assert_eq!(header_offset(Layout::new::<u32>()), HEADER_SIZE);
     }
 }
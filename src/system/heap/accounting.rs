@@ -0,0 +1,135 @@
+//! Allocation counters, tagged by the subsystem that made the allocation.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Subsystem {
+    Other,
+    Task,
+    Sync,
+    Net,
+    Stream,
+    /// Packet buffers are allocated from and freed by lwIP, so only
+    /// allocations and failures are counted, not live bytes
+    Packet,
+    /// Anything allocated through the global allocator, eg. `alloc::vec::Vec`
+    Global,
+}
+
+impl Subsystem {
+    pub const COUNT: usize = 7;
+
+    pub const ALL: [Subsystem; Self::COUNT] = [
+        Subsystem::Other,
+        Subsystem::Task,
+        Subsystem::Sync,
+        Subsystem::Net,
+        Subsystem::Stream,
+        Subsystem::Packet,
+        Subsystem::Global,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Other => "other",
+            Subsystem::Task => "task",
+            Subsystem::Sync => "sync",
+            Subsystem::Net => "net",
+            Subsystem::Stream => "stream",
+            Subsystem::Packet => "packet",
+            Subsystem::Global => "global",
+        }
+    }
+
+    fn counters(self) -> &'static Counters {
+        &COUNTERS[self as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SubsystemStats {
+    pub allocs: u32,
+    pub frees: u32,
+    pub failures: u32,
+    pub live_bytes: u32,
+    pub peak_bytes: u32,
+    /// Allocations made on the audio path after init, see [`super::realtime`]
+    pub faults: u32,
+}
+
+struct Counters {
+    allocs: AtomicU32,
+    frees: AtomicU32,
+    failures: AtomicU32,
+    live_bytes: AtomicU32,
+    peak_bytes: AtomicU32,
+    faults: AtomicU32,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            allocs: AtomicU32::new(0),
+            frees: AtomicU32::new(0),
+            failures: AtomicU32::new(0),
+            live_bytes: AtomicU32::new(0),
+            peak_bytes: AtomicU32::new(0),
+            faults: AtomicU32::new(0),
+        }
+    }
+}
+
+static COUNTERS: [Counters; Subsystem::COUNT] = [
+    Counters::new(),
+    Counters::new(),
+    Counters::new(),
+    Counters::new(),
+    Counters::new(),
+    Counters::new(),
+    Counters::new(),
+];
+
+pub fn record_alloc(tag: Subsystem, bytes: usize) {
+    let counters = tag.counters();
+    counters.allocs.fetch_add(1, Ordering::Relaxed);
+
+    super::realtime::check_alloc(tag);
+
+    let bytes = bytes as u32;
+    let live = counters.live_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    counters.peak_bytes.fetch_max(live, Ordering::Relaxed);
+}
+
+/// For allocations which are freed outside of our control
+pub fn record_untracked_alloc(tag: Subsystem) {
+    tag.counters().allocs.fetch_add(1, Ordering::Relaxed);
+    super::realtime::check_alloc(tag);
+}
+
+pub fn record_free(tag: Subsystem, bytes: usize) {
+    let counters = tag.counters();
+    counters.frees.fetch_add(1, Ordering::Relaxed);
+    counters.live_bytes.fetch_sub(bytes as u32, Ordering::Relaxed);
+}
+
+pub fn record_failure(tag: Subsystem) {
+    tag.counters().failures.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_fault(tag: Subsystem) {
+    tag.counters().faults.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn stats(tag: Subsystem) -> SubsystemStats {
+    let counters = tag.counters();
+
+    SubsystemStats {
+        allocs: counters.allocs.load(Ordering::Relaxed),
+        frees: counters.frees.load(Ordering::Relaxed),
+        failures: counters.failures.load(Ordering::Relaxed),
+        live_bytes: counters.live_bytes.load(Ordering::Relaxed),
+        peak_bytes: counters.peak_bytes.load(Ordering::Relaxed),
+        faults: counters.faults.load(Ordering::Relaxed),
+    }
+}
@@ -1,34 +1,125 @@
+use core::cell::UnsafeCell;
+use core::mem::{ManuallyDrop, MaybeUninit};
 use core::ptr::NonNull;
-use core::sync::atomic::{Ordering, AtomicUsize};
+use core::sync::atomic::{Ordering, AtomicBool, AtomicUsize};
 use core::ops::Deref;
 
-use super::{HeapBox, MallocError};
+use super::{Caps, HeapBox, MallocError, Subsystem, free};
 
 pub struct SharedBox<T> {
     ptr: NonNull<Inner<T>>
 }
 
+/// Non-owning handle to a [`SharedBox`] allocation. Keeps the allocation
+/// alive but not the value inside it.
+pub struct WeakBox<T> {
+    ptr: NonNull<Inner<T>>
+}
+
 unsafe impl<T: Send + Sync> Send for SharedBox<T> {}
 unsafe impl<T: Send + Sync> Sync for SharedBox<T> {}
+unsafe impl<T: Send + Sync> Send for WeakBox<T> {}
+unsafe impl<T: Send + Sync> Sync for WeakBox<T> {}
+
+/// Statically allocated storage for one [`SharedBox`], reusable once every
+/// `SharedBox` and [`WeakBox`] pointing into it has been dropped
+pub struct SharedSlot<T> {
+    inner: UnsafeCell<MaybeUninit<Inner<T>>>,
+    in_use: AtomicBool,
+}
+
+unsafe impl<T: Send + Sync> Sync for SharedSlot<T> {}
+
+#[derive(Debug)]
+pub struct SlotInUse;
 
 struct Inner<T> {
-    data: T,
-    refcount: AtomicUsize,
+    /// Dropped when strong hits 0
+    data: ManuallyDrop<T>,
+    strong: AtomicUsize,
+    /// Number of WeakBoxes, plus one held collectively by all SharedBoxes.
+    /// The allocation is freed when this hits 0
+    weak: AtomicUsize,
+    /// Set if this lives in a SharedSlot rather than on the heap
+    slot: Option<&'static AtomicBool>,
+}
+
+impl<T> SharedSlot<T> {
+    #[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+    pub const fn new() -> Self {
+        SharedSlot {
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+            in_use: AtomicBool::new(false),
+        }
+    }
 }
 
 impl<T> SharedBox<T> {
     pub fn alloc(value: T) -> Result<Self, MallocError> {
-        let ptr = HeapBox::into_raw(HeapBox::alloc(Inner {
-            data: value,
-            refcount: AtomicUsize::new(1),
+        Self::alloc_tagged(Subsystem::Other, value)
+    }
+
+    /// Like [`SharedBox::alloc`], accounting the allocation against tag.
+    /// Always placed in internal RAM, as atomics don't work in PSRAM.
+    pub fn alloc_tagged(tag: Subsystem, value: T) -> Result<Self, MallocError> {
+        let ptr = HeapBox::into_raw(HeapBox::alloc_caps(tag, Caps::INTERNAL, Inner {
+            data: ManuallyDrop::new(value),
+            strong: AtomicUsize::new(1),
+            weak: AtomicUsize::new(1),
+            slot: None,
         })?);
 
         Ok(SharedBox { ptr })
     }
 
+    /// Like [`SharedBox::alloc`], placing value in a static slot instead
+    /// of on the heap
+    #[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+    pub fn in_slot(slot: &'static SharedSlot<T>, value: T) -> Result<Self, SlotInUse> {
+        if slot.in_use.swap(true, Ordering::Acquire) {
+            return Err(SlotInUse);
+        }
+
+        let ptr = slot.inner.get().cast::<Inner<T>>();
+
+        unsafe {
+            ptr.write(Inner {
+                data: ManuallyDrop::new(value),
+                strong: AtomicUsize::new(1),
+                weak: AtomicUsize::new(1),
+                slot: Some(&slot.in_use),
+            });
+
+            Ok(SharedBox { ptr: NonNull::new_unchecked(ptr) })
+        }
+    }
+
+    #[allow(unused)]
     pub fn unique(shared: &SharedBox<T>) -> bool {
         let shared = unsafe { shared.ptr.as_ref() };
-        shared.refcount.load(Ordering::Relaxed) == 1
+        shared.strong.load(Ordering::Relaxed) == 1
+    }
+
+    pub fn downgrade(shared: &SharedBox<T>) -> WeakBox<T> {
+        unsafe {
+            shared.ptr.as_ref().weak.fetch_add(1, Ordering::SeqCst);
+        }
+        WeakBox { ptr: shared.ptr }
+    }
+}
+
+impl<T> WeakBox<T> {
+    /// Returns `None` once all [`SharedBox`]es have been dropped
+    pub fn upgrade(&self) -> Option<SharedBox<T>> {
+        let inner = unsafe { self.ptr.as_ref() };
+
+        // never resurrect a value that has already been dropped, so only
+        // increment strong if it's still non-zero:
+        inner.strong.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |strong| {
+            (strong != 0).then(|| strong + 1)
+        }).ok()?;
+
+        Some(SharedBox { ptr: self.ptr })
     }
 }
 
@@ -43,22 +134,184 @@ impl<T> Deref for SharedBox<T> {
 impl<T> Clone for SharedBox<T> {
     fn clone(&self) -> Self {
         unsafe {
-            self.ptr.as_ref().refcount.fetch_add(1, Ordering::SeqCst);
+            self.ptr.as_ref().strong.fetch_add(1, Ordering::SeqCst);
         }
         SharedBox { ptr: self.ptr }
     }
 }
 
+impl<T> Clone for WeakBox<T> {
+    fn clone(&self) -> Self {
+        unsafe {
+            self.ptr.as_ref().weak.fetch_add(1, Ordering::SeqCst);
+        }
+        WeakBox { ptr: self.ptr }
+    }
+}
+
 impl<T> Drop for SharedBox<T> {
     fn drop(&mut self) {
-        let refcount = unsafe {
-            self.ptr.as_ref().refcount.fetch_sub(1, Ordering::SeqCst)
+        let strong = unsafe {
+            self.ptr.as_ref().strong.fetch_sub(1, Ordering::SeqCst)
         };
 
-        if refcount == 1 {
-            // if previous refcount before fetch_sub was 1, we were the only
-            // owner. drop
-            unsafe { HeapBox::from_raw(self.ptr); }
+        if strong == 1 {
+            // if previous strong count before fetch_sub was 1, we were the
+            // last owner. drop the value, then release the weak ref held on
+            // behalf of all owners
+            unsafe {
+                ManuallyDrop::drop(&mut (*self.ptr.as_ptr()).data);
+                release_weak(self.ptr);
+            }
+        }
+    }
+}
+
+impl<T> Drop for WeakBox<T> {
+    fn drop(&mut self) {
+        unsafe { release_weak(self.ptr); }
+    }
+}
+
+unsafe fn release_weak<T>(ptr: NonNull<Inner<T>>) {
+    let weak = ptr.as_ref().weak.fetch_sub(1, Ordering::SeqCst);
+
+    if weak == 1 {
+        // data has already been dropped, release the allocation only:
+        match ptr.as_ref().slot {
+            Some(in_use) => in_use.store(false, Ordering::Release),
+            None => free(ptr),
+        }
+    }
+}
+
+/// Run these under Miri too, which catches use after free and data races
+/// the assertions alone can't: `cargo miri test --target <host> heap::arc`
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    /// Counts drops, and checks it's never touched after being dropped
+    struct Tracked {
+        drops: Arc<AtomicUsize>,
+        alive: bool,
+    }
+
+    impl Tracked {
+        fn new(drops: &Arc<AtomicUsize>) -> Self {
+            Tracked { drops: drops.clone(), alive: true }
+        }
+
+        fn check(&self) {
+            assert!(self.alive, "used after drop");
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.check();
+            self.alive = false;
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn value_dropped_with_last_strong_ref() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let shared = SharedBox::alloc(Tracked::new(&drops)).unwrap();
+        let weak = SharedBox::downgrade(&shared);
+        let clone = shared.clone();
+
+        assert!(!SharedBox::unique(&shared));
+
+        drop(shared);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        weak.upgrade().unwrap().check();
+
+        drop(clone);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn weak_refs_outlive_value() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let shared = SharedBox::alloc(Tracked::new(&drops)).unwrap();
+        let weak = SharedBox::downgrade(&shared);
+        let weak2 = weak.clone();
+
+        drop(shared);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+
+        // the allocation is only freed here, with the last weak ref:
+        drop(weak);
+        assert!(weak2.upgrade().is_none());
+        drop(weak2);
+    }
+
+    #[test]
+    fn slot_reusable_once_all_refs_dropped() {
+        static SLOT: SharedSlot<u32> = SharedSlot::new();
+
+        let shared = SharedBox::in_slot(&SLOT, 1).unwrap();
+        let weak = SharedBox::downgrade(&shared);
+        assert!(SharedBox::in_slot(&SLOT, 2).is_err());
+
+        drop(shared);
+        assert!(SharedBox::in_slot(&SLOT, 3).is_err());
+
+        drop(weak);
+        assert_eq!(*SharedBox::in_slot(&SLOT, 4).unwrap(), 4);
+    }
+
+    #[test]
+    fn upgrade_racing_last_drop() {
+        let rounds = if cfg!(miri) { 20 } else { 2000 };
+
+        for _ in 0..rounds {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let shared = SharedBox::alloc(Tracked::new(&drops)).unwrap();
+            let weak = SharedBox::downgrade(&shared);
+
+            let dropper = thread::spawn(move || drop(shared));
+
+            // either we win and keep the value alive a little longer, or
+            // the value has already gone and upgrade fails. never both:
+            let upgraded = weak.upgrade();
+
+            if let Some(shared) = &upgraded {
+                shared.check();
+            }
+
+            dropper.join().unwrap();
+
+            let expected_drops = if upgraded.is_some() { 0 } else { 1 };
+            assert_eq!(drops.load(Ordering::SeqCst), expected_drops);
+
+            drop(upgraded);
+            drop(weak);
+            assert_eq!(drops.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn weak_dropped_racing_last_strong_drop() {
+        let rounds = if cfg!(miri) { 20 } else { 2000 };
+
+        for _ in 0..rounds {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let shared = SharedBox::alloc(Tracked::new(&drops)).unwrap();
+            let weak = SharedBox::downgrade(&shared);
+
+            // whichever goes last frees the allocation, exactly once:
+            let dropper = thread::spawn(move || drop(weak));
+            drop(shared);
+            dropper.join().unwrap();
+
+            assert_eq!(drops.load(Ordering::SeqCst), 1);
         }
     }
 }
@@ -3,7 +3,9 @@ use core::ops::{Deref, DerefMut};
 use core::pin::Pin;
 use core::ptr::NonNull;
 
-use super::{MallocError, alloc, free};
+use core::alloc::Layout;
+
+use super::{Caps, MallocError, Subsystem, alloc_layout_caps, free};
 
 #[repr(transparent)]
 pub struct HeapBox<T> {
@@ -12,9 +14,22 @@ pub struct HeapBox<T> {
 
 impl<T> Unpin for HeapBox<T> {}
 
+unsafe impl<T: Send> Send for HeapBox<T> {}
+unsafe impl<T: Sync> Sync for HeapBox<T> {}
+
 impl<T> HeapBox<T> {
     pub fn alloc(value: T) -> Result<Self, MallocError> {
-        let ptr = alloc::<T>()?;
+        Self::alloc_tagged(Subsystem::Other, value)
+    }
+
+    /// Like [`HeapBox::alloc`], accounting the allocation against tag
+    pub fn alloc_tagged(tag: Subsystem, value: T) -> Result<Self, MallocError> {
+        Self::alloc_caps(tag, Caps::DEFAULT, value)
+    }
+
+    /// Like [`HeapBox::alloc_tagged`], placing value in memory satisfying caps
+    pub fn alloc_caps(tag: Subsystem, caps: Caps, value: T) -> Result<Self, MallocError> {
+        let ptr = alloc_layout_caps(Layout::new::<T>(), tag, caps)?.cast::<T>();
         unsafe { core::ptr::write(ptr.as_ptr(), value); }
         Ok(HeapBox { ptr })
     }
@@ -23,6 +38,10 @@ impl<T> HeapBox<T> {
         Self::alloc(value).map(Self::into_pin)
     }
 
+    pub fn pin_tagged(tag: Subsystem, value: T) -> Result<Pin<Self>, MallocError> {
+        Self::alloc_tagged(tag, value).map(Self::into_pin)
+    }
+
     pub fn into_pin(box_: Self) -> Pin<Self> {
         // SAFETY: can't move out of out Pin if T is not Unpin
         unsafe { Pin::new_unchecked(box_) }
@@ -103,6 +122,14 @@ pub struct UntypedHeapBox {
     drop: unsafe fn(NonNull<()>),
 }
 
+impl UntypedHeapBox {
+    /// drop is responsible for both dropping the value and releasing its
+    /// storage, which need not be on the heap
+    pub(super) unsafe fn from_raw_parts(ptr: NonNull<()>, drop: unsafe fn(NonNull<()>)) -> Self {
+        UntypedHeapBox { ptr, drop }
+    }
+}
+
 impl Drop for UntypedHeapBox {
     fn drop(&mut self) {
         unsafe {
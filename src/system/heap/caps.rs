@@ -0,0 +1,50 @@
+use core::ops::BitOr;
+
+use crate::sys;
+
+/// Memory capabilities an allocation must satisfy, passed through to
+/// `heap_caps_malloc` as a set of `MALLOC_CAP_*` flags
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Caps(u32);
+
+#[allow(unused)]
+impl Caps {
+    /// Whatever plain `malloc` would give us
+    pub const DEFAULT: Caps = Caps(sys::MALLOC_CAP_DEFAULT);
+
+    /// Internal RAM, still accessible while flash cache is disabled
+    pub const INTERNAL: Caps = Caps(sys::MALLOC_CAP_INTERNAL | sys::MALLOC_CAP_8BIT);
+
+    /// Memory usable by DMA capable peripherals
+    pub const DMA: Caps = Caps(sys::MALLOC_CAP_DMA);
+
+    /// External PSRAM. Slower than internal RAM and not accessible while
+    /// flash cache is disabled, so keep ISR-touched data out of here. Atomic
+    /// compare and swap doesn't work here either, so no locks or refcounts
+    pub const SPIRAM: Caps = Caps(sys::MALLOC_CAP_SPIRAM | sys::MALLOC_CAP_8BIT);
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// PSRAM if this board has any, otherwise the default heap
+    pub fn spiram_or_default() -> Caps {
+        if spiram_available() {
+            Caps::SPIRAM
+        } else {
+            Caps::DEFAULT
+        }
+    }
+}
+
+impl BitOr for Caps {
+    type Output = Caps;
+
+    fn bitor(self, other: Caps) -> Caps {
+        Caps(self.0 | other.0)
+    }
+}
+
+pub fn spiram_available() -> bool {
+    unsafe { sys::heap_caps_get_total_size(Caps::SPIRAM.bits()) > 0 }
+}
@@ -1,8 +1,12 @@
+use core::alloc::Layout;
 use core::mem::MaybeUninit;
 use core::ptr::{self, NonNull};
 use core::slice;
 
-use esp_idf_sys as sys;
+use super::{Caps, MallocError, Subsystem, alloc_layout_caps, free_layout};
+
+/// DMA descriptors want word aligned buffers
+const DMA_ALIGN: usize = 4;
 
 pub struct DmaBuffer {
     ptr: NonNull<u8>,
@@ -13,19 +17,25 @@ pub struct DmaBufferUninit {
     buffer: DmaBuffer,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct DmaAllocError {
-    #[allow(unused)]
-    bytes: usize,
+#[allow(unused)]
+pub fn alloc(size: usize) -> Result<DmaBufferUninit, MallocError> {
+    alloc_tagged(Subsystem::Other, size)
 }
 
-pub fn alloc(size: usize) -> Result<DmaBufferUninit, DmaAllocError> {
-    let ptr = unsafe { sys::heap_caps_malloc(size, sys::MALLOC_CAP_DMA) };
-    let ptr = NonNull::new(ptr).cast().ok_or(DmaAllocError { bytes: size })?;
+#[allow(unused)]
+pub fn alloc_tagged(tag: Subsystem, size: usize) -> Result<DmaBufferUninit, MallocError> {
+    let ptr = alloc_layout_caps(layout(size), tag, Caps::DMA)?.cast();
     let buffer = DmaBuffer { ptr, len: size };
     Ok(DmaBufferUninit { buffer })
 }
 
+fn layout(size: usize) -> Layout {
+    // DMA_ALIGN is a non-zero power of two, this only fails for sizes which
+    // could never be allocated anyway:
+    Layout::from_size_align(size, DMA_ALIGN).unwrap()
+}
+
+#[allow(unused)]
 impl DmaBuffer {
     pub fn len(&self) -> usize {
         self.len
@@ -48,6 +58,7 @@ impl DmaBuffer {
     }
 }
 
+#[allow(unused)]
 impl DmaBufferUninit {
     pub fn len(&self) -> usize {
         self.buffer.len
@@ -75,6 +86,6 @@ impl DmaBufferUninit {
 
 impl Drop for DmaBuffer {
     fn drop(&mut self) {
-        unsafe { sys::heap_caps_free(self.ptr.as_ptr().cast()); }
+        unsafe { free_layout(self.ptr.cast(), layout(self.len)); }
     }
 }
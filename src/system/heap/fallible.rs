@@ -0,0 +1,70 @@
+//! Fallible allocation helpers for `alloc` collections.
+//!
+//! Running out of memory through the infallible `alloc` APIs restarts the
+//! device, so realtime paths which must keep running should allocate
+//! through these instead and handle the error.
+
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{MallocError, Subsystem};
+
+fn error(bytes: usize) -> MallocError {
+    MallocError { bytes, tag: Subsystem::Global }
+}
+
+#[allow(unused)]
+pub fn try_box<T>(value: T) -> Result<Box<T>, MallocError> {
+    let layout = Layout::new::<T>();
+
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+
+    // SAFETY: layout has non-zero size
+    let ptr = unsafe { alloc(layout) }.cast::<T>();
+
+    if ptr.is_null() {
+        return Err(error(layout.size()));
+    }
+
+    // SAFETY: ptr was allocated by the global allocator with T's layout
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+#[allow(unused)]
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, MallocError> {
+    let mut vec = Vec::new();
+    try_reserve(&mut vec, capacity)?;
+    Ok(vec)
+}
+
+#[allow(unused)]
+pub fn try_reserve<T>(vec: &mut Vec<T>, additional: usize) -> Result<(), MallocError> {
+    vec.try_reserve_exact(additional)
+        .map_err(|_| error(additional.saturating_mul(core::mem::size_of::<T>())))
+}
+
+#[allow(unused)]
+pub fn try_push<T>(vec: &mut Vec<T>, value: T) -> Result<(), MallocError> {
+    if vec.len() == vec.capacity() {
+        vec.try_reserve(1)
+            .map_err(|_| error(core::mem::size_of::<T>()))?;
+    }
+
+    vec.push(value);
+    Ok(())
+}
+
+#[allow(unused)]
+pub fn try_string(s: &str) -> Result<String, MallocError> {
+    let mut string = String::new();
+    string.try_reserve_exact(s.len()).map_err(|_| error(s.len()))?;
+    string.push_str(s);
+    Ok(string)
+}
@@ -0,0 +1,89 @@
+//! Detects heap allocations on the audio path once init is complete.
+//!
+//! With the `static-alloc` feature, everything long-lived is allocated
+//! before the heap is sealed. After that, tasks on the audio path should
+//! never touch the heap, and any allocation they do make is counted as a
+//! fault against its subsystem rather than failing outright.
+
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::sys;
+
+use super::{accounting, Subsystem};
+
+/// The app task, the stream task, and the lwIP thread running our receive
+/// callback, with some room to spare
+const MAX_AUDIO_TASKS: usize = 4;
+
+static SEALED: AtomicBool = AtomicBool::new(false);
+
+static AUDIO_TASKS: [AtomicPtr<sys::tskTaskControlBlock>; MAX_AUDIO_TASKS] = [
+    AtomicPtr::new(null_mut()),
+    AtomicPtr::new(null_mut()),
+    AtomicPtr::new(null_mut()),
+    AtomicPtr::new(null_mut()),
+];
+
+/// Marks the end of init. Allocations from audio path tasks from here on
+/// are counted as faults.
+pub fn seal() {
+    if !SEALED.swap(true, Ordering::SeqCst) {
+        log::info!("heap sealed, audio path allocations will now be counted as faults");
+    }
+}
+
+pub fn is_sealed() -> bool {
+    SEALED.load(Ordering::Relaxed)
+}
+
+/// Keeps the current task registered as being on the audio path until
+/// dropped. Must be dropped on the task that created it, or after that
+/// task has stopped running audio path code.
+pub struct AudioPathTask {
+    index: Option<usize>,
+}
+
+impl AudioPathTask {
+    pub fn register_current() -> Self {
+        let task = unsafe { sys::xTaskGetCurrentTaskHandle() };
+
+        for (index, slot) in AUDIO_TASKS.iter().enumerate() {
+            let claimed = slot.compare_exchange(
+                null_mut(),
+                task,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+
+            if claimed.is_ok() {
+                return AudioPathTask { index: Some(index) };
+            }
+        }
+
+        log::warn!("too many audio path tasks, allocations on this task will not be checked");
+        AudioPathTask { index: None }
+    }
+}
+
+impl Drop for AudioPathTask {
+    fn drop(&mut self) {
+        if let Some(index) = self.index {
+            AUDIO_TASKS[index].store(null_mut(), Ordering::SeqCst);
+        }
+    }
+}
+
+/// Called for every allocation. Cheap when the heap is not sealed, which is
+/// always the case without the `static-alloc` feature.
+pub(super) fn check_alloc(tag: Subsystem) {
+    if !is_sealed() {
+        return;
+    }
+
+    let task = unsafe { sys::xTaskGetCurrentTaskHandle() };
+
+    if AUDIO_TASKS.iter().any(|slot| slot.load(Ordering::Relaxed) == task) {
+        accounting::record_fault(tag);
+    }
+}
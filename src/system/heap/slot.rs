@@ -0,0 +1,111 @@
+use core::cell::UnsafeCell;
+use core::mem::{self, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::UntypedHeapBox;
+
+/// Statically allocated storage for one value of any type up to `WORDS`
+/// 8 byte words in size, for when the type can't be named in a `static`,
+/// eg. closures.
+#[repr(C)]
+pub struct StaticSlot<const WORDS: usize> {
+    // must be first, erased StaticBoxes find the slot from the data pointer:
+    data: UnsafeCell<MaybeUninit<[u64; WORDS]>>,
+    in_use: AtomicBool,
+}
+
+unsafe impl<const WORDS: usize> Sync for StaticSlot<WORDS> {}
+
+#[derive(Debug)]
+pub enum SlotError {
+    InUse,
+    TooLarge {
+        #[allow(unused)]
+        bytes: usize,
+    },
+}
+
+/// Owns a value placed in a [`StaticSlot`], freeing the slot on drop
+pub struct StaticBox<T, const WORDS: usize> {
+    slot: &'static StaticSlot<WORDS>,
+    ptr: NonNull<T>,
+}
+
+#[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+impl<const WORDS: usize> StaticSlot<WORDS> {
+    pub const fn new() -> Self {
+        StaticSlot {
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+            in_use: AtomicBool::new(false),
+        }
+    }
+
+    pub fn put<T>(&'static self, value: T) -> Result<StaticBox<T, WORDS>, SlotError> {
+        if mem::size_of::<T>() > WORDS * 8 || mem::align_of::<T>() > mem::align_of::<u64>() {
+            return Err(SlotError::TooLarge { bytes: mem::size_of::<T>() });
+        }
+
+        if self.in_use.swap(true, Ordering::Acquire) {
+            return Err(SlotError::InUse);
+        }
+
+        let ptr = self.data.get().cast::<T>();
+        unsafe { ptr.write(value); }
+
+        Ok(StaticBox {
+            slot: self,
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+        })
+    }
+
+    fn release(&self) {
+        self.in_use.store(false, Ordering::Release);
+    }
+}
+
+#[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+impl<T, const WORDS: usize> StaticBox<T, WORDS> {
+    pub fn as_borrowed_mut_ptr(box_: &StaticBox<T, WORDS>) -> *mut T {
+        box_.ptr.as_ptr()
+    }
+
+    /// Erases to the same type as [`super::HeapBox::erase_type`], so that
+    /// callers can hold either kind of allocation
+    pub fn erase_type(box_: StaticBox<T, WORDS>) -> UntypedHeapBox {
+        unsafe fn drop_release<T, const WORDS: usize>(ptr: NonNull<()>) {
+            core::ptr::drop_in_place(ptr.cast::<T>().as_ptr());
+
+            // data is the first field of the repr(C) slot:
+            let slot = ptr.cast::<StaticSlot<WORDS>>();
+            slot.as_ref().release();
+        }
+
+        let ptr = box_.ptr.cast();
+        mem::forget(box_);
+
+        unsafe { UntypedHeapBox::from_raw_parts(ptr, drop_release::<T, WORDS>) }
+    }
+}
+
+impl<T, const WORDS: usize> Deref for StaticBox<T, WORDS> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, const WORDS: usize> DerefMut for StaticBox<T, WORDS> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T, const WORDS: usize> Drop for StaticBox<T, WORDS> {
+    fn drop(&mut self) {
+        unsafe { core::ptr::drop_in_place(self.ptr.as_ptr()); }
+        self.slot.release();
+    }
+}
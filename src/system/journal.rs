@@ -0,0 +1,215 @@
+//! Fixed size, timestamped journal of notable events, for reconstructing
+//! what happened around an incident. Where `STATS` says how many underruns
+//! there were, the journal says when, and what else was going on.
+//!
+//! Recording is cheap and safe from interrupt handlers. Bursts of repeated
+//! events such as underruns are folded into one entry with a count, so a
+//! bad patch doesn't push everything else out of the journal.
+
+use core::fmt::{self, Display};
+use core::net::Ipv4Addr;
+
+use bark_protocol::types::SessionId;
+use esp_idf_sys as sys;
+
+use crate::platform::wifi;
+use crate::sync::mutex::CriticalMutex;
+
+pub const JOURNAL_LEN: usize = 64;
+
+/// Repeats of a burst event within this long of the last are folded into
+/// the same entry
+const BURST_GAP_MS: u64 = 1000;
+
+pub type TaskName = heapless::String<16>;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    WifiConnected { ip: Ipv4Addr },
+    WifiDisconnected { reason: u16, rssi: i8 },
+    SessionStart { sid: SessionId },
+    SessionStop { sid: SessionId },
+    /// The DAC ran out of audio
+    Underrun,
+    /// A packet arrived after its slot in the queue had already played
+    LatePacket,
+    TaskFailed { task: TaskName },
+}
+
+impl Event {
+    /// Index into `Journal::bursts` for events which come in bursts
+    fn burst(&self) -> Option<usize> {
+        match self {
+            Event::Underrun => Some(0),
+            Event::LatePacket => Some(1),
+            _ => None,
+        }
+    }
+}
+
+const BURST_KINDS: usize = 2;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub seq: u32,
+    /// Uptime when the event first occurred
+    pub at_ms: u64,
+    /// Uptime of the latest repeat, same as `at_ms` unless a burst
+    pub last_ms: u64,
+    /// Occurrences folded into this entry
+    pub count: u32,
+    pub event: Event,
+}
+
+struct Journal {
+    entries: [Option<Entry>; JOURNAL_LEN],
+    next_seq: u32,
+    /// Seq of the latest entry of each burst kind
+    bursts: [Option<u32>; BURST_KINDS],
+}
+
+static JOURNAL: CriticalMutex<Journal> = CriticalMutex::declare(Journal {
+    entries: [const { None }; JOURNAL_LEN],
+    next_seq: 0,
+    bursts: [None; BURST_KINDS],
+});
+
+impl Journal {
+    fn oldest_seq(&self) -> u32 {
+        self.next_seq.saturating_sub(JOURNAL_LEN as u32)
+    }
+
+    fn get_mut(&mut self, seq: u32) -> Option<&mut Entry> {
+        if seq < self.oldest_seq() || seq >= self.next_seq {
+            return None;
+        }
+
+        self.entries[seq as usize % JOURNAL_LEN].as_mut()
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.entries[entry.seq as usize % JOURNAL_LEN] = Some(entry);
+        self.next_seq += 1;
+    }
+}
+
+/// Records an event. May be called from interrupt context.
+pub fn record(event: Event) {
+    let now = uptime_ms();
+    let mut journal = JOURNAL.lock();
+
+    if let Some(kind) = event.burst() {
+        let open = journal.bursts[kind].and_then(|seq| journal.get_mut(seq));
+
+        if let Some(entry) = open {
+            if now.saturating_sub(entry.last_ms) < BURST_GAP_MS {
+                entry.last_ms = now;
+                entry.count = entry.count.saturating_add(1);
+                return;
+            }
+        }
+
+        journal.bursts[kind] = Some(journal.next_seq);
+    }
+
+    let seq = journal.next_seq;
+
+    journal.push(Entry {
+        seq,
+        at_ms: now,
+        last_ms: now,
+        count: 1,
+        event,
+    });
+}
+
+/// Seq that the next event recorded will have. Pass to [`read`] to see only
+/// what happened from now on.
+#[allow(unused)]
+pub fn next_seq() -> u32 {
+    JOURNAL.lock().next_seq
+}
+
+/// Calls `f` with each entry still in the journal from `since` onwards,
+/// oldest first. Only one entry is copied out at a time, so this is fine
+/// to call from a task with a small stack.
+pub fn read(since: u32, mut f: impl FnMut(&Entry)) {
+    let mut seq = since;
+
+    loop {
+        let entry = {
+            let mut journal = JOURNAL.lock();
+
+            if seq >= journal.next_seq {
+                return;
+            }
+
+            // skip ahead past anything already overwritten:
+            seq = seq.max(journal.oldest_seq());
+            journal.get_mut(seq).cloned()
+        };
+
+        if let Some(entry) = entry {
+            f(&entry);
+        }
+
+        seq += 1;
+    }
+}
+
+/// Truncates a task name to fit in [`Event::TaskFailed`]
+pub fn task_name(name: &str) -> TaskName {
+    let mut task = TaskName::new();
+
+    for c in name.chars() {
+        if task.push(c).is_err() {
+            break;
+        }
+    }
+
+    task
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} {}", self.seq, Uptime(self.at_ms))?;
+
+        match &self.event {
+            Event::WifiConnected { ip } => write!(f, " wifi connected: ip={ip}")?,
+            Event::WifiDisconnected { reason, rssi } => {
+                write!(f, " wifi disconnected: reason={reason}")?;
+
+                if let Some(name) = wifi::disconnect_reason_name(*reason) {
+                    write!(f, " ({name})")?;
+                }
+
+                write!(f, " rssi={rssi}")?;
+            }
+            Event::SessionStart { sid } => write!(f, " session start: sid={sid:?}")?,
+            Event::SessionStop { sid } => write!(f, " session stop: sid={sid:?}")?,
+            Event::Underrun => f.write_str(" underrun")?,
+            Event::LatePacket => f.write_str(" late packet")?,
+            Event::TaskFailed { task } => write!(f, " task failed: {task}")?,
+        }
+
+        if self.count > 1 {
+            write!(f, " x{} over {}ms", self.count, self.last_ms - self.at_ms)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats milliseconds of uptime as seconds
+struct Uptime(u64);
+
+impl Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "+{}.{:03}s", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+fn uptime_ms() -> u64 {
+    let micros = unsafe { sys::esp_timer_get_time() };
+    (micros / 1000) as u64
+}
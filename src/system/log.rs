@@ -5,10 +5,17 @@ use log::Level;
 use cstr::cstr;
 use core::fmt::Write;
 
+pub mod deferred;
+pub mod filter;
+pub mod history;
+pub mod ratelimit;
+pub mod syslog;
+
 pub fn init() {
     static LOG: EspLog = EspLog;
+    unsafe { history::init(); }
     log::set_logger(&LOG).expect("init logger");
-    log::set_max_level(log::LevelFilter::Trace);
+    log::set_max_level(filter::max_level());
 }
 
 const RESET: &str = "\x1b[0m";
@@ -16,11 +23,19 @@ const RESET: &str = "\x1b[0m";
 struct EspLog;
 
 impl log::Log for EspLog {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        filter::enabled(metadata.level(), metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
+        // the log macros don't call enabled themselves, check before we
+        // do any formatting:
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        syslog::forward(record);
+
         let color = log_color(record.level());
         let label = log_label(record.level());
         let target = record.target();
@@ -35,6 +50,8 @@ impl log::Log for EspLog {
             c_bright = color.bright,
         );
 
+        history::record(buffer.as_bytes());
+
         let tag = static_str(record.target())
             .unwrap_or("rust-dynamic");
 
@@ -104,6 +121,10 @@ impl<const SIZE: usize> Buffer<SIZE> {
         &mut self.buff[self.len..]
     }
 
+    fn as_bytes(&self) -> &[u8] {
+        &self.buff[0..self.len]
+    }
+
     fn as_bytes_with_nul(&self) -> &[u8] {
         &self.buff[0..(self.len + 1)]
     }
@@ -0,0 +1,201 @@
+//! Deferred logging, for interrupt handlers and time critical callbacks
+//! which can't afford to format and write out a log line.
+//!
+//! [`deferred!`] captures a static format string and up to [`MAX_ARGS`]
+//! plain arguments into a lock-free queue, and a low priority task formats
+//! and logs them later. Format strings support `{}` placeholders only.
+//! Records which don't fit in the queue are dropped and counted.
+
+use core::fmt::{self, Display};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use esp_idf_sys as sys;
+use log::Level;
+
+use crate::sync::mpsc::MpscQueue;
+use crate::sync::notify::Notify;
+use crate::system::task;
+
+pub const MAX_ARGS: usize = 4;
+
+const QUEUE_LEN: usize = 32;
+
+/// Records pushed from interrupt context can't wake the formatter task,
+/// they wait for the next periodic flush
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Logs from anywhere, including interrupt handlers, without formatting or
+/// blocking. Arguments must convert into [`Arg`].
+///
+/// ```ignore
+/// deferred!(Level::Debug, "underrun, {} frames short", missing);
+/// ```
+macro_rules! deferred {
+    ($level:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {
+        $crate::system::log::deferred::push(
+            $level,
+            module_path!(),
+            $fmt,
+            &[$($crate::system::log::deferred::Arg::from($arg)),*],
+        )
+    };
+}
+
+pub(crate) use deferred;
+
+#[derive(Clone, Copy)]
+pub enum Arg {
+    Unsigned(u32),
+    Signed(i32),
+    Str(&'static str),
+}
+
+impl From<u32> for Arg {
+    fn from(value: u32) -> Self { Arg::Unsigned(value) }
+}
+
+impl From<u16> for Arg {
+    fn from(value: u16) -> Self { Arg::Unsigned(value.into()) }
+}
+
+impl From<u8> for Arg {
+    fn from(value: u8) -> Self { Arg::Unsigned(value.into()) }
+}
+
+impl From<usize> for Arg {
+    fn from(value: usize) -> Self { Arg::Unsigned(value as u32) }
+}
+
+impl From<i32> for Arg {
+    fn from(value: i32) -> Self { Arg::Signed(value) }
+}
+
+impl From<bool> for Arg {
+    fn from(value: bool) -> Self { Arg::Str(if value { "true" } else { "false" }) }
+}
+
+impl From<&'static str> for Arg {
+    fn from(value: &'static str) -> Self { Arg::Str(value) }
+}
+
+impl Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arg::Unsigned(value) => write!(f, "{value}"),
+            Arg::Signed(value) => write!(f, "{value}"),
+            Arg::Str(value) => f.write_str(value),
+        }
+    }
+}
+
+struct Record {
+    level: Level,
+    target: &'static str,
+    fmt: &'static str,
+    args: [Arg; MAX_ARGS],
+    nargs: u8,
+    uptime_us: i64,
+}
+
+static QUEUE: MpscQueue<Record, QUEUE_LEN> = MpscQueue::new();
+static QUEUED: Notify = Notify::new();
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Called by [`deferred!`]. Arguments past [`MAX_ARGS`] are ignored.
+pub fn push(level: Level, target: &'static str, fmt: &'static str, args: &[Arg]) {
+    // cheap check to skip disabled levels, per target filters are applied
+    // once the record is formatted:
+    if level > log::max_level() {
+        return;
+    }
+
+    let mut record = Record {
+        level,
+        target,
+        fmt,
+        args: [Arg::Unsigned(0); MAX_ARGS],
+        nargs: args.len().min(MAX_ARGS) as u8,
+        uptime_us: unsafe { sys::esp_timer_get_time() },
+    };
+
+    record.args[..usize::from(record.nargs)].copy_from_slice(&args[..usize::from(record.nargs)]);
+
+    if QUEUE.try_push(record).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    if unsafe { sys::xPortInIsrContext() } == 0 {
+        QUEUED.notify();
+    }
+}
+
+/// Substitutes args into the format string
+struct Formatted<'a>(&'a Record);
+
+impl Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let record = self.0;
+        let mut args = record.args[..usize::from(record.nargs)].iter();
+        let mut rest = record.fmt;
+
+        while let Some(brace) = rest.find(['{', '}']) {
+            f.write_str(&rest[..brace])?;
+            rest = &rest[brace..];
+
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                f.write_str(&rest[..1])?;
+                rest = &rest[2..];
+            } else if rest.starts_with("{}") {
+                match args.next() {
+                    Some(arg) => write!(f, "{arg}")?,
+                    None => f.write_str("{?}")?,
+                }
+                rest = &rest[2..];
+            } else {
+                f.write_str(&rest[..1])?;
+                rest = &rest[1..];
+            }
+        }
+
+        f.write_str(rest)
+    }
+}
+
+pub fn start() {
+    let result = task::new("bark::logfmt")
+        .stack_size(4096)
+        .spawn(formatter_task);
+
+    if let Err(e) = result {
+        log::error!("failed to spawn deferred log task: {e:?}");
+    }
+}
+
+async fn formatter_task() {
+    loop {
+        let _ = task::timeout(FLUSH_INTERVAL, QUEUED.notified()).await;
+
+        // SAFETY: this task is the only consumer
+        while let Some(record) = unsafe { QUEUE.pop() } {
+            emit(&record);
+        }
+
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("deferred log queue full, dropped {dropped} records");
+        }
+    }
+}
+
+fn emit(record: &Record) {
+    let now = unsafe { sys::esp_timer_get_time() };
+    let delay_ms = (now - record.uptime_us) / 1000;
+
+    log::logger().log(&log::Record::builder()
+        .level(record.level)
+        .target(record.target)
+        .args(format_args!("{} (deferred {delay_ms}ms)", Formatted(record)))
+        .build());
+}
@@ -0,0 +1,203 @@
+//! Runtime log level filters, per target.
+//!
+//! Filters are written as a spec in the same form as `RUST_LOG`, eg.
+//! `warn,bark_esp::app=debug`: a default level, followed by levels for
+//! target prefixes. The longest matching prefix wins. The spec is persisted
+//! in NVS so it survives a reset, and is reverted along with the rest of
+//! the config in safe mode.
+
+use core::fmt::{self, Write};
+use core::str::FromStr;
+
+use cstr::cstr;
+use derive_more::From;
+use esp_idf_sys as sys;
+use log::{Level, LevelFilter};
+
+use crate::platform::nvs;
+use crate::sync::mutex::CriticalMutex;
+
+pub const MAX_TARGETS: usize = 8;
+pub const MAX_TARGET_LEN: usize = 48;
+
+/// Longest spec that can be produced from the limits above
+pub const MAX_SPEC_LEN: usize = 6 + MAX_TARGETS * (MAX_TARGET_LEN + 7);
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+const NVS_KEY: &core::ffi::CStr = cstr!("log");
+
+type Target = heapless::String<MAX_TARGET_LEN>;
+pub type Spec = heapless::String<MAX_SPEC_LEN>;
+
+struct Filters {
+    default: LevelFilter,
+    targets: heapless::Vec<(Target, LevelFilter), MAX_TARGETS>,
+}
+
+static FILTERS: CriticalMutex<Filters> = CriticalMutex::declare(Filters {
+    default: DEFAULT_LEVEL,
+    targets: heapless::Vec::new(),
+});
+
+#[derive(Debug)]
+pub enum SpecError {
+    TooManyTargets,
+    TargetTooLong,
+    BadLevel,
+}
+
+impl Filters {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let mut best: Option<(usize, LevelFilter)> = None;
+
+        for (prefix, level) in &self.targets {
+            if !matches_prefix(target, prefix) {
+                continue;
+            }
+
+            if best.map(|(len, _)| prefix.len() > len).unwrap_or(true) {
+                best = Some((prefix.len(), *level));
+            }
+        }
+
+        best.map(|(_, level)| level).unwrap_or(self.default)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets.iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, LevelFilter::max)
+    }
+
+    fn set(&mut self, target: &str, level: LevelFilter) -> Result<(), SpecError> {
+        if let Some(entry) = self.targets.iter_mut().find(|(prefix, _)| prefix == target) {
+            entry.1 = level;
+            return Ok(());
+        }
+
+        let prefix = Target::from_str(target).map_err(|()| SpecError::TargetTooLong)?;
+
+        self.targets.push((prefix, level))
+            .map_err(|_| SpecError::TooManyTargets)
+    }
+}
+
+/// Targets are module paths, so a prefix only matches whole path segments
+fn matches_prefix(target: &str, prefix: &str) -> bool {
+    match target.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+/// Called from the logger before any formatting is done. Safe to call from
+/// interrupt context.
+pub fn enabled(level: Level, target: &str) -> bool {
+    level <= FILTERS.lock().level_for(target)
+}
+
+/// Most verbose level enabled for any target, for `log::set_max_level`
+pub fn max_level() -> LevelFilter {
+    FILTERS.lock().max_level()
+}
+
+pub fn set_default(level: LevelFilter) {
+    FILTERS.lock().default = level;
+    log::set_max_level(max_level());
+}
+
+pub fn set(target: &str, level: LevelFilter) -> Result<(), SpecError> {
+    FILTERS.lock().set(target, level)?;
+    log::set_max_level(max_level());
+    Ok(())
+}
+
+/// Replaces all filters with those in `spec`. On error, the existing
+/// filters are left untouched.
+pub fn set_spec(spec: &str) -> Result<(), SpecError> {
+    let mut filters = Filters {
+        default: DEFAULT_LEVEL,
+        targets: heapless::Vec::new(),
+    };
+
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((target, level)) => {
+                let level = parse_level(level)?;
+                filters.set(target.trim(), level)?;
+            }
+            None => {
+                filters.default = parse_level(directive)?;
+            }
+        }
+    }
+
+    let max = filters.max_level();
+    *FILTERS.lock() = filters;
+    log::set_max_level(max);
+    Ok(())
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, SpecError> {
+    LevelFilter::from_str(s.trim()).map_err(|_| SpecError::BadLevel)
+}
+
+/// Current filters, in the form accepted by [`set_spec`]
+pub fn spec() -> Spec {
+    let mut spec = Spec::new();
+    let _ = write_spec(&mut spec);
+    spec
+}
+
+fn write_spec(out: &mut impl Write) -> fmt::Result {
+    // copy out so we don't format inside a critical section:
+    let (default, targets) = {
+        let filters = FILTERS.lock();
+        (filters.default, filters.targets.clone())
+    };
+
+    write!(out, "{}", level_name(default))?;
+
+    for (target, level) in &targets {
+        write!(out, ",{target}={}", level_name(*level))?;
+    }
+
+    Ok(())
+}
+
+fn level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+#[derive(Debug, From)]
+pub enum LoadError {
+    Nvs(sys::EspError),
+    Spec(SpecError),
+}
+
+/// Applies the filters persisted in NVS, if any. Call after NVS is
+/// initialized.
+pub fn load() -> Result<(), LoadError> {
+    // read_config_str needs room for the nul terminator:
+    let spec = unsafe { nvs::read_config_str::<{ MAX_SPEC_LEN + 1 }>(NVS_KEY)? };
+
+    if let Some(spec) = spec {
+        set_spec(&spec)?;
+    }
+
+    Ok(())
+}
+
+/// Persists the current filters to NVS, to be applied on next boot
+pub fn save() -> Result<(), sys::EspError> {
+    let spec = spec();
+    unsafe { nvs::write_config_str(NVS_KEY, &spec) }
+}
@@ -0,0 +1,174 @@
+//! The most recent log output, kept in RTC memory which survives a software
+//! reset, so that after a dropout or crash we can see what led up to it.
+//!
+//! Lines are captured without their colour codes. Capture never blocks the
+//! logger: if another task or an interrupt is writing or dumping the history
+//! at the same moment, the line is left out of the history and counted
+//! instead.
+
+use core::cell::SyncUnsafeCell;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use esp_println::{print, println};
+
+use crate::sync::ringbuffer::RingBuffer;
+
+/// Arbitrary, changed whenever the layout of History changes
+const MAGIC: u32 = 0x106b_0001;
+
+/// RTC slow memory is only 8KiB in total, shared with the crash log and
+/// boot state
+pub const HISTORY_BYTES: usize = 4096;
+
+#[repr(C)]
+struct History {
+    magic: u32,
+    ring: RingBuffer<u8, HISTORY_BYTES>,
+}
+
+#[link_section = ".rtc_noinit"]
+static HISTORY: SyncUnsafeCell<MaybeUninit<History>> = SyncUnsafeCell::new(MaybeUninit::uninit());
+
+/// Set once init has validated the history
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Held by whoever is writing or dumping the history
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+static MISSED: AtomicU32 = AtomicU32::new(0);
+
+/// Call once only, before the first line is logged
+pub unsafe fn init() {
+    let history = (*HISTORY.get()).as_mut_ptr();
+
+    // indices may be garbage after power on even if the magic happens to
+    // match, check them too:
+    let valid = ptr::addr_of!((*history).magic).read() == MAGIC
+        && (*history).ring.is_consistent();
+
+    if !valid {
+        // zero is a valid empty ring buffer:
+        ptr::write_bytes(history, 0, 1);
+        (*history).magic = MAGIC;
+    }
+
+    READY.store(true, Ordering::Release);
+
+    // mark where this boot starts in the output carried over from the last:
+    record(b"--- boot ---");
+}
+
+fn ring() -> &'static RingBuffer<u8, HISTORY_BYTES> {
+    unsafe { &(*HISTORY.get()).assume_init_ref().ring }
+}
+
+struct Busy;
+
+impl Busy {
+    fn try_acquire() -> Option<Busy> {
+        if !READY.load(Ordering::Acquire) {
+            return None;
+        }
+
+        BUSY.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Busy)
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.store(false, Ordering::Release);
+    }
+}
+
+/// Appends a formatted line, dropping colour escape sequences
+pub fn record(line: &[u8]) {
+    let Some(_busy) = Busy::try_acquire() else {
+        MISSED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+
+    let ring = ring();
+    let mut rest = line;
+
+    while let Some(esc) = rest.iter().position(|b| *b == 0x1b) {
+        // SAFETY: we hold BUSY, so we are the only reader and writer
+        unsafe { ring.write_overwriting(&rest[..esc]); }
+
+        // escape sequences we emit all end in 'm':
+        let end = rest[esc..].iter().position(|b| *b == b'm').map(|i| esc + i + 1);
+        rest = &rest[end.unwrap_or(rest.len())..];
+    }
+
+    unsafe {
+        ring.write_overwriting(rest);
+        ring.write_overwriting(b"\n");
+    }
+}
+
+/// Number of lines left out of the history because it was busy
+pub fn missed() -> u32 {
+    MISSED.load(Ordering::Relaxed)
+}
+
+/// Calls func with the history, oldest first, as two slices since the ring
+/// buffer may wrap. Lines logged while func runs are not captured, so keep
+/// it short. Returns false if the history is busy or not initialized.
+pub fn dump(func: impl FnOnce(&[u8], &[u8])) -> bool {
+    let Some(_busy) = Busy::try_acquire() else {
+        return false;
+    };
+
+    // SAFETY: we hold BUSY. reads nothing, so the history is left intact:
+    unsafe {
+        ring().read_in_place(|left, right| {
+            func(left, right);
+            0
+        });
+    }
+
+    true
+}
+
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{s}");
+        Ok(())
+    }
+}
+
+/// Prints the history straight to the console, bypassing the logger
+pub fn print() {
+    let dumped = dump(|left, right| {
+        let _ = write_bytes(&mut Console, left)
+            .and_then(|()| write_bytes(&mut Console, right));
+    });
+
+    if !dumped {
+        println!("log history busy, try again");
+    }
+}
+
+/// Writes part of the history as text. The oldest line may have been cut
+/// mid-character when it was overwritten, as may the wraparound point, so
+/// invalid bytes are skipped over.
+pub fn write_bytes(out: &mut impl Write, mut bytes: &[u8]) -> fmt::Result {
+    while !bytes.is_empty() {
+        match core::str::from_utf8(bytes) {
+            Ok(s) => return out.write_str(s),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                out.write_str(unsafe { core::str::from_utf8_unchecked(valid) })?;
+                bytes = &rest[e.error_len().unwrap_or(rest.len())..];
+            }
+        }
+    }
+
+    Ok(())
+}
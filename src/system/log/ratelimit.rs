@@ -0,0 +1,205 @@
+//! Rate limited logging, for warnings on paths which may fire at packet rate.
+//!
+//! Each call site gets its own limit of so many messages per second. Past
+//! that, messages are counted rather than logged, and the next message to
+//! make it through says how many were suppressed. If none does, [`flush`]
+//! reports the count once the window has passed.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use log::Level;
+
+use crate::sync::mutex::CriticalMutex;
+use crate::sys;
+
+pub const DEFAULT_PER_SECOND: u32 = 1;
+
+/// Call sites beyond this many are left out of [`flush`], their counts are
+/// only reported with their next message
+pub const MAX_LIMITS: usize = 16;
+
+const WINDOW_MS: u32 = 1000;
+
+/// Logs at most `per_second` messages per second from this call site:
+///
+/// ```ignore
+/// log_ratelimited!(Level::Warn, 5; "dropped packet: seq={seq}");
+/// ```
+#[cfg_attr(test, allow(unused_macros))]
+macro_rules! log_ratelimited {
+    ($level:expr, $per_second:expr; $($arg:tt)+) => {{
+        static LIMIT: $crate::system::log::ratelimit::RateLimit =
+            $crate::system::log::ratelimit::RateLimit::new(
+                $level, $per_second, module_path!(), concat!(file!(), ":", line!()));
+
+        if let Some(suppressed) = LIMIT.check() {
+            if suppressed > 0 {
+                ::log::log!($level, "{} (suppressed {} similar)", format_args!($($arg)+), suppressed);
+            } else {
+                ::log::log!($level, $($arg)+);
+            }
+        }
+    }};
+    ($level:expr; $($arg:tt)+) => {
+        $crate::system::log::ratelimit::log_ratelimited!(
+            $level, $crate::system::log::ratelimit::DEFAULT_PER_SECOND; $($arg)+)
+    };
+}
+
+#[cfg_attr(test, allow(unused_macros))]
+macro_rules! warn_ratelimited {
+    ($($arg:tt)+) => {
+        $crate::system::log::ratelimit::log_ratelimited!(::log::Level::Warn; $($arg)+)
+    };
+}
+
+pub(crate) use log_ratelimited;
+pub(crate) use warn_ratelimited;
+
+/// Limits which have suppressed messages, for [`flush`] to check on
+static LIMITS: CriticalMutex<heapless::Vec<&'static RateLimit, MAX_LIMITS>> =
+    CriticalMutex::declare(heapless::Vec::new());
+
+pub struct RateLimit {
+    level: Level,
+    per_second: u32,
+    target: &'static str,
+    location: &'static str,
+    window_start_ms: AtomicU32,
+    count: AtomicU32,
+    suppressed: AtomicU32,
+    registered: AtomicBool,
+}
+
+impl RateLimit {
+    pub const fn new(level: Level, per_second: u32, target: &'static str, location: &'static str) -> Self {
+        RateLimit {
+            level,
+            per_second,
+            target,
+            location,
+            window_start_ms: AtomicU32::new(0),
+            count: AtomicU32::new(0),
+            suppressed: AtomicU32::new(0),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns None if the message should be suppressed, otherwise the
+    /// number suppressed since the last one allowed through. Racing callers
+    /// may occasionally let an extra message through, which is fine.
+    pub fn check(&'static self) -> Option<u32> {
+        let now = now_ms();
+        let start = self.window_start_ms.load(Ordering::Relaxed);
+
+        if now.wrapping_sub(start) >= WINDOW_MS {
+            let swapped = self.window_start_ms.compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed);
+            if swapped.is_ok() {
+                self.count.store(0, Ordering::Relaxed);
+            }
+        }
+
+        if self.count.fetch_add(1, Ordering::Relaxed) < self.per_second {
+            Some(self.suppressed.swap(0, Ordering::Relaxed))
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            self.register();
+            None
+        }
+    }
+
+    fn register(&'static self) {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            // if full, counts still go out with the next message:
+            let _ = LIMITS.lock().push(self);
+        }
+    }
+
+    /// Takes the suppressed count once the window it was counted in has
+    /// passed, unless a message has since reported it
+    fn take_stale(&self, now: u32) -> Option<u32> {
+        let start = self.window_start_ms.load(Ordering::Relaxed);
+
+        if now.wrapping_sub(start) < WINDOW_MS {
+            return None;
+        }
+
+        match self.suppressed.swap(0, Ordering::Relaxed) {
+            0 => None,
+            suppressed => Some(suppressed),
+        }
+    }
+}
+
+/// Logs the suppressed count of each call site which has gone quiet since,
+/// so that the end of a burst isn't lost. Call periodically.
+pub fn flush() {
+    // copy out so we don't log inside a critical section:
+    let limits = LIMITS.lock().clone();
+    let now = now_ms();
+
+    for limit in limits {
+        if let Some(suppressed) = limit.take_stale(now) {
+            log::log!(target: limit.target, limit.level,
+                "suppressed {} similar from {}", suppressed, limit.location);
+        }
+    }
+}
+
+fn now_ms() -> u32 {
+    let micros = unsafe { sys::esp_timer_get_time() };
+    (micros / 1000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+
+    use crate::host::clock;
+
+    use super::*;
+
+    fn limit_per_second(per_second: u32) -> &'static RateLimit {
+        // each test thread runs on its own clock, so needs its own limit:
+        Box::leak(Box::new(RateLimit::new(Level::Warn, per_second, "test", "test.rs:1")))
+    }
+
+    #[test]
+    fn reports_suppressed_with_next_message() {
+        let limit = limit_per_second(2);
+        clock::set(0);
+
+        assert_eq!(limit.check(), Some(0));
+        assert_eq!(limit.check(), Some(0));
+        assert_eq!(limit.check(), None);
+        assert_eq!(limit.check(), None);
+
+        clock::set(1_000_000);
+        assert_eq!(limit.check(), Some(2));
+        assert_eq!(limit.take_stale(now_ms()), None);
+    }
+
+    #[test]
+    fn flushes_suppressed_after_burst_goes_quiet() {
+        let limit = limit_per_second(1);
+        clock::set(5_000_000);
+
+        assert_eq!(limit.check(), Some(0));
+        for _ in 0..10 {
+            assert_eq!(limit.check(), None);
+        }
+
+        // still within the window the burst was counted in:
+        clock::set(5_500_000);
+        assert_eq!(limit.take_stale(now_ms()), None);
+
+        // nothing more is logged, so the count only goes out by flushing:
+        clock::set(6_000_000);
+        assert_eq!(limit.take_stale(now_ms()), Some(10));
+        assert_eq!(limit.take_stale(now_ms()), None);
+
+        // and isn't repeated with the next message:
+        clock::set(9_000_000);
+        assert_eq!(limit.check(), Some(0));
+    }
+}
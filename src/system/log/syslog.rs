@@ -0,0 +1,390 @@
+//! Forwards log records to a remote collector over UDP, as RFC 5424 syslog.
+//!
+//! Logging must never block the caller, so records are formatted straight
+//! into a small bounded queue and sent from a low priority task. Records
+//! which don't fit in the queue, or which fail to send, are dropped and
+//! counted. Each record carries a sequence number so the collector can
+//! tell where records went missing.
+
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::fmt::{self, Write};
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::ptr;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+use cstr::cstr;
+use derive_more::From;
+use log::Level;
+
+#[cfg(test)]
+use crate::host::net::UdpSocket;
+#[cfg(not(test))]
+use crate::platform::net::socket::UdpSocket;
+#[cfg(not(test))]
+use crate::platform::nvs;
+use crate::sync::mutex::CriticalMutex;
+use crate::sync::notify::Notify;
+use crate::sys;
+use crate::system::task;
+
+const QUEUE_LEN: usize = 8;
+const DATAGRAM_LEN: usize = 256;
+
+/// Records logged from interrupt context can't wake the sender task, they
+/// wait for the next periodic flush
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_PORT: u16 = 514;
+
+/// local0
+const FACILITY: u8 = 16;
+
+/// RFC 5424 limits MSGID to 32 characters
+const MSGID_LEN: usize = 32;
+
+const NVS_KEY: &CStr = cstr!("syslog");
+
+type Datagram = heapless::Vec<u8, DATAGRAM_LEN>;
+
+static COLLECTOR: CriticalMutex<Option<SocketAddrV4>> = CriticalMutex::declare(None);
+
+/// Mirrors `COLLECTOR.is_some()`, so the logger can bail out before
+/// formatting without taking a lock
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Records are formatted in place: a slot is reserved under the lock,
+/// filled in outside it, then marked ready for the sender
+static SLOTS: [Slot; QUEUE_LEN] = [const { Slot::new() }; QUEUE_LEN];
+
+/// Which slots are queued, oldest first
+static QUEUE: CriticalMutex<Ring> = CriticalMutex::declare(Ring { head: 0, len: 0 });
+
+static QUEUED: Notify = Notify::new();
+
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// Low 3 bytes of the MAC address, for the hostname
+static HOST_ID: AtomicU32 = AtomicU32::new(0);
+
+static SENT: AtomicU32 = AtomicU32::new(0);
+static DROPPED_QUEUE_FULL: AtomicU32 = AtomicU32::new(0);
+static DROPPED_SEND_FAILED: AtomicU32 = AtomicU32::new(0);
+
+struct Slot {
+    ready: AtomicBool,
+    datagram: UnsafeCell<Datagram>,
+}
+
+// SAFETY: a slot is only written by whoever reserved it, and only read by
+// the sender once it is marked ready
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    const fn new() -> Self {
+        Slot {
+            ready: AtomicBool::new(false),
+            datagram: UnsafeCell::new(Datagram::new()),
+        }
+    }
+}
+
+struct Ring {
+    head: usize,
+    len: usize,
+}
+
+/// Totals since boot
+pub struct SyslogStats {
+    pub sent: u32,
+    pub dropped_queue_full: u32,
+    pub dropped_send_failed: u32,
+}
+
+pub fn stats() -> SyslogStats {
+    SyslogStats {
+        sent: SENT.load(Ordering::Relaxed),
+        dropped_queue_full: DROPPED_QUEUE_FULL.load(Ordering::Relaxed),
+        dropped_send_failed: DROPPED_SEND_FAILED.load(Ordering::Relaxed),
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn collector() -> Option<SocketAddrV4> {
+    *COLLECTOR.lock()
+}
+
+/// Sets where records are sent, or stops forwarding if `None`
+pub fn set_collector(addr: Option<SocketAddrV4>) {
+    *COLLECTOR.lock() = addr;
+    ENABLED.store(addr.is_some(), Ordering::Relaxed);
+}
+
+/// Called from the logger for every enabled record. Never blocks.
+pub fn forward(record: &log::Record) {
+    if !is_enabled() {
+        return;
+    }
+
+    let Some(slot) = reserve() else {
+        DROPPED_QUEUE_FULL.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+
+    // SAFETY: the slot is ours until we mark it ready
+    let datagram = unsafe { &mut *slot.datagram.get() };
+    datagram.clear();
+    let _ = write_datagram(&mut Truncate(datagram), record);
+    slot.ready.store(true, Ordering::Release);
+
+    if unsafe { sys::xPortInIsrContext() } == 0 {
+        QUEUED.notify();
+    }
+}
+
+fn reserve() -> Option<&'static Slot> {
+    let mut queue = QUEUE.lock();
+
+    if queue.len == QUEUE_LEN {
+        return None;
+    }
+
+    let index = (queue.head + queue.len) % QUEUE_LEN;
+    queue.len += 1;
+    Some(&SLOTS[index])
+}
+
+/// Formats into a datagram, cutting off whatever doesn't fit
+struct Truncate<'a>(&'a mut Datagram);
+
+impl Write for Truncate<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.0.capacity() - self.0.len();
+        let len = s.len().min(room);
+        let _ = self.0.extend_from_slice(&s.as_bytes()[..len]);
+
+        // stop formatting once full:
+        if len < s.len() { Err(fmt::Error) } else { Ok(()) }
+    }
+}
+
+fn write_datagram(out: &mut impl Write, record: &log::Record) -> fmt::Result {
+    let priority = FACILITY * 8 + severity(record.level());
+    let host_id = HOST_ID.load(Ordering::Relaxed);
+
+    // PROCID: the task which logged the record
+    let task = unsafe { CStr::from_ptr(sys::pcTaskGetName(ptr::null_mut())) };
+    let task = task.to_str().ok().filter(|s| !s.is_empty() && !s.contains(' ')).unwrap_or("-");
+
+    // MSGID: the target, which is plain ascii
+    let target = record.target();
+    let msgid = target.get(..MSGID_LEN).unwrap_or(target);
+
+    // sequenceId must be in 1..=i32::MAX
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed) % (i32::MAX as u32) + 1;
+
+    // sysUpTime is in hundredths of a second
+    let uptime = unsafe { sys::esp_timer_get_time() } / 10_000;
+
+    // we have no wall clock time, so leave TIMESTAMP as nil and let the
+    // collector stamp records on receipt
+    write!(out, "<{priority}>1 - bark-{host_id:06x} bark {task} {msgid} ")?;
+    write!(out, "[meta sequenceId=\"{sequence}\" sysUpTime=\"{uptime}\"] ")?;
+    write!(out, "{}", record.args())
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug => 7,
+        Level::Trace => 7,
+    }
+}
+
+/// Starts the sender task. Records are only queued once a collector is set.
+#[cfg(not(test))]
+pub fn start() {
+    let mut mac = [0u8; 6];
+    unsafe { sys::esp_efuse_mac_get_default(mac.as_mut_ptr()); }
+    HOST_ID.store(u32::from_be_bytes([0, mac[3], mac[4], mac[5]]), Ordering::Relaxed);
+
+    let result = task::new("bark::syslog")
+        .stack_size(4096)
+        .spawn(sender_task);
+
+    if let Err(e) = result {
+        log::error!("failed to spawn syslog task: {e:?}");
+    }
+}
+
+#[cfg(not(test))]
+async fn sender_task() {
+    let socket = match UdpSocket::new() {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!("failed to open syslog socket: {e:?}");
+            return;
+        }
+    };
+
+    loop {
+        let _ = task::timeout(FLUSH_INTERVAL, QUEUED.notified()).await;
+        send_queued(&socket);
+    }
+}
+
+/// Sends records in the order they were queued, stopping at the first one
+/// still being formatted
+fn send_queued(socket: &UdpSocket) {
+    while let Some(slot) = front() {
+        // SAFETY: ready slots aren't written again until released
+        let datagram = unsafe { &*slot.datagram.get() };
+
+        // collector may have been unset since the record was queued. don't
+        // log failures here, that would only queue more records to fail to
+        // send:
+        if let Some(addr) = collector() {
+            match socket.send_to(datagram, addr) {
+                Ok(()) => SENT.fetch_add(1, Ordering::Relaxed),
+                Err(_) => DROPPED_SEND_FAILED.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+
+        release_front();
+    }
+}
+
+/// The oldest record, if it is ready. It stays queued while it's sent, so
+/// we needn't copy it out or hold the lock.
+fn front() -> Option<&'static Slot> {
+    let queue = QUEUE.lock();
+    let slot = &SLOTS[queue.head];
+    (queue.len > 0 && slot.ready.load(Ordering::Acquire)).then_some(slot)
+}
+
+fn release_front() {
+    let mut queue = QUEUE.lock();
+    SLOTS[queue.head].ready.store(false, Ordering::Relaxed);
+    queue.head = (queue.head + 1) % QUEUE_LEN;
+    queue.len -= 1;
+}
+
+#[cfg(not(test))]
+#[derive(Debug, From)]
+pub enum LoadError {
+    Nvs(sys::EspError),
+    BadAddress,
+}
+
+/// Parses a collector address, either `ip` or `ip:port`
+pub fn parse_collector(value: &str) -> Option<SocketAddrV4> {
+    SocketAddrV4::from_str(value)
+        .or_else(|_| Ipv4Addr::from_str(value).map(|ip| SocketAddrV4::new(ip, DEFAULT_PORT)))
+        .ok()
+}
+
+/// Applies the collector persisted in NVS, if any
+#[cfg(not(test))]
+pub fn load() -> Result<(), LoadError> {
+    let value = unsafe { nvs::read_config_str::<24>(NVS_KEY)? };
+
+    // saved as empty when forwarding is turned off:
+    let Some(value) = value.filter(|value| !value.is_empty()) else {
+        return Ok(());
+    };
+
+    let addr = parse_collector(&value).ok_or(LoadError::BadAddress)?;
+
+    set_collector(Some(addr));
+    log::info!("forwarding logs to syslog collector at {addr}");
+    Ok(())
+}
+
+/// Persists the current collector to NVS, to be applied on next boot
+#[cfg(not(test))]
+pub fn save() -> Result<(), sys::EspError> {
+    let mut value = heapless::String::<24>::new();
+
+    if let Some(addr) = collector() {
+        let _ = write!(&mut value, "{addr}");
+    }
+
+    unsafe { nvs::write_config_str(NVS_KEY, &value) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::format;
+    use std::net::{self, SocketAddr};
+    use std::string::String;
+
+    use super::*;
+
+    #[test]
+    fn parses_collector_with_or_without_port() {
+        let ip = Ipv4Addr::new(10, 0, 0, 2);
+
+        assert_eq!(parse_collector("10.0.0.2"), Some(SocketAddrV4::new(ip, DEFAULT_PORT)));
+        assert_eq!(parse_collector("10.0.0.2:1514"), Some(SocketAddrV4::new(ip, 1514)));
+        assert_eq!(parse_collector("10.0.0.2:"), None);
+        assert_eq!(parse_collector("collector"), None);
+    }
+
+    #[test]
+    fn truncates_long_records() {
+        let mut datagram = Datagram::new();
+        let long = "x".repeat(DATAGRAM_LEN + 10);
+
+        assert!(write!(&mut Truncate(&mut datagram), "{long}").is_err());
+        assert_eq!(datagram.len(), DATAGRAM_LEN);
+    }
+
+    #[test]
+    fn sends_queued_records_to_collector() {
+        let listener = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an ipv4 address");
+        };
+
+        set_collector(Some(addr));
+
+        for i in 0..QUEUE_LEN + 2 {
+            forward(&log::Record::builder()
+                .level(Level::Warn)
+                .target("bark::test")
+                .args(format_args!("record {i}"))
+                .build());
+        }
+
+        assert_eq!(stats().dropped_queue_full, 2);
+
+        send_queued(&UdpSocket::new().unwrap());
+        assert_eq!(stats().sent, QUEUE_LEN as u32);
+
+        let mut buf = [0u8; DATAGRAM_LEN];
+
+        for i in 0..QUEUE_LEN {
+            let len = listener.recv(&mut buf).unwrap();
+
+            assert_eq!(String::from_utf8_lossy(&buf[..len]), format!(
+                "<132>1 - bark-000000 bark test bark::test [meta sequenceId=\"{}\" sysUpTime=\"0\"] record {i}",
+                i + 1,
+            ));
+        }
+
+        // the queue has room again:
+        forward(&log::Record::builder().args(format_args!("again")).build());
+        assert_eq!(stats().dropped_queue_full, 2);
+
+        set_collector(None);
+    }
+}
@@ -1,11 +1,29 @@
+#[cfg(not(test))]
+pub mod boot;
+#[cfg(not(test))]
+pub mod crash;
 pub mod heap;
+#[cfg(not(test))]
+pub mod journal;
+#[cfg(not(test))]
 pub mod log;
+/// Only the syslog forwarder and rate limiting are built for host tests
+#[cfg(test)]
+pub mod log {
+    pub mod ratelimit;
+    pub mod syslog;
+}
+#[cfg(not(test))]
 pub mod logo;
+#[cfg(not(test))]
 pub mod panic;
+#[cfg_attr(test, path = "task/sim.rs")]
 pub mod task;
+#[cfg(not(test))]
 pub mod uart;
 
 /// Call once only
+#[cfg(not(test))]
 pub unsafe fn init() {
     // init uart and log first
     uart::init_uart0();
@@ -13,4 +31,7 @@ pub unsafe fn init() {
 
     // say hello :)
     esp_println::print!("{}", logo::LOGO);
+
+    // report any crashes before the last reset
+    crash::init();
 }
@@ -1,5 +1,25 @@
+use core::alloc::Layout;
+
+use super::{crash, heap};
+
+/// Called when an infallible `alloc` API fails to allocate. Restart cleanly
+/// rather than panicking into the panic handler backtrace.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let bytes = layout.size();
+    crash::record_oom(bytes);
+
+    let info = heap::info();
+    log::error!("OUT OF MEMORY: failed to allocate {bytes} bytes (free={}, largest_free_block={}), restarting",
+        info.free, info.largest_free_block);
+    unsafe { esp_idf_sys::esp_restart(); }
+}
+
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    // record first, in case logging panics again:
+    crash::record_panic(info);
+
     log::error!("PANIC: {info}");
     unsafe { esp_idf_sys::abort(); }
 }
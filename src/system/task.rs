@@ -2,30 +2,52 @@ use core::ffi::{CStr, c_void};
 use core::fmt::Debug;
 use core::future::Future;
 use core::ptr::{self, NonNull};
+use core::sync::atomic::AtomicU8;
+use core::time::Duration;
 
 use derive_more::From;
 use esp_idf_sys as sys;
 
-use super::heap::{HeapBox, MallocError};
+use super::heap::{HeapBox, MallocError, Subsystem};
+use super::journal::{self, Event};
+
+use stack::StackRef;
+use watchdog::WatchdogConfig;
 
 mod execute;
 mod registry;
+mod stack;
+mod ticks;
 mod waker;
+mod watchdog;
+pub mod time;
 pub mod top;
 
-pub use waker::TaskWakerSet;
+pub use registry::MAX_TASKS;
+#[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+pub use stack::TaskStack;
+pub use time::{timeout, TimedOut};
+pub use waker::{TaskWaker, TaskWakerSet};
 
 pub type TaskPtr = NonNull<sys::tskTaskControlBlock>;
 
 const DEFAULT_STACK_SIZE: u32 = 8192;
 const DEFAULT_PRIORITY: u32 = 0;
 
+/// ESP32 is dual core
+const NUM_CORES: usize = 2;
+
+/// Warn at exit if a task came within this many bytes of overflowing its stack
+const STACK_HEADROOM_WARNING: u32 = 512;
+
 #[must_use = "must call TaskBuilder::spawn to actually create task"]
 pub struct TaskBuilder {
     name: &'static str,
     stack_bytes: u32,
     priority: u32,
     core: i32,
+    stack: Option<StackRef>,
+    watchdog: WatchdogConfig,
 }
 
 pub fn new(name: &'static str) -> TaskBuilder {
@@ -34,6 +56,8 @@ pub fn new(name: &'static str) -> TaskBuilder {
         stack_bytes: DEFAULT_STACK_SIZE,
         priority: DEFAULT_PRIORITY,
         core: 0,
+        stack: None,
+        watchdog: WatchdogConfig::default(),
     }
 }
 
@@ -47,6 +71,10 @@ pub fn current() -> TaskPtr {
 pub enum SpawnError {
     AllocateClosure(MallocError),
     TaskCreateError,
+    /// The static stack is still in use by another task
+    StackInUse,
+    /// The static stack is too small to hold the task's future
+    StackTooSmall,
 }
 
 impl TaskBuilder {
@@ -63,8 +91,45 @@ impl TaskBuilder {
     }
 
     #[allow(unused)]
-    pub fn use_alternate_core(mut self) -> Self {
-        self.core = 1;
+    pub fn use_alternate_core(self) -> Self {
+        self.pin_to_core(1)
+    }
+
+    #[allow(unused)]
+    pub fn pin_to_core(mut self, core: usize) -> Self {
+        assert!(core < NUM_CORES, "no such core: {core}");
+        self.core = core as i32;
+        self
+    }
+
+    /// Lets the scheduler run the task on whichever core is free
+    #[allow(unused)]
+    pub fn no_affinity(mut self) -> Self {
+        self.core = sys::tskNO_AFFINITY as i32;
+        self
+    }
+
+    /// Opts the task out of the task watchdog. For tasks which block inside
+    /// their future rather than waiting asynchronously.
+    #[allow(unused)]
+    pub fn no_watchdog(mut self) -> Self {
+        self.watchdog = WatchdogConfig::Disabled;
+        self
+    }
+
+    /// Fires the task watchdog if the task's future stays pending for
+    /// longer than timeout without being woken
+    #[allow(unused)]
+    pub fn watchdog_timeout(mut self, timeout: Duration) -> Self {
+        self.watchdog = WatchdogConfig::Enabled { pending_timeout: Some(timeout) };
+        self
+    }
+
+    /// Runs the task on a caller-provided stack rather than allocating one.
+    /// The stack size set by [`TaskBuilder::stack_size`] is ignored.
+    #[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+    pub fn static_stack<const BYTES: usize>(mut self, stack: &'static TaskStack<BYTES>) -> Self {
+        self.stack = Some(stack.as_ref());
         self
     }
 
@@ -74,7 +139,31 @@ impl TaskBuilder {
         Fut: Future<Output = R>,
         R: TaskReturn,
     {
-        let boxed_main = HeapBox::alloc(main)?;
+        match self.stack {
+            Some(stack) => self.spawn_static(stack, main),
+            None => self.spawn_heap(main),
+        }
+    }
+
+    fn spawn_heap<F, Fut, R>(self, main: F) -> Result<(), SpawnError>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = R>,
+        R: TaskReturn,
+    {
+        struct HeapStart<F> {
+            main: F,
+            stack_bytes: u32,
+            watchdog: WatchdogConfig,
+        }
+
+        let stack_bytes = self.stack_bytes + core::mem::size_of::<Fut>() as u32;
+
+        let boxed_main = HeapBox::alloc_tagged(Subsystem::Task, HeapStart {
+            main,
+            stack_bytes,
+            watchdog: self.watchdog,
+        })?;
 
         unsafe extern "C" fn start<F, Fut, R>(param: *mut c_void)
         where
@@ -86,19 +175,11 @@ impl TaskBuilder {
             // are run on any left over values before we call vTaskDelete:
             {
                 // unbox closure from param
-                let boxed_main = NonNull::new_unchecked(param).cast::<F>();
+                let boxed_main = NonNull::new_unchecked(param).cast::<HeapStart<F>>();
                 let boxed_main = HeapBox::from_raw(boxed_main);
-                let main = HeapBox::into_inner(boxed_main);
-
-                // invoke closure as task routine
-                let result = execute::execute(main);
+                let HeapStart { main, stack_bytes, watchdog } = HeapBox::into_inner(boxed_main);
 
-                // get task name
-                let name = CStr::from_ptr(sys::pcTaskGetName(ptr::null_mut()));
-                let name = name.to_str().unwrap_or_default();
-
-                // log task exit with task name
-                result.log(name);
+                run_task(main, stack_bytes, watchdog);
             }
 
             // freertos tasks must never return, instead delete current task:
@@ -110,18 +191,13 @@ impl TaskBuilder {
 
         log::info!("Spawning task: {}", self.name);
 
-        let stack_size = self.stack_bytes + core::mem::size_of::<Fut>() as u32;
-
-        const TASK_NAME_LENGTH: usize = 32;
-        let mut name = heapless::Vec::<u8, { TASK_NAME_LENGTH + 1 }>::new();
-        let _ = name.extend(self.name.bytes().take(TASK_NAME_LENGTH));
-        let _ = name.push(0);
+        let name = self.c_name();
 
         let rc = unsafe {
             sys::xTaskCreatePinnedToCore(
                 Some(start::<F, Fut, R>),
                 name.as_ptr().cast(),
-                stack_size,
+                stack_bytes,
                 boxed_main_ptr.cast::<c_void>().as_ptr(),
                 self.priority,
                 ptr::null_mut(),
@@ -139,6 +215,126 @@ impl TaskBuilder {
             Err(SpawnError::TaskCreateError)
         }
     }
+
+    fn spawn_static<F, Fut, R>(self, stack: StackRef, main: F) -> Result<(), SpawnError>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = R>,
+        R: TaskReturn,
+    {
+        // the closure is handed to the new task in the bottom of its own
+        // stack memory, which we carve off before giving the rest to
+        // FreeRTOS. no allocation needed, and no waiting for the task to
+        // take ownership
+        struct StaticStart<F> {
+            main: F,
+            stack_bytes: u32,
+            watchdog: WatchdogConfig,
+            state: &'static AtomicU8,
+        }
+
+        unsafe extern "C" fn start<F, Fut, R>(param: *mut c_void)
+        where
+            F: FnOnce() -> Fut + Send + 'static,
+            Fut: Future<Output = R>,
+            R: TaskReturn
+        {
+            let StaticStart { main, stack_bytes, watchdog, state } = param.cast::<StaticStart<F>>().read();
+
+            run_task(main, stack_bytes, watchdog);
+
+            // can't delete ourselves, see stack module docs:
+            stack::exit_current(state);
+        }
+
+        let carve = core::mem::size_of::<StaticStart<F>>()
+            .next_multiple_of(stack::STACK_ALIGN);
+
+        assert!(core::mem::align_of::<StaticStart<F>>() <= stack::STACK_ALIGN);
+
+        if carve + core::mem::size_of::<Fut>() >= stack.bytes {
+            return Err(SpawnError::StackTooSmall);
+        }
+
+        stack.acquire()?;
+
+        log::info!("Spawning task: {} (static stack)", self.name);
+
+        let name = self.c_name();
+        let start_ptr = stack.memory.cast::<StaticStart<F>>();
+        let stack_bytes = (stack.bytes - carve) as u32;
+
+        let handle = unsafe {
+            start_ptr.write(StaticStart {
+                main,
+                stack_bytes,
+                watchdog: self.watchdog,
+                state: stack.state,
+            });
+
+            sys::xTaskCreateStaticPinnedToCore(
+                Some(start::<F, Fut, R>),
+                name.as_ptr().cast(),
+                stack_bytes,
+                start_ptr.cast(),
+                self.priority,
+                stack.memory.add(carve).cast(),
+                stack.tcb,
+                self.core,
+            )
+        };
+
+        if handle.is_null() {
+            // nobody took ownership of the closure, drop it here
+            unsafe { ptr::drop_in_place(start_ptr); }
+            stack.release();
+            return Err(SpawnError::TaskCreateError);
+        }
+
+        Ok(())
+    }
+
+    fn c_name(&self) -> heapless::Vec<u8, { TASK_NAME_LENGTH + 1 }> {
+        let mut name = heapless::Vec::new();
+        let _ = name.extend(self.name.bytes().take(TASK_NAME_LENGTH));
+        let _ = name.push(0);
+        name
+    }
+}
+
+const TASK_NAME_LENGTH: usize = 32;
+
+unsafe fn run_task<F, Fut, R>(main: F, stack_bytes: u32, watchdog: WatchdogConfig)
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = R>,
+    R: TaskReturn,
+{
+    // invoke closure as task routine
+    let result = execute::execute(main, watchdog);
+
+    // get task name
+    let name = CStr::from_ptr(sys::pcTaskGetName(ptr::null_mut()));
+    let name = name.to_str().unwrap_or_default();
+
+    // log task exit with task name
+    result.log(name);
+
+    report_stack_usage(name, stack_bytes);
+}
+
+/// Logs how much of its stack the current task used at its deepest, so
+/// stacks can be sized from real numbers
+fn report_stack_usage(name: &str, stack_bytes: u32) {
+    // on ESP-IDF, the high water mark is in bytes rather than words:
+    let headroom = unsafe { sys::uxTaskGetStackHighWaterMark(ptr::null_mut()) };
+    let used = stack_bytes.saturating_sub(headroom);
+
+    if headroom < STACK_HEADROOM_WARNING {
+        log::warn!("{name} stack usage: {used}/{stack_bytes} bytes, only {headroom} bytes headroom!");
+    } else {
+        log::info!("{name} stack usage: {used}/{stack_bytes} bytes");
+    }
 }
 
 pub trait TaskReturn {
@@ -157,6 +353,7 @@ impl<T: TaskReturn, E: Debug> TaskReturn for Result<T, E> {
             Ok(val) => val.log(task_name),
             Err(err) => {
                 log::error!("{task_name} failed with error: {err:?}");
+                journal::record(Event::TaskFailed { task: journal::task_name(task_name) });
             }
         }
     }
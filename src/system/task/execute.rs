@@ -5,9 +5,11 @@ use core::ptr::null_mut;
 use esp_idf_sys as sys;
 
 use super::registry::TaskRegistration;
+use super::ticks::ticks_before;
 use super::waker::TaskWaker;
+use super::watchdog::{TaskWatchdog, WatchdogConfig};
 
-pub fn execute<Func, Fut, Ret>(func: Func) -> Ret
+pub fn execute<Func, Fut, Ret>(func: Func, watchdog: WatchdogConfig) -> Ret
 where
     Func: FnOnce() -> Fut,
     Fut: Future<Output = Ret>,
@@ -16,22 +18,59 @@ where
     let waker = TaskWaker::new(registration.id()).to_waker();
     let mut cx = Context::from_waker(&waker);
 
+    let watchdog = TaskWatchdog::subscribe(
+        registration.id(),
+        watchdog,
+        core::any::type_name::<Fut>(),
+    );
+
     let fut = func();
     futures::pin_mut!(fut);
 
     loop {
+        watchdog.begin_poll();
+
         if let Poll::Ready(ret) = fut.as_mut().poll(&mut cx) {
             return ret;
         }
 
-        unsafe {
-            sys::xTaskGenericNotifyWait(
-                0,
-                0,
-                0,
-                null_mut(),
-                sys::freertos_wait_forever,
-            );
+        watchdog.end_poll();
+
+        // if any future asked to be polled again by a deadline, only sleep
+        // until then:
+        let deadline = registration.id().slot().take_deadline();
+
+        loop {
+            let wait_ticks = match deadline {
+                Some(deadline) => {
+                    let now = unsafe { sys::xTaskGetTickCount() };
+                    if ticks_before(now, deadline) {
+                        deadline.wrapping_sub(now)
+                    } else {
+                        // deadline already passed, poll again straight away
+                        break;
+                    }
+                }
+                None => sys::freertos_wait_forever,
+            };
+
+            let notified = unsafe {
+                sys::xTaskGenericNotifyWait(
+                    0,
+                    0,
+                    0,
+                    null_mut(),
+                    watchdog.limit_wait(wait_ticks),
+                )
+            };
+
+            if notified == 1 {
+                break;
+            }
+
+            // timed out. go round again to check the deadline, feeding the
+            // watchdog in case that's what we woke up for:
+            watchdog.feed_while_pending();
         }
     }
 }
@@ -1,10 +1,12 @@
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
 use core::ptr::{null_mut, NonNull};
 
 use esp_idf_sys as sys;
 
 use crate::system::task::{self, TaskPtr};
 
+use super::ticks::ticks_before;
+
 pub const MAX_TASKS: usize = 32;
 
 pub struct TaskRegistration {
@@ -69,7 +71,11 @@ impl TaskId {
     }
 
     pub fn slot(&self) -> &'static TaskSlot {
-        &SLOTS[usize::from(self.0)]
+        &SLOTS[self.index()]
+    }
+
+    pub fn index(&self) -> usize {
+        usize::from(self.0)
     }
 
     pub fn as_bit(&self) -> u32 {
@@ -81,14 +87,22 @@ impl TaskId {
     }
 }
 
-#[repr(transparent)]
 pub struct TaskSlot {
-    ptr: AtomicPtr<sys::tskTaskControlBlock>
+    ptr: AtomicPtr<sys::tskTaskControlBlock>,
+    /// Earliest tick count at which a future in this task asked to be
+    /// polled again, only meaningful if `has_deadline` is set. Only ever
+    /// accessed from the task owning the slot.
+    deadline: AtomicU32,
+    has_deadline: AtomicBool,
 }
 
 impl TaskSlot {
     pub const fn empty() -> Self {
-        TaskSlot { ptr: AtomicPtr::new(null_mut()) }
+        TaskSlot {
+            ptr: AtomicPtr::new(null_mut()),
+            deadline: AtomicU32::new(0),
+            has_deadline: AtomicBool::new(false),
+        }
     }
 
     pub fn load(&self) -> Option<TaskPtr> {
@@ -96,9 +110,34 @@ impl TaskSlot {
     }
 
     fn clear(&self) {
+        self.has_deadline.store(false, Ordering::Relaxed);
         self.ptr.store(null_mut(), Ordering::Relaxed)
     }
 
+    /// Requests that the task be woken no later than `ticks`. If a deadline
+    /// is already set, the earlier of the two is kept.
+    pub fn set_deadline(&self, ticks: sys::TickType_t) {
+        if self.has_deadline.load(Ordering::Relaxed) {
+            let current = self.deadline.load(Ordering::Relaxed);
+            if ticks_before(current, ticks) {
+                return;
+            }
+        }
+
+        self.deadline.store(ticks, Ordering::Relaxed);
+        self.has_deadline.store(true, Ordering::Relaxed);
+    }
+
+    /// Takes the current deadline, leaving none set. Futures which still
+    /// need a deadline set it again the next time they are polled.
+    pub fn take_deadline(&self) -> Option<sys::TickType_t> {
+        if self.has_deadline.swap(false, Ordering::Relaxed) {
+            Some(self.deadline.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+
     fn try_claim(&self, task: TaskPtr) -> Result<(), ()> {
         let result = self.ptr.compare_exchange(
             null_mut(),
@@ -0,0 +1,314 @@
+//! Host stand-in for the task executor, for tests.
+//!
+//! Futures spawned on a [`Sim`] run as simulated tasks, polled one at a
+//! time on the test thread in a deterministic order. Wakeups go through
+//! the same [`TaskWaker`] and [`TaskWakerSet`] interfaces as on target, so
+//! the sync primitives under test can't tell the difference. Time is
+//! virtual: whenever every task is blocked, the clock jumps straight to the
+//! next deadline. Deadlines are kept in ticks, as on target, so timeouts
+//! round and clamp the same way.
+
+use core::convert::Infallible;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll, RawWakerVTable, Waker};
+use core::time::Duration;
+use std::boxed::Box;
+use std::cell::Cell;
+use std::vec::Vec;
+
+use pin_project::pin_project;
+
+use crate::host::clock;
+use crate::sync::isr::IsrResult;
+use crate::sys;
+
+mod ticks;
+
+pub const MAX_TASKS: usize = 32;
+
+thread_local! {
+    /// Bitset of tasks to poll on the next pass
+    static READY: Cell<u32> = const { Cell::new(0) };
+    /// Task being polled, woken in place of wakers we didn't create
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+    /// Earliest deadline each task is waiting for, in virtual micros
+    static DEADLINES: [Cell<Option<i64>>; MAX_TASKS] = const { [const { Cell::new(None) }; MAX_TASKS] };
+}
+
+fn mark_ready(bits: u32) {
+    READY.with(|ready| ready.set(ready.get() | bits));
+}
+
+fn set_deadline(task: usize, at: i64) {
+    DEADLINES.with(|deadlines| {
+        let deadline = &deadlines[task];
+        deadline.set(Some(deadline.get().map_or(at, |existing| existing.min(at))));
+    });
+}
+
+type TaskFuture<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+/// Runs simulated tasks to completion. Only one `Sim` may run per thread at
+/// a time.
+pub struct Sim<'a> {
+    tasks: Vec<Option<TaskFuture<'a>>>,
+}
+
+impl<'a> Sim<'a> {
+    /// Resets the scheduler state and the virtual clock for this thread
+    pub fn new() -> Self {
+        READY.with(|ready| ready.set(0));
+        DEADLINES.with(|deadlines| deadlines.iter().for_each(|deadline| deadline.set(None)));
+        clock::set(0);
+
+        Sim { tasks: Vec::new() }
+    }
+
+    /// Adds a task, to be first polled when the sim runs. Tasks are polled
+    /// in the order they were spawned.
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'a) {
+        assert!(self.tasks.len() < MAX_TASKS, "too many simulated tasks");
+        mark_ready(1 << self.tasks.len());
+        self.tasks.push(Some(Box::pin(future)));
+    }
+
+    /// Polls tasks until every one has completed. Panics if every remaining
+    /// task is blocked with nothing left to wake it.
+    pub fn run(mut self) {
+        while self.tasks.iter().any(Option::is_some) {
+            let ready = READY.with(|ready| ready.replace(0));
+
+            if ready == 0 {
+                self.advance_clock();
+                continue;
+            }
+
+            for (id, slot) in self.tasks.iter_mut().enumerate() {
+                if ready & (1 << id) == 0 {
+                    continue;
+                }
+
+                let Some(task) = slot else { continue };
+
+                CURRENT.with(|current| current.set(id));
+                let waker = TaskWaker::new(id).to_waker();
+
+                if task.as_mut().poll(&mut Context::from_waker(&waker)).is_ready() {
+                    *slot = None;
+                    DEADLINES.with(|deadlines| deadlines[id].set(None));
+                }
+            }
+        }
+    }
+
+    /// Jumps to the earliest deadline of any live task, and wakes every task
+    /// waiting for it
+    fn advance_clock(&self) {
+        let next = DEADLINES.with(|deadlines| {
+            self.tasks.iter().enumerate()
+                .filter(|(_, task)| task.is_some())
+                .filter_map(|(id, _)| deadlines[id].get())
+                .min()
+        });
+
+        let Some(next) = next else {
+            panic!("simulated tasks deadlocked, every task is blocked with no deadline");
+        };
+
+        clock::set(next.max(clock::now()));
+
+        DEADLINES.with(|deadlines| {
+            for (id, deadline) in deadlines.iter().enumerate() {
+                if deadline.get().is_some_and(|at| at <= next) {
+                    deadline.set(None);
+                    mark_ready(1 << id);
+                }
+            }
+        });
+    }
+}
+
+/// Lets every other ready task run before resuming
+pub async fn yield_now() {
+    let mut yielded = false;
+
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+
+        yielded = true;
+        TaskWaker::from_context(cx).wake();
+        Poll::Pending
+    }).await
+}
+
+#[derive(Clone, Copy)]
+pub struct TaskWaker {
+    id: usize,
+}
+
+impl TaskWaker {
+    fn new(id: usize) -> Self {
+        TaskWaker { id }
+    }
+
+    fn to_waker(self) -> Waker {
+        unsafe { Waker::from_raw(waker_impl::new(self.id)) }
+    }
+
+    /// Simulated tasks are only ever polled by [`Sim::run`], so the waker
+    /// always belongs to the current task
+    pub fn from_context(_cx: &Context) -> Self {
+        TaskWaker::new(CURRENT.with(Cell::get))
+    }
+
+    pub fn wake(&self) {
+        mark_ready(1 << self.id);
+    }
+
+    pub unsafe fn wake_from_isr(&self) -> IsrResult<(), Infallible> {
+        self.wake();
+        IsrResult::ok((), true)
+    }
+}
+
+pub struct TaskWakerSet {
+    bits: AtomicU32,
+}
+
+impl TaskWakerSet {
+    pub const fn new() -> Self {
+        TaskWakerSet { bits: AtomicU32::new(0) }
+    }
+
+    pub fn add_task(&self, context: &Context) {
+        let waker = TaskWaker::from_context(context);
+        self.bits.fetch_or(1 << waker.id, Ordering::SeqCst);
+    }
+
+    pub fn wake_all(&self) {
+        mark_ready(self.bits.swap(0, Ordering::SeqCst));
+    }
+
+    pub unsafe fn wake_from_isr(&self) -> IsrResult<(), Infallible> {
+        let bits = self.bits.swap(0, Ordering::SeqCst);
+        mark_ready(bits);
+        IsrResult::ok((), bits != 0)
+    }
+}
+
+mod waker_impl {
+    use core::task::RawWaker;
+
+    use super::{mark_ready, RawWakerVTable};
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+    pub fn new(id: usize) -> RawWaker {
+        RawWaker::new(id as *const (), &VTABLE)
+    }
+
+    unsafe fn clone(data: *const ()) -> RawWaker {
+        RawWaker::new(data, &VTABLE)
+    }
+
+    unsafe fn wake(data: *const ()) {
+        mark_ready(1 << data as usize);
+    }
+
+    unsafe fn drop(_: *const ()) {}
+}
+
+pub mod time {
+    use super::*;
+
+    /// Returned by [`timeout`] when the deadline passes before the inner
+    /// future completes
+    #[derive(Debug)]
+    pub struct TimedOut;
+
+    use super::ticks::{duration_to_ticks, ticks_before};
+
+    /// A point in virtual time, in ticks
+    #[derive(Copy, Clone, Debug)]
+    pub struct Deadline {
+        ticks: sys::TickType_t,
+    }
+
+    impl Deadline {
+        pub fn after(duration: Duration) -> Self {
+            Deadline { ticks: now().wrapping_add(duration_to_ticks(duration)) }
+        }
+
+        pub fn has_passed(&self) -> bool {
+            !ticks_before(now(), self.ticks)
+        }
+
+        pub fn poll_elapsed(&self, cx: &Context) -> Poll<()> {
+            if self.has_passed() {
+                return Poll::Ready(());
+            }
+
+            // tests don't run long enough for the tick count to wrap:
+            let at = i64::from(self.ticks) * 1_000_000 / i64::from(sys::CONFIG_FREERTOS_HZ);
+            set_deadline(TaskWaker::from_context(cx).id, at);
+            Poll::Pending
+        }
+    }
+
+    pub fn now() -> sys::TickType_t {
+        (clock::now() * i64::from(sys::CONFIG_FREERTOS_HZ) / 1_000_000) as sys::TickType_t
+    }
+
+    pub async fn sleep(duration: Duration) {
+        let deadline = Deadline::after(duration);
+        poll_fn(|cx| deadline.poll_elapsed(cx)).await
+    }
+
+    pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+        Timeout {
+            future,
+            deadline: Deadline::after(duration),
+        }
+    }
+
+    #[pin_project]
+    pub struct Timeout<F> {
+        #[pin]
+        future: F,
+        deadline: Deadline,
+    }
+
+    impl<F: Future> Future for Timeout<F> {
+        type Output = Result<F::Output, TimedOut>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let this = self.project();
+
+            if let Poll::Ready(output) = this.future.poll(cx) {
+                return Poll::Ready(Ok(output));
+            }
+
+            match this.deadline.poll_elapsed(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(TimedOut)),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+}
+
+pub use time::{timeout, TimedOut};
+
+/// Runs a single future to completion as the only simulated task
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut output = None;
+
+    let mut sim = Sim::new();
+    sim.spawn(async { output = Some(future.await) });
+    sim.run();
+
+    output.expect("sim ran to completion")
+}
@@ -0,0 +1,121 @@
+//! Caller-provided, statically allocated task stacks.
+//!
+//! A task running on a static stack can't delete itself, since FreeRTOS
+//! still references its TCB until the idle task gets around to cleaning it
+//! up, and we have no way of knowing when that has happened. Instead the
+//! task suspends itself on exit, and whoever next spawns on the same stack
+//! deletes it first, which releases the TCB straight away.
+//!
+//! Spawning never waits for the previous task to finish suspending, since
+//! spawns happen from async tasks which mustn't block. Until it has, the
+//! stack is reported as in use and the caller tries again later.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use esp_idf_sys as sys;
+
+use super::SpawnError;
+
+/// Required alignment of stack memory on Xtensa
+pub(super) const STACK_ALIGN: usize = 16;
+
+const FREE: u8 = 0;
+const RUNNING: u8 = 1;
+const EXITED: u8 = 2;
+
+pub struct TaskStack<const BYTES: usize> {
+    tcb: UnsafeCell<MaybeUninit<sys::StaticTask_t>>,
+    state: AtomicU8,
+    stack: UnsafeCell<StackMemory<BYTES>>,
+}
+
+unsafe impl<const BYTES: usize> Sync for TaskStack<BYTES> {}
+
+#[repr(C, align(16))]
+struct StackMemory<const BYTES: usize>([MaybeUninit<u8>; BYTES]);
+
+impl<const BYTES: usize> TaskStack<BYTES> {
+    #[cfg_attr(not(feature = "static-alloc"), allow(unused))]
+    pub const fn new() -> Self {
+        TaskStack {
+            tcb: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU8::new(FREE),
+            // SAFETY: an array of MaybeUninit needs no initialization
+            stack: UnsafeCell::new(StackMemory(unsafe { MaybeUninit::uninit().assume_init() })),
+        }
+    }
+
+    pub(super) fn as_ref(&'static self) -> StackRef {
+        StackRef {
+            tcb: self.tcb.get().cast(),
+            state: &self.state,
+            memory: self.stack.get().cast(),
+            bytes: BYTES,
+        }
+    }
+}
+
+/// Type-erased reference to a [`TaskStack`]
+#[derive(Clone, Copy)]
+pub(super) struct StackRef {
+    pub tcb: *mut sys::StaticTask_t,
+    pub state: &'static AtomicU8,
+    pub memory: *mut u8,
+    pub bytes: usize,
+}
+
+impl StackRef {
+    /// Claims the stack for a new task, reaping the task which last ran on
+    /// it if necessary
+    pub fn acquire(&self) -> Result<(), SpawnError> {
+        let claimed = self.state.compare_exchange(FREE, RUNNING, Ordering::SeqCst, Ordering::SeqCst);
+
+        match claimed {
+            Ok(_) => Ok(()),
+            Err(EXITED) => {
+                if self.state.compare_exchange(EXITED, RUNNING, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                    return Err(SpawnError::StackInUse);
+                }
+
+                if unsafe { self.try_reap() } {
+                    Ok(())
+                } else {
+                    self.state.store(EXITED, Ordering::SeqCst);
+                    Err(SpawnError::StackInUse)
+                }
+            }
+            Err(_) => Err(SpawnError::StackInUse),
+        }
+    }
+
+    /// Hands the stack back after a failed spawn
+    pub fn release(&self) {
+        self.state.store(FREE, Ordering::SeqCst);
+    }
+
+    /// Deletes the previous, exited task. It marks itself exited just
+    /// before suspending, so returns false if it hasn't got that far yet
+    unsafe fn try_reap(&self) -> bool {
+        // for static tasks, the handle is the TCB:
+        let handle = self.tcb.cast::<sys::tskTaskControlBlock>();
+
+        if sys::eTaskGetState(handle) != sys::eTaskState_eSuspended {
+            return false;
+        }
+
+        sys::vTaskDelete(handle);
+        true
+    }
+}
+
+/// Called on the exiting task, in place of `vTaskDelete`
+pub(super) unsafe fn exit_current(state: &AtomicU8) -> ! {
+    state.store(EXITED, Ordering::SeqCst);
+
+    loop {
+        sys::vTaskSuspend(ptr::null_mut());
+    }
+}
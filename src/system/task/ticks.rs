@@ -0,0 +1,55 @@
+//! FreeRTOS tick arithmetic, shared with the host simulation of tasks
+
+use core::time::Duration;
+
+use crate::sys;
+
+/// Tick counts wrap, so are compared by their signed difference. A deadline
+/// can be at most this far ahead, which at 100 Hz is over eight months.
+pub const MAX_TICKS: sys::TickType_t = i32::MAX as sys::TickType_t;
+
+/// Rounds up, so that any nonzero duration waits for at least one tick, and
+/// clamps to [`MAX_TICKS`], so that very long durations wait practically
+/// forever rather than wrapping into the past
+pub fn duration_to_ticks(duration: Duration) -> sys::TickType_t {
+    let ticks = (duration.as_nanos() * u128::from(sys::CONFIG_FREERTOS_HZ)).div_ceil(1_000_000_000);
+    ticks.min(u128::from(MAX_TICKS)) as sys::TickType_t
+}
+
+/// Wraparound-aware comparison of tick counts
+pub fn ticks_before(a: sys::TickType_t, b: sys::TickType_t) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::host::clock;
+    use crate::system::task::time::{sleep, Deadline};
+    use crate::system::task::{block_on, timeout};
+
+    use super::*;
+
+    #[test]
+    fn long_durations_wait_rather_than_wrapping() {
+        assert_eq!(duration_to_ticks(Duration::MAX), MAX_TICKS);
+        assert!(!Deadline::after(Duration::MAX).has_passed());
+
+        block_on(async {
+            assert!(timeout(Duration::from_secs(60), sleep(Duration::MAX)).await.is_err());
+            assert_eq!(clock::now(), 60_000_000);
+        });
+    }
+
+    #[test]
+    fn short_durations_wait_at_least_one_tick() {
+        assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+        assert_eq!(duration_to_ticks(Duration::from_micros(1)), 1);
+        assert_eq!(duration_to_ticks(Duration::from_millis(15)), 2);
+        assert!(!Deadline::after(Duration::from_micros(1)).has_passed());
+
+        block_on(async {
+            sleep(Duration::from_millis(1)).await;
+            assert_eq!(clock::now(), 10_000);
+        });
+    }
+}
@@ -0,0 +1,89 @@
+use core::future::{Future, poll_fn};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use esp_idf_sys as sys;
+use pin_project::pin_project;
+
+use super::ticks::ticks_before;
+use super::waker::TaskWaker;
+
+pub use super::ticks::duration_to_ticks;
+
+/// Returned by [`timeout`] when the deadline passes before the inner future
+/// completes
+#[derive(Debug)]
+pub struct TimedOut;
+
+/// A point in time measured in FreeRTOS ticks
+#[derive(Copy, Clone, Debug)]
+pub struct Deadline {
+    ticks: sys::TickType_t,
+}
+
+impl Deadline {
+    pub fn after(duration: Duration) -> Self {
+        let ticks = now().wrapping_add(duration_to_ticks(duration));
+        Deadline { ticks }
+    }
+
+    pub fn has_passed(&self) -> bool {
+        !ticks_before(now(), self.ticks)
+    }
+
+    /// Resolves once the deadline has passed, arranging for the current task
+    /// to be woken at the deadline otherwise
+    pub fn poll_elapsed(&self, cx: &Context) -> Poll<()> {
+        if self.has_passed() {
+            return Poll::Ready(());
+        }
+
+        let waker = TaskWaker::from_context(cx);
+        waker.id().slot().set_deadline(self.ticks);
+        Poll::Pending
+    }
+}
+
+pub fn now() -> sys::TickType_t {
+    unsafe { sys::xTaskGetTickCount() }
+}
+
+#[allow(unused)]
+pub async fn sleep(duration: Duration) {
+    let deadline = Deadline::after(duration);
+    poll_fn(|cx| deadline.poll_elapsed(cx)).await
+}
+
+/// Runs future to completion, giving up if it has not completed within
+/// duration. The future is dropped on timeout.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        deadline: Deadline::after(duration),
+    }
+}
+
+#[pin_project]
+pub struct Timeout<F> {
+    #[pin]
+    future: F,
+    deadline: Deadline,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, TimedOut>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if let Poll::Ready(output) = this.future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        match this.deadline.poll_elapsed(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(TimedOut)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
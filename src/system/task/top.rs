@@ -79,6 +79,19 @@ async fn task() {
     }
 }
 
+/// Calls `f` with each task's name, run time counter and stack high water
+/// mark, returning the total run time. A task's CPU usage over an interval
+/// is the change in its run time over the change in the total.
+pub fn for_each_task(mut f: impl FnMut(&AsciiStr, u32, usize)) -> u32 {
+    let state = get_system_state();
+
+    for task in &state.tasks {
+        f(task.name(), task.runtime_tick_counter(), task.stack_high_watermark());
+    }
+
+    state.elapsed_ticks
+}
+
 struct SystemState {
     elapsed_ticks: u32,
     tasks: Vec<TaskStatus, MAX_TOP_TASKS>,
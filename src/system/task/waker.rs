@@ -10,6 +10,7 @@ use crate::sync::isr::IsrResult;
 use super::TaskPtr;
 use super::registry::{self, TaskId};
 
+#[derive(Clone, Copy)]
 pub struct TaskWaker {
     id: TaskId,
 }
@@ -34,6 +35,24 @@ impl TaskWaker {
 
         TaskWaker { id }
     }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Wakes this task alone, rather than everything in a [`TaskWakerSet`]
+    pub fn wake(&self) {
+        if let Some(task) = self.id.slot().load() {
+            unsafe { wake(task); }
+        }
+    }
+
+    pub unsafe fn wake_from_isr(&self) -> IsrResult<(), Infallible> {
+        match self.id.slot().load() {
+            Some(task) => wake_from_isr(task),
+            None => IsrResult::default(),
+        }
+    }
 }
 
 pub struct TaskWakerSet {
@@ -0,0 +1,202 @@
+//! Integration with the ESP-IDF task watchdog.
+//!
+//! Every task running our executor subscribes to the task watchdog unless
+//! it opts out, and feeds it before each poll of its future. That catches a
+//! poll which blocks for longer than the watchdog timeout. Tasks can also
+//! set their own timeout on how long their future may stay pending without
+//! being woken, which catches futures stuck waiting on something that will
+//! never happen, eg. a deadlocked lock.
+//!
+//! Pending tasks wake up periodically to keep feeding the watchdog, until
+//! their own timeout passes. When the watchdog fires, we print which future
+//! each watched task was in and for how long.
+
+use core::ffi::CStr;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use esp_idf_sys as sys;
+use esp_println::println;
+
+use super::registry::{TaskId, MAX_TASKS};
+use super::ticks::ticks_before;
+use super::time;
+
+/// How often a pending task wakes to feed the watchdog. Comfortably inside
+/// the default watchdog timeout of 5 seconds
+const FEED_INTERVAL_MS: u32 = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum WatchdogConfig {
+    Disabled,
+    Enabled {
+        /// Maximum time the task's future may stay pending without being
+        /// woken. None for tasks which may legitimately wait forever.
+        pending_timeout: Option<Duration>,
+    },
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig::Enabled { pending_timeout: None }
+    }
+}
+
+const IDLE: u8 = 0;
+const POLLING: u8 = 1;
+const PENDING: u8 = 2;
+
+/// What a watched task is up to, for reporting from the watchdog ISR
+struct WatchSlot {
+    future_name: AtomicPtr<u8>,
+    future_name_len: AtomicUsize,
+    state: AtomicU8,
+    since: AtomicU32,
+    pending_timeout: AtomicU32,
+}
+
+impl WatchSlot {
+    const fn new() -> Self {
+        WatchSlot {
+            future_name: AtomicPtr::new(ptr::null_mut()),
+            future_name_len: AtomicUsize::new(0),
+            state: AtomicU8::new(IDLE),
+            since: AtomicU32::new(0),
+            pending_timeout: AtomicU32::new(0),
+        }
+    }
+
+    fn future_name(&self) -> &'static str {
+        let ptr = self.future_name.load(Ordering::Relaxed);
+        let len = self.future_name_len.load(Ordering::Relaxed);
+
+        if ptr.is_null() {
+            return "(unknown)";
+        }
+
+        // SAFETY: only ever set from a &'static str
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len)) }
+    }
+
+    fn set_state(&self, state: u8, since: sys::TickType_t) {
+        self.since.store(since, Ordering::Relaxed);
+        self.state.store(state, Ordering::Relaxed);
+    }
+}
+
+const EMPTY_SLOT: WatchSlot = WatchSlot::new();
+static SLOTS: [WatchSlot; MAX_TASKS] = [EMPTY_SLOT; MAX_TASKS];
+
+pub struct TaskWatchdog {
+    slot: Option<&'static WatchSlot>,
+    pending_timeout: Option<sys::TickType_t>,
+}
+
+impl TaskWatchdog {
+    /// Subscribes the current task to the task watchdog
+    pub fn subscribe(id: TaskId, config: WatchdogConfig, future_name: &'static str) -> Self {
+        let WatchdogConfig::Enabled { pending_timeout } = config else {
+            return TaskWatchdog { slot: None, pending_timeout: None };
+        };
+
+        let rc = unsafe { sys::esp_task_wdt_add(ptr::null_mut()) };
+        if rc != sys::ESP_OK {
+            log::warn!("failed to subscribe task to watchdog: {rc}");
+            return TaskWatchdog { slot: None, pending_timeout: None };
+        }
+
+        let pending_timeout = pending_timeout.map(time::duration_to_ticks);
+
+        let slot = &SLOTS[id.index()];
+        slot.future_name.store(future_name.as_ptr().cast_mut(), Ordering::Relaxed);
+        slot.future_name_len.store(future_name.len(), Ordering::Relaxed);
+        slot.pending_timeout.store(pending_timeout.unwrap_or(0), Ordering::Relaxed);
+        slot.set_state(IDLE, time::now());
+
+        TaskWatchdog { slot: Some(slot), pending_timeout }
+    }
+
+    pub fn begin_poll(&self) {
+        if let Some(slot) = self.slot {
+            unsafe { sys::esp_task_wdt_reset(); }
+            slot.set_state(POLLING, time::now());
+        }
+    }
+
+    pub fn end_poll(&self) {
+        if let Some(slot) = self.slot {
+            slot.set_state(PENDING, time::now());
+        }
+    }
+
+    /// Caps how long the executor may block, so that it can keep feeding
+    /// the watchdog while pending
+    pub fn limit_wait(&self, ticks: sys::TickType_t) -> sys::TickType_t {
+        match self.slot {
+            Some(_) => ticks.min(time::duration_to_ticks(Duration::from_millis(FEED_INTERVAL_MS.into()))),
+            None => ticks,
+        }
+    }
+
+    /// Called when the executor wakes only to feed the watchdog. Stops
+    /// feeding once the task's pending timeout has passed, so the watchdog
+    /// fires.
+    pub fn feed_while_pending(&self) {
+        let Some(slot) = self.slot else { return };
+
+        if let Some(timeout) = self.pending_timeout {
+            let since = slot.since.load(Ordering::Relaxed);
+            if !ticks_before(time::now(), since.wrapping_add(timeout)) {
+                return;
+            }
+        }
+
+        unsafe { sys::esp_task_wdt_reset(); }
+    }
+}
+
+impl Drop for TaskWatchdog {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            unsafe { sys::esp_task_wdt_delete(ptr::null_mut()); }
+            slot.future_name.store(ptr::null_mut(), Ordering::Relaxed);
+            slot.set_state(IDLE, 0);
+        }
+    }
+}
+
+/// Called by ESP-IDF from the task watchdog ISR when the watchdog fires
+#[no_mangle]
+extern "C" fn esp_task_wdt_isr_user_handler() {
+    let now = unsafe { sys::xTaskGetTickCountFromISR() };
+
+    println!("task watchdog fired! watched tasks:");
+
+    for id in TaskId::iter() {
+        let Some(task) = id.slot().load() else { continue };
+
+        let slot = &SLOTS[id.index()];
+        let state = match slot.state.load(Ordering::Relaxed) {
+            POLLING => "polling",
+            PENDING => "pending",
+            _ => continue,
+        };
+
+        let name = unsafe { CStr::from_ptr(sys::pcTaskGetName(task.as_ptr())) };
+        let name = name.to_str().unwrap_or_default();
+
+        let ticks = now.wrapping_sub(slot.since.load(Ordering::Relaxed));
+        let millis = u64::from(ticks) * 1000 / u64::from(sys::CONFIG_FREERTOS_HZ);
+
+        let timeout = slot.pending_timeout.load(Ordering::Relaxed);
+        let stuck = match slot.state.load(Ordering::Relaxed) {
+            PENDING => timeout != 0 && ticks >= timeout,
+            _ => millis >= u64::from(FEED_INTERVAL_MS),
+        };
+
+        println!("  {}{name}: {state} for {millis}ms in {}",
+            if stuck { "STUCK " } else { "" },
+            slot.future_name());
+    }
+}